@@ -0,0 +1,249 @@
+use crate::{
+    certificate_authority::CertificateAuthority,
+    forward_middleware,
+    grpc::GrpcListener,
+    proxy_listener::ProxyListener,
+    result::Result,
+    server::{Chain, Listener, Server},
+    storage,
+    tls::{self, ServerCertificate, TlsListener},
+    websocket::WebSocketListener,
+    CacheMiddleware, ForwardMiddleware, Parody, RequestStorage, ServedRecordings,
+};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Configures and starts a Parody server
+///
+/// # Example
+/// ```
+/// use std::str::FromStr;
+/// use std::path::Path;
+/// use std::time::Duration;
+/// let parody = parody::ParodyBuilder::new()
+///     .with_storage_config(parody::storage::Config::default().with_root_dir(Path::new("/tmp/parody/example.com").to_owned()))
+///     .with_upstream_config(parody::upstream::Config::default().with_timeout(Duration::from_secs(5)))
+///     .start(url::Url::from_str("http://example.com").unwrap())
+///     .unwrap();
+/// println!("PARODY_PORT={}", parody.port());
+/// ```
+#[derive(Default)]
+pub struct ParodyBuilder {
+    storage_config: storage::Config,
+    upstream_config: forward_middleware::Config,
+    server_certificate: Option<ServerCertificate>,
+    certificate_authority: Option<CertificateAuthority>,
+    rewrite_origin: bool,
+    journal_path: Option<PathBuf>,
+}
+
+impl ParodyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_storage_config(mut self, storage_config: storage::Config) -> Self {
+        self.storage_config = storage_config;
+        self
+    }
+
+    /// Settings of the client making requests to the upstream
+    pub fn with_upstream_config(mut self, upstream_config: forward_middleware::Config) -> Self {
+        self.upstream_config = upstream_config;
+        self
+    }
+
+    /// Replaces the upstream origin with Parody's one in replayed responses,
+    /// applies to [`start`](#method.start) only
+    pub fn with_origin_rewrite(mut self) -> Self {
+        self.rewrite_origin = true;
+        self
+    }
+
+    /// Appends recordings served by the server to the file, one per line
+    ///
+    /// Recordings never listed in journals are unused, see [`storage::find_unused`].
+    ///
+    /// [`storage::find_unused`]: storage/fn.find_unused.html
+    pub fn with_journal_file(mut self, journal_path: PathBuf) -> Self {
+        self.journal_path = Some(journal_path);
+        self
+    }
+
+    /// Serves HTTPS instead of plain HTTP, applies to [`start`](#method.start) only
+    pub fn with_tls(mut self, server_certificate: ServerCertificate) -> Self {
+        self.server_certificate = Some(server_certificate);
+        self
+    }
+
+    /// Decrypts `CONNECT` tunnels, applies to [`start_proxy`](#method.start_proxy) only
+    pub fn with_certificate_authority(
+        mut self,
+        certificate_authority: CertificateAuthority,
+    ) -> Self {
+        self.certificate_authority = Some(certificate_authority);
+        self
+    }
+
+    /// Starts a server forwarding requests to the upstream at random port at localhost
+    ///
+    /// Plain HTTP servers also record and replay WebSocket sessions
+    /// and gRPC calls made over cleartext HTTP/2.
+    pub fn start(self, upstream_url: url::Url) -> Result<Parody> {
+        self.server(upstream_url)?.run()
+    }
+
+    /// Like [`start`](#method.start), but runs the server on the current Tokio runtime
+    pub async fn start_async(self, upstream_url: url::Url) -> Result<Parody> {
+        self.server(upstream_url)?.spawn()
+    }
+
+    /// Starts a forward HTTP proxy at random port at localhost
+    pub fn start_proxy(self) -> Result<Parody> {
+        self.proxy_server()?.run()
+    }
+
+    /// Like [`start_proxy`](#method.start_proxy), but runs the server on the current Tokio runtime
+    pub async fn start_proxy_async(self) -> Result<Parody> {
+        self.proxy_server()?.spawn()
+    }
+
+    fn server(mut self, upstream_url: url::Url) -> Result<ParodyServer> {
+        if self.rewrite_origin {
+            self.storage_config.use_origin_rewrite(upstream_url.clone());
+        }
+
+        let served = ServedRecordings::new(
+            self.storage_config.get_root_dir().to_owned(),
+            self.journal_path,
+        );
+        let plain_listeners: Vec<Box<dyn Listener>> = vec![
+            Box::new(GrpcListener::new(
+                upstream_url.clone(),
+                self.storage_config.clone(),
+                &self.upstream_config,
+                served.clone(),
+            )?),
+            Box::new(WebSocketListener::new(
+                upstream_url.clone(),
+                self.storage_config.clone(),
+                &self.upstream_config,
+                served.clone(),
+            )?),
+        ];
+        let mut chain = Chain::new(crate::handle_request);
+        chain.link_before(CacheMiddleware::new().with_storage_config(self.storage_config));
+        chain.link_before(ForwardMiddleware::new(upstream_url).with_config(&self.upstream_config)?);
+
+        let (server_config, certificate_pem) = match self.server_certificate {
+            Some(ServerCertificate::SelfSigned) => {
+                let certificate_authority = CertificateAuthority::generate()?;
+                (
+                    certificate_authority.issue_server_config(vec![
+                        "localhost".to_owned(),
+                        "127.0.0.1".to_owned(),
+                    ])?,
+                    certificate_authority.certificate_pem().to_owned(),
+                )
+            }
+            Some(ServerCertificate::Pem {
+                certificate_pem,
+                key_pem,
+            }) => (
+                tls::server_config_from_pem(&certificate_pem, &key_pem)?,
+                certificate_pem,
+            ),
+            None => return ParodyServer::new(plain_listeners, chain, served),
+        };
+
+        let mut server = ParodyServer::new(
+            vec![Box::new(TlsListener::new(server_config))],
+            chain,
+            served,
+        )?;
+        server.certificate_pem = Some(certificate_pem);
+        Ok(server)
+    }
+
+    fn proxy_server(self) -> Result<ParodyServer> {
+        let mut listener = ProxyListener::new();
+
+        if let Some(certificate_authority) = self.certificate_authority {
+            listener = listener.with_certificate_authority(Arc::new(certificate_authority));
+        }
+
+        let served = ServedRecordings::new(
+            self.storage_config.get_root_dir().to_owned(),
+            self.journal_path,
+        );
+        let mut chain = Chain::new(crate::handle_request);
+        chain.link_before(
+            CacheMiddleware::new().with_storage_config(self.storage_config.with_host_path()),
+        );
+        chain.link_before(ForwardMiddleware::proxy().with_config(&self.upstream_config)?);
+
+        ParodyServer::new(vec![Box::new(listener)], chain, served)
+    }
+}
+
+/// A server ready to run and what Parody exposes about it
+struct ParodyServer {
+    server: Server,
+    a_storage: Arc<Mutex<crate::Requests>>,
+    served: ServedRecordings,
+    certificate_pem: Option<String>,
+}
+
+impl ParodyServer {
+    fn new(
+        listeners: Vec<Box<dyn Listener>>,
+        mut chain: Chain,
+        served: ServedRecordings,
+    ) -> Result<Self> {
+        let a_storage = Arc::new(Mutex::new(Vec::new()));
+
+        chain.link_extension(RequestStorage(a_storage.clone()));
+        chain.link_extension(served.clone());
+
+        Ok(Self {
+            server: Server::new(listeners, chain)?,
+            a_storage,
+            served,
+            certificate_pem: None,
+        })
+    }
+
+    fn run(self) -> Result<Parody> {
+        let parody = Parody::run(self.server)?;
+        Ok(Self::attach(
+            parody,
+            self.a_storage,
+            self.served,
+            self.certificate_pem,
+        ))
+    }
+
+    fn spawn(self) -> Result<Parody> {
+        let parody = Parody::spawn(self.server, &tokio::runtime::Handle::current())?;
+        Ok(Self::attach(
+            parody,
+            self.a_storage,
+            self.served,
+            self.certificate_pem,
+        ))
+    }
+
+    fn attach(
+        mut parody: Parody,
+        a_storage: Arc<Mutex<crate::Requests>>,
+        served: ServedRecordings,
+        certificate_pem: Option<String>,
+    ) -> Parody {
+        parody.a_storage = Some(a_storage);
+        parody.served = Some(served);
+        parody.certificate_pem = certificate_pem;
+        parody
+    }
+}
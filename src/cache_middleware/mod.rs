@@ -1,23 +1,23 @@
-use crate::{error::Error, request::ParodyRequest, storage, storage::DirectoryStorage};
-use iron::{middleware::BeforeMiddleware, typemap::Key, IronError, IronResult};
+use crate::{
+    server::{BeforeMiddleware, HandlerError, Request},
+    storage,
+    storage::DirectoryStorage,
+};
 use std::path::PathBuf;
 
 #[cfg(test)]
 mod test;
 
-impl ParodyRequest for iron::Request<'_, '_> {
-    fn get_method(&self) -> std::string::String {
-        self.method.as_ref().to_owned()
-    }
-    fn get_url(&self) -> url::Url {
-        self.url.clone().into()
-    }
-}
-
 pub struct CacheMiddleware {
     storage_config: storage::Config,
 }
 
+impl Default for CacheMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CacheMiddleware {
     pub fn new() -> Self {
         Self {
@@ -41,36 +41,18 @@ impl CacheMiddleware {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct ResponseCache;
-impl Key for ResponseCache {
-    type Value = storage::DirectoryStorage;
-}
-
-#[derive(Clone, Copy)]
-struct CachedResponse;
-impl Key for CachedResponse {
-    type Value = iron::Response;
-}
+/// Storage of the request's response, inserted into request extensions
+#[derive(Clone)]
+pub struct ResponseCache(pub DirectoryStorage);
 
 impl BeforeMiddleware for CacheMiddleware {
-    fn before(&self, req: &mut iron::Request) -> IronResult<()> {
+    fn before(&self, req: &mut Request) -> Result<(), HandlerError> {
         trace!("Entered BeforeMiddleware::before");
 
-        let storage = DirectoryStorage::new_with_config(req, self.storage_config.clone()).map_err(
-            |error| match error {
-                Error::Common(error) => error.into(),
-                _ => IronError::new(Box::new(error), iron::status::InternalServerError),
-            },
-        )?;
+        let storage = DirectoryStorage::new_with_config(req, self.storage_config.clone())?;
 
-        req.extensions.insert::<ResponseCache>(storage);
+        req.extensions.insert(ResponseCache(storage));
 
         Ok(())
     }
-
-    fn catch(&self, _req: &mut iron::Request, err: IronError) -> IronResult<()> {
-        trace!("Entered BeforeMiddleware::catch");
-        Err(err)
-    }
 }
@@ -0,0 +1,178 @@
+use crate::{result::Result, tls};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose, SerialNumber,
+};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(test)]
+mod test;
+
+const CERTIFICATE_FILE_NAME: &str = "parody-ca.pem";
+const KEY_FILE_NAME: &str = "parody-ca.key.pem";
+const COMMON_NAME: &str = "Parody CA";
+
+/// A local certificate authority minting certificates for intercepted hosts
+///
+/// Clients should trust the authority's certificate (see [`certificate_pem`])
+/// to accept the per-host certificates Parody presents on `CONNECT`.
+///
+/// [`certificate_pem`]: #method.certificate_pem
+pub struct CertificateAuthority {
+    certificate: rcgen::Certificate,
+    certificate_pem: String,
+    key_pair: KeyPair,
+    /// All host certificates share the same key, minting them stays cheap
+    host_key_pair: KeyPair,
+    server_configs: Mutex<HashMap<String, Arc<rustls::ServerConfig>>>,
+}
+
+impl std::fmt::Debug for CertificateAuthority {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CertificateAuthority")
+    }
+}
+
+impl CertificateAuthority {
+    /// Generates a new authority, it lives only in memory
+    pub fn generate() -> Result<Self> {
+        let key_pair = KeyPair::generate()?;
+        let mut params = CertificateParams::default();
+
+        params
+            .distinguished_name
+            .push(DnType::CommonName, COMMON_NAME);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+
+        let certificate = params.self_signed(&key_pair)?;
+        let certificate_pem = certificate.pem();
+
+        Self::with_certificate(certificate, certificate_pem, key_pair)
+    }
+
+    /// Loads an authority from PEM-encoded certificate and private key
+    pub fn from_pem(certificate_pem: &str, key_pem: &str) -> Result<Self> {
+        let key_pair = KeyPair::from_pem(key_pem)?;
+        // Re-signing the parsed parameters gives a certificate with the same subject and key,
+        // which is all we need to issue host certificates
+        let certificate =
+            CertificateParams::from_ca_cert_pem(certificate_pem)?.self_signed(&key_pair)?;
+
+        Self::with_certificate(certificate, certificate_pem.to_owned(), key_pair)
+    }
+
+    /// Loads an authority from the directory or generates and saves a new one there
+    ///
+    /// Keeping the authority between runs lets clients trust it once.
+    pub fn load_or_generate(dir: &Path) -> Result<Self> {
+        let certificate_path = dir.join(CERTIFICATE_FILE_NAME);
+        let key_path = dir.join(KEY_FILE_NAME);
+
+        if certificate_path.exists() && key_path.exists() {
+            debug!(target: "mitm", "Loading CA from: {}", dir.to_string_lossy());
+            return Self::from_pem(
+                &std::fs::read_to_string(&certificate_path)?,
+                &std::fs::read_to_string(&key_path)?,
+            );
+        }
+
+        let authority = Self::generate()?;
+
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(&certificate_path, &authority.certificate_pem)?;
+        write_private(&key_path, authority.key_pair.serialize_pem().as_bytes())?;
+        info!(target: "mitm", "Saved new CA to: {}", dir.to_string_lossy());
+
+        Ok(authority)
+    }
+
+    fn with_certificate(
+        certificate: rcgen::Certificate,
+        certificate_pem: String,
+        key_pair: KeyPair,
+    ) -> Result<Self> {
+        Ok(Self {
+            certificate,
+            certificate_pem,
+            key_pair,
+            host_key_pair: KeyPair::generate()?,
+            server_configs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// PEM-encoded authority certificate for clients to trust
+    pub fn certificate_pem(&self) -> &str {
+        &self.certificate_pem
+    }
+
+    /// TLS configuration presenting a certificate for the host
+    pub(crate) fn server_config(&self, host: &str) -> Result<Arc<rustls::ServerConfig>> {
+        let mut server_configs = self.server_configs.lock().unwrap();
+
+        if let Some(server_config) = server_configs.get(host) {
+            return Ok(server_config.clone());
+        }
+
+        debug!(target: "mitm", "Minting certificate for: {}", host);
+        let server_config = self.issue_server_config(vec![host.to_owned()])?;
+        server_configs.insert(host.to_owned(), server_config.clone());
+
+        Ok(server_config)
+    }
+
+    /// TLS configuration presenting a new certificate for all the names
+    pub(crate) fn issue_server_config(
+        &self,
+        names: Vec<String>,
+    ) -> Result<Arc<rustls::ServerConfig>> {
+        let mut params = CertificateParams::new(names.clone())?;
+
+        if let Some(name) = names.first() {
+            params.distinguished_name.push(DnType::CommonName, name);
+        }
+
+        params.serial_number = Some(SerialNumber::from(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or_default(),
+        ));
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+
+        let certificate =
+            params.signed_by(&self.host_key_pair, &self.certificate, &self.key_pair)?;
+
+        tls::server_config(
+            vec![certificate.der().clone()],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.host_key_pair.serialize_der())),
+        )
+    }
+}
+
+/// Writes a file only the owner can read, others could forge certificates with the key
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(contents)
+}
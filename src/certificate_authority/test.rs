@@ -0,0 +1,51 @@
+use super::*;
+
+#[test]
+fn test_certificate_authority_should_issue_server_config_once_per_host() {
+    let certificate_authority = CertificateAuthority::generate().expect("CA should be generated");
+
+    let server_config = certificate_authority
+        .server_config("example.com")
+        .expect("Server config should be created");
+
+    assert!(Arc::ptr_eq(
+        &server_config,
+        &certificate_authority.server_config("example.com").unwrap()
+    ));
+    assert!(!Arc::ptr_eq(
+        &server_config,
+        &certificate_authority.server_config("localhost").unwrap()
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_certificate_authority_load_or_generate_should_save_key_readable_by_owner_only() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let ca_dir = tempfile::tempdir().expect("Cannot create CA directory");
+    CertificateAuthority::load_or_generate(ca_dir.path()).expect("CA should be generated");
+
+    let permissions = std::fs::metadata(ca_dir.path().join(KEY_FILE_NAME))
+        .expect("Key should be saved")
+        .permissions();
+    assert_eq!(permissions.mode() & 0o777, 0o600);
+}
+
+#[test]
+fn test_certificate_authority_load_or_generate_should_reuse_saved_authority() {
+    let ca_dir = tempfile::tempdir().expect("Cannot create CA directory");
+
+    let generated =
+        CertificateAuthority::load_or_generate(ca_dir.path()).expect("CA should be generated");
+    let loaded =
+        CertificateAuthority::load_or_generate(ca_dir.path()).expect("CA should be loaded");
+
+    assert!(generated
+        .certificate_pem()
+        .starts_with("-----BEGIN CERTIFICATE-----"));
+    assert_eq!(generated.certificate_pem(), loaded.certificate_pem());
+    loaded
+        .server_config("example.com")
+        .expect("Loaded CA should issue certificates");
+}
@@ -10,11 +10,14 @@ pub enum UtilError {
 pub enum Error {
     AlreadyListening,
     CacheMiss,
+    /// Recording was made by a newer Parody
+    UnsupportedMetadataVersion(u32),
     Common(CommonError),
     Util(UtilError),
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum CommonError {
     IoError(std::io::Error),
     YamlError(serde_yaml::Error),
@@ -23,6 +26,15 @@ pub enum CommonError {
     HyperError(hyper::Error),
     UrlError(url::ParseError),
     ReqwestError(reqwest::Error),
+    CertificateError(rcgen::Error),
+    TlsError(rustls::Error),
+    PemError(rustls::pki_types::pem::Error),
+    Base64Error(base64::DecodeError),
+    /// Boxed, WebSocket errors may carry a whole handshake response
+    WebSocketError(Box<tungstenite::Error>),
+    Http2Error(h2::Error),
+    HttpError(http::Error),
+    NativeTlsError(native_tls::Error),
 }
 
 impl From<UtilError> for Error {
@@ -73,18 +85,70 @@ impl From<reqwest::Error> for CommonError {
     }
 }
 
+impl From<rcgen::Error> for CommonError {
+    fn from(source: rcgen::Error) -> CommonError {
+        CommonError::CertificateError(source)
+    }
+}
+
+impl From<rustls::Error> for CommonError {
+    fn from(source: rustls::Error) -> CommonError {
+        CommonError::TlsError(source)
+    }
+}
+
+impl From<rustls::pki_types::pem::Error> for CommonError {
+    fn from(source: rustls::pki_types::pem::Error) -> CommonError {
+        CommonError::PemError(source)
+    }
+}
+
+impl From<base64::DecodeError> for CommonError {
+    fn from(source: base64::DecodeError) -> CommonError {
+        CommonError::Base64Error(source)
+    }
+}
+
+impl From<tungstenite::Error> for CommonError {
+    fn from(source: tungstenite::Error) -> CommonError {
+        CommonError::WebSocketError(Box::new(source))
+    }
+}
+
+impl From<h2::Error> for CommonError {
+    fn from(source: h2::Error) -> CommonError {
+        CommonError::Http2Error(source)
+    }
+}
+
+impl From<http::Error> for CommonError {
+    fn from(source: http::Error) -> CommonError {
+        CommonError::HttpError(source)
+    }
+}
+
+impl From<native_tls::Error> for CommonError {
+    fn from(source: native_tls::Error) -> CommonError {
+        CommonError::NativeTlsError(source)
+    }
+}
+
 impl<T: Into<CommonError>> From<T> for Error {
     fn from(source: T) -> Error {
         Error::Common(source.into())
     }
 }
 
-impl<'a> std::fmt::Display for Error {
+impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Error::AlreadyListening => write!(f, "Server is already listening"),
             Error::CacheMiss => write!(f, "Response not found in cache"),
-            error => std::fmt::Display::fmt(error, f),
+            Error::UnsupportedMetadataVersion(version) => {
+                write!(f, "Unsupported response metadata version: {}", version)
+            }
+            Error::Common(error) => error.fmt(f),
+            Error::Util(error) => write!(f, "{:?}", error),
         }
     }
 }
@@ -99,6 +163,14 @@ impl fmt::Display for CommonError {
             CommonError::HyperError(error) => error.fmt(f),
             CommonError::UrlError(error) => error.fmt(f),
             CommonError::ReqwestError(error) => error.fmt(f),
+            CommonError::CertificateError(error) => error.fmt(f),
+            CommonError::TlsError(error) => error.fmt(f),
+            CommonError::PemError(error) => error.fmt(f),
+            CommonError::Base64Error(error) => error.fmt(f),
+            CommonError::WebSocketError(error) => error.fmt(f),
+            CommonError::Http2Error(error) => error.fmt(f),
+            CommonError::HttpError(error) => error.fmt(f),
+            CommonError::NativeTlsError(error) => error.fmt(f),
         }
     }
 }
@@ -113,22 +185,25 @@ impl std::error::Error for CommonError {
             CommonError::HyperError(error) => Some(error),
             CommonError::UrlError(error) => Some(error),
             CommonError::ReqwestError(error) => Some(error),
+            CommonError::CertificateError(error) => Some(error),
+            CommonError::TlsError(error) => Some(error),
+            CommonError::PemError(error) => Some(error),
+            CommonError::Base64Error(error) => Some(error),
+            CommonError::WebSocketError(error) => Some(error.as_ref()),
+            CommonError::Http2Error(error) => Some(error),
+            CommonError::HttpError(error) => Some(error),
+            CommonError::NativeTlsError(error) => Some(error),
         }
     }
 }
 
-impl Into<iron::IronError> for CommonError {
-    fn into(self) -> iron::IronError {
-        iron::IronError::new(Box::new(self), iron::status::InternalServerError)
-    }
-}
-
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::AlreadyListening => None,
             Error::Common(error) => error.source(),
             Error::CacheMiss => None,
+            Error::UnsupportedMetadataVersion(_) => None,
             Error::Util(error) => error.source(),
         }
     }
@@ -143,7 +218,6 @@ impl std::fmt::Display for UtilError {
     }
 }
 
-
 impl std::error::Error for UtilError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         None
@@ -0,0 +1,185 @@
+use crate::result::Result;
+use std::time::Duration;
+
+/// How the upstream client treats redirects
+///
+/// Following redirects stores the final response under the original URL,
+/// so by default redirect responses are recorded as is.
+#[derive(Debug, Clone, Default)]
+pub enum RedirectPolicy {
+    /// Follow at most the given number of redirects
+    Limited(usize),
+    /// Return redirect responses as is
+    #[default]
+    None,
+}
+
+/// A client certificate for upstreams requiring mutual TLS
+#[derive(Clone)]
+pub struct ClientIdentity {
+    pub pkcs12_der: Vec<u8>,
+    password: String,
+}
+
+impl ClientIdentity {
+    pub fn from_pkcs12_der(pkcs12_der: &[u8], password: &str) -> Self {
+        Self {
+            pkcs12_der: pkcs12_der.to_vec(),
+            password: password.to_owned(),
+        }
+    }
+}
+
+/// Keeps the password out of logs
+impl std::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field(
+                "pkcs12_der",
+                &format_args!("[{} bytes]", self.pkcs12_der.len()),
+            )
+            .field("password", &"[REDACTED]")
+            .finish()
+    }
+}
+
+/// Settings of the client making requests to the upstream
+///
+/// A single client is built from the config and reused for all requests,
+/// so upstream connections are pooled.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// PEM-encoded certificates to trust in addition to the system ones
+    pub root_certificates_pem: Vec<Vec<u8>>,
+    /// Accept any upstream certificate, useful for staging environments
+    pub accept_invalid_certs: bool,
+    pub client_identity: Option<ClientIdentity>,
+    pub connect_timeout: Option<Duration>,
+    /// Timeout of the whole upstream request, including reading the response,
+    /// so it cuts long streams short
+    pub timeout: Option<Duration>,
+    /// Timeout of each read from the upstream, streams last as long as data keeps coming
+    pub read_timeout: Option<Duration>,
+    pub redirect_policy: RedirectPolicy,
+    /// HTTP proxy to reach the upstream through
+    pub proxy: Option<url::Url>,
+    /// Rewrite `Location` headers pointing at the upstream to Parody's address
+    pub rewrite_location: bool,
+}
+
+impl Config {
+    pub fn with_root_certificate_pem(mut self, certificate_pem: &[u8]) -> Self {
+        self.root_certificates_pem.push(certificate_pem.to_vec());
+        self
+    }
+
+    pub fn with_invalid_certs_accepted(mut self) -> Self {
+        self.accept_invalid_certs = true;
+        self
+    }
+
+    pub fn with_client_identity_pkcs12(mut self, pkcs12_der: &[u8], password: &str) -> Self {
+        self.client_identity = Some(ClientIdentity::from_pkcs12_der(pkcs12_der, password));
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
+
+    pub fn with_redirect_policy(mut self, redirect_policy: RedirectPolicy) -> Self {
+        self.redirect_policy = redirect_policy;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: url::Url) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_location_rewrite(mut self) -> Self {
+        self.rewrite_location = true;
+        self
+    }
+
+    /// TLS settings of the client for connections made without it, e.g. WebSocket ones
+    pub(crate) fn build_tls_connector(&self) -> Result<native_tls::TlsConnector> {
+        Ok(self.get_tls_connector_builder()?.build()?)
+    }
+
+    /// Like [`build_tls_connector`](#method.build_tls_connector), negotiating HTTP/2, e.g. for gRPC
+    pub(crate) fn build_http2_tls_connector(&self) -> Result<native_tls::TlsConnector> {
+        Ok(self
+            .get_tls_connector_builder()?
+            .request_alpns(&["h2"])
+            .build()?)
+    }
+
+    fn get_tls_connector_builder(&self) -> Result<native_tls::TlsConnectorBuilder> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+
+        for certificate_pem in &self.root_certificates_pem {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(certificate_pem)?);
+        }
+
+        if let Some(client_identity) = &self.client_identity {
+            builder.identity(native_tls::Identity::from_pkcs12(
+                &client_identity.pkcs12_der,
+                &client_identity.password,
+            )?);
+        }
+
+        Ok(builder)
+    }
+
+    pub fn build_client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .redirect(match self.redirect_policy {
+                RedirectPolicy::Limited(max) => reqwest::redirect::Policy::limited(max),
+                RedirectPolicy::None => reqwest::redirect::Policy::none(),
+            });
+
+        for certificate_pem in &self.root_certificates_pem {
+            builder =
+                builder.add_root_certificate(reqwest::Certificate::from_pem(certificate_pem)?);
+        }
+
+        if let Some(client_identity) = &self.client_identity {
+            builder = builder.identity(reqwest::Identity::from_pkcs12_der(
+                &client_identity.pkcs12_der,
+                &client_identity.password,
+            )?);
+        }
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        if let Some(read_timeout) = self.read_timeout {
+            builder = builder.read_timeout(read_timeout);
+        }
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+        }
+
+        Ok(builder.build()?)
+    }
+}
@@ -0,0 +1,118 @@
+//! Upstream connections made without the HTTP client, e.g. WebSocket and gRPC ones
+//!
+//! They follow the client config: the connect timeout, the read timeout and the proxy.
+
+use super::Config;
+use crate::result::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{future::Future, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Longest response head of a proxy answering a tunnel request
+const MAX_PROXY_HEAD_LENGTH: usize = 8192;
+
+/// Opens a connection to the host, tunnelled through the proxy of the config if it has one
+///
+/// The connect timeout covers the proxy handshake too.
+pub(crate) async fn connect(config: &Config, host: &str, port: u16) -> Result<TcpStream> {
+    let connecting = async {
+        match &config.proxy {
+            Some(proxy) => connect_through(proxy, host, port).await,
+            None => Ok(TcpStream::connect((host, port)).await?),
+        }
+    };
+
+    match config.connect_timeout {
+        Some(connect_timeout) => tokio::time::timeout(connect_timeout, connecting)
+            .await
+            .map_err(|_| timed_out(format!("Connecting to {}:{} timed out", host, port)))?,
+        None => connecting.await,
+    }
+}
+
+/// Waits for a read from the upstream, failing once it takes longer than the read timeout
+pub(crate) async fn read<T>(
+    read_timeout: Option<Duration>,
+    reading: impl Future<Output = T>,
+) -> Result<T> {
+    match read_timeout {
+        Some(read_timeout) => Ok(tokio::time::timeout(read_timeout, reading)
+            .await
+            .map_err(|_| timed_out("Reading from the upstream timed out".to_owned()))?),
+        None => Ok(reading.await),
+    }
+}
+
+/// Asks the HTTP proxy for a tunnel to the host with `CONNECT`
+async fn connect_through(proxy: &url::Url, host: &str, port: u16) -> Result<TcpStream> {
+    if proxy.scheme() != "http" {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "Connections are tunnelled through HTTP proxies only: {}",
+                proxy
+            ),
+        )
+        .into());
+    }
+
+    let mut stream = TcpStream::connect((
+        proxy.host_str().unwrap_or("localhost"),
+        proxy.port_or_known_default().unwrap_or(80),
+    ))
+    .await?;
+    let authority = match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            percent_encoding::percent_decode_str(proxy.username()).decode_utf8_lossy(),
+            percent_encoding::percent_decode_str(proxy.password().unwrap_or_default())
+                .decode_utf8_lossy()
+        );
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            STANDARD.encode(credentials)
+        );
+    }
+
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte, whatever follows the head belongs to the tunnel
+    let mut head = Vec::new();
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_PROXY_HEAD_LENGTH {
+            return Err(std::io::Error::other("Proxy response head is too long").into());
+        }
+
+        head.push(stream.read_u8().await?);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let _ = response.parse(&head);
+
+    match response.code {
+        Some(200..=299) => Ok(stream),
+        code => Err(std::io::Error::other(format!(
+            "Proxy refused the tunnel to {}: {}",
+            authority,
+            code.map(|code| code.to_string())
+                .unwrap_or_else(|| "invalid response".to_owned())
+        ))
+        .into()),
+    }
+}
+
+fn timed_out(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, message)
+}
@@ -0,0 +1,25 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ForwardError {
+    RequestToSelf,
+}
+
+impl fmt::Display for ForwardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ForwardError::RequestToSelf => {
+                write!(
+                    f,
+                    "Proxy request points to Parody itself, use an absolute URI"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ForwardError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
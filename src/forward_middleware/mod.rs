@@ -1,9 +1,35 @@
-extern crate tempfile;
-use crate::result::Result;
-use iron::typemap::Key;
-use std::{fs::File, io::Seek, path::PathBuf, str::FromStr};
+use crate::{
+    response::ParodyResponse,
+    result::Result,
+    server::{BeforeMiddleware, Body, HandlerError, Request},
+};
+use bytes::{Buf, Bytes};
+pub use config::{ClientIdentity, Config, RedirectPolicy};
+pub use error::ForwardError;
+use http_body_util::BodyExt;
+use std::{future::Future, io::Read, net::SocketAddr, path::PathBuf};
+use tokio::sync::mpsc::{channel, Receiver};
+
+mod config;
+pub(crate) mod connect;
+mod error;
 #[cfg(test)]
-mod test;
+pub(crate) mod test;
+
+/// Response body chunks read ahead of the client, bounds memory used by slow clients
+const BODY_CHUNKS_IN_FLIGHT: usize = 4;
+/// Headers of the client connection, RFC 9110 §7.6.1, not forwarded to the upstream
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
 
 /// Lazily makes requests to the Upstream
 ///
@@ -11,60 +37,230 @@ mod test;
 /// it inserts a request-making object to the environment
 /// and if necessary - the request might be executed.
 pub struct ForwardMiddleware {
-    upstream_url: reqwest::Url,
+    /// Where to forward requests to, `None` in the forward-proxy mode
+    upstream_url: Option<reqwest::Url>,
+    /// Shared by all forwarded requests to pool upstream connections
+    client: reqwest::Client,
+    rewrite_location: bool,
 }
 
 pub trait ProxyLoad {
-    fn load(self) -> Result<reqwest::Response>;
+    /// Executes the request streaming the incoming body to the upstream
+    fn load(self, body: Body) -> impl Future<Output = Result<UpstreamResponse>> + Send;
+}
+
+/// The request to the upstream, inserted into request extensions
+#[derive(Clone)]
+pub struct ProxyResponse(pub ProxyRequest);
+
+/// A request to the upstream and the client to execute it with
+#[derive(Clone)]
+pub struct ProxyRequest {
+    client: reqwest::Client,
+    method: reqwest::Method,
+    url: url::Url,
+    headers: reqwest::header::HeaderMap,
+    /// Upstream URL the request path is relative to, set when `Location` should be rewritten
+    location_base: Option<url::Url>,
+    /// Whether the incoming request has a body to forward
+    has_body: bool,
 }
 
-#[derive(Clone, Copy)]
-pub struct ProxyResponse;
+/// Reads a body received by another task
+///
+/// Storage saves bodies with blocking I/O, so it's read on a blocking thread
+/// while the upstream response is polled on the runtime.
+struct ChannelReader {
+    receiver: Receiver<std::io::Result<Bytes>>,
+    chunk: Bytes,
+}
 
-impl ProxyLoad for reqwest::Request {
-    fn load(self) -> Result<reqwest::Response> {
-        trace!("Loading proxy response: {} {}", self.method(), self.url());
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() && !buf.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                // The sender is gone once the whole body is received
+                None => return Ok(0),
+            }
+        }
 
-        let response = reqwest::Client::new()
-            .execute(self)
-            .map_err(|error| error.into());
+        let read = buf.len().min(self.chunk.len());
+        self.chunk.copy_to_slice(&mut buf[..read]);
 
-        response
+        Ok(read)
     }
 }
 
-impl Key for ProxyResponse {
-    type Value = reqwest::Request;
+impl ProxyRequest {
+    pub fn url(&self) -> &url::Url {
+        &self.url
+    }
+}
+
+impl ProxyLoad for ProxyRequest {
+    async fn load(self, body: Body) -> Result<UpstreamResponse> {
+        trace!("Loading proxy response: {} {}", self.method, self.url);
+
+        let mut request = self
+            .client
+            .request(self.method, self.url)
+            .headers(self.headers);
+
+        if self.has_body {
+            request = request.body(reqwest::Body::wrap_stream(body.into_data_stream()));
+        }
+
+        let mut response = request.send().await?;
+        let mut headers: Vec<(String, Vec<u8>)> = response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_vec()))
+            .collect();
+
+        if let Some(location_base) = &self.location_base {
+            for (name, value) in headers.iter_mut() {
+                if !name.eq_ignore_ascii_case("location") {
+                    continue;
+                }
+
+                if let Some(location) = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|location| get_relative_location(location, location_base))
+                {
+                    debug!(target: "forward", "Rewrote location to: {}", location);
+                    *value = location.into_bytes();
+                }
+            }
+        }
+
+        let status = response.status();
+        let version = response.version();
+        let url = response.url().clone();
+        let (sender, receiver) = channel(BODY_CHUNKS_IN_FLIGHT);
+
+        tokio::spawn(async move {
+            loop {
+                let chunk = match response.chunk().await {
+                    Ok(Some(chunk)) => Ok(chunk),
+                    Ok(None) => return,
+                    Err(error) => Err(std::io::Error::other(error)),
+                };
+                let is_error = chunk.is_err();
+
+                if sender.send(chunk).await.is_err() || is_error {
+                    return;
+                }
+            }
+        });
+
+        Ok(UpstreamResponse {
+            status,
+            version,
+            url,
+            headers,
+            body: ChannelReader {
+                receiver,
+                chunk: Bytes::new(),
+            },
+        })
+    }
+}
+
+/// A response from the upstream with headers prepared for storing
+///
+/// The body is read with blocking I/O, so it must not be read on the runtime.
+pub struct UpstreamResponse {
+    status: reqwest::StatusCode,
+    version: reqwest::Version,
+    url: url::Url,
+    headers: Vec<(String, Vec<u8>)>,
+    body: ChannelReader,
+}
+
+impl ParodyResponse for UpstreamResponse {
+    fn get_status(&self) -> u16 {
+        self.status.as_u16()
+    }
+
+    fn get_headers(&self) -> Vec<(String, Vec<u8>)> {
+        self.headers.clone()
+    }
+
+    fn get_body_reader(&mut self) -> &mut dyn std::io::Read {
+        &mut self.body
+    }
+
+    fn get_reason(&self) -> Option<String> {
+        self.status.canonical_reason().map(str::to_owned)
+    }
+
+    fn get_version(&self) -> Option<String> {
+        Some(format!("{:?}", self.version))
+    }
+
+    fn get_url(&self) -> Option<url::Url> {
+        Some(self.url.clone())
+    }
 }
 
 impl ForwardMiddleware {
     pub fn new(upstream_url: url::Url) -> Self {
         Self {
-            upstream_url: upstream_url,
+            upstream_url: Some(upstream_url),
+            client: new_default_client(),
+            rewrite_location: false,
         }
     }
-}
 
-impl iron::BeforeMiddleware for ForwardMiddleware {
-    fn before(&self, req: &mut iron::Request) -> iron::IronResult<()> {
-        trace!(
-            "In ForwardMiddleware for: {} {:.32}",
-            req.method,
-            req.url
-        );
+    /// Forwards requests to hosts from their absolute-form URIs
+    ///
+    /// Use it when clients are configured to use Parody as an HTTP proxy.
+    pub fn proxy() -> Self {
+        Self {
+            upstream_url: None,
+            client: new_default_client(),
+            rewrite_location: false,
+        }
+    }
 
-        let mut new_url_path = PathBuf::from(self.upstream_url.path());
-        new_url_path = new_url_path.join(req.url.path().join("/"));
-        let mut new_url: url::Url = req.url.clone().into();
+    /// Makes upstream requests with a client built from the config
+    pub fn with_config(mut self, config: &Config) -> Result<Self> {
+        self.client = config.build_client()?;
+        self.rewrite_location = config.rewrite_location;
+        Ok(self)
+    }
+
+    fn get_upstream_request_url(
+        &self,
+        req: &Request,
+    ) -> std::result::Result<url::Url, HandlerError> {
+        let upstream_url = match &self.upstream_url {
+            Some(upstream_url) => upstream_url,
+            None => {
+                if points_to(&req.url, &req.local_addr) {
+                    return Err(HandlerError::new(
+                        ForwardError::RequestToSelf,
+                        http::StatusCode::BAD_REQUEST,
+                    ));
+                }
+
+                return Ok(req.url.clone());
+            }
+        };
+
+        let mut new_url_path = PathBuf::from(upstream_url.path());
+        new_url_path = new_url_path.join(req.url.path().trim_start_matches('/'));
+        let mut new_url = req.url.clone();
 
         new_url
-            .set_host(self.upstream_url.host_str())
+            .set_host(upstream_url.host_str())
             .expect("Copying host should always succeed");
         new_url
-            .set_port(self.upstream_url.port())
+            .set_port(upstream_url.port())
             .expect("Copying port should always succeed");
         new_url
-            .set_scheme(self.upstream_url.scheme())
+            .set_scheme(upstream_url.scheme())
             .expect("Copying scheme should always succeed");
         new_url.set_path(
             new_url_path
@@ -72,46 +268,161 @@ impl iron::BeforeMiddleware for ForwardMiddleware {
                 .expect("New path should not contain invalid characters"),
         );
 
+        Ok(new_url)
+    }
+
+    fn get_upstream_host(&self, upstream_request_url: &url::Url) -> String {
+        match &self.upstream_url {
+            Some(upstream_url) => upstream_url
+                .host_str()
+                .expect("There should be a host string in the upstream URL")
+                .to_owned(),
+            None => {
+                let host = upstream_request_url
+                    .host_str()
+                    .expect("Absolute request URI should have a host");
+
+                match upstream_request_url.port() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host.to_owned(),
+                }
+            }
+        }
+    }
+}
+
+/// Client recording redirects as is, like the default config does
+fn new_default_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("Default upstream client should always build")
+}
+
+/// Makes the location relative to the base if it points below it
+///
+/// Relative references resolve against whatever address Parody is reached at,
+/// so recorded redirects lead back to Parody instead of the upstream.
+fn get_relative_location(location: &str, base: &url::Url) -> Option<String> {
+    let location = url::Url::parse(location).ok()?;
+
+    if location.scheme() != base.scheme()
+        || location.host_str() != base.host_str()
+        || location.port_or_known_default() != base.port_or_known_default()
+    {
+        return None;
+    }
+
+    let base_path = base.path().trim_end_matches('/');
+    let path = location.path().strip_prefix(base_path)?;
+
+    if !path.is_empty() && !path.starts_with('/') {
+        return None;
+    }
+
+    let mut relative = format!("/{}", path.trim_start_matches('/'));
+
+    if let Some(query) = location.query() {
+        relative.push('?');
+        relative.push_str(query);
+    }
+
+    if let Some(fragment) = location.fragment() {
+        relative.push('#');
+        relative.push_str(fragment);
+    }
+
+    Some(relative)
+}
+
+/// Whether the URL points to the given local address
+///
+/// Forwarding such requests would make Parody call itself in a loop.
+fn points_to(url: &url::Url, local_addr: &SocketAddr) -> bool {
+    if url.port_or_known_default() != Some(local_addr.port()) {
+        return false;
+    }
+
+    match url.host() {
+        Some(url::Host::Domain(domain)) => domain == "localhost",
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback() || ip == local_addr.ip(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback() || ip == local_addr.ip(),
+        None => false,
+    }
+}
+
+impl BeforeMiddleware for ForwardMiddleware {
+    fn before(&self, req: &mut Request) -> std::result::Result<(), HandlerError> {
+        trace!("In ForwardMiddleware for: {} {:.32}", req.method, req.url);
+
+        let new_url = self.get_upstream_request_url(req)?;
+        let host = self.get_upstream_host(&new_url);
+        let location_base = match (self.rewrite_location, &self.upstream_url) {
+            (false, _) => None,
+            (true, Some(upstream_url)) => Some(upstream_url.clone()),
+            (true, None) => new_url.join("/").ok(),
+        };
+
         trace!("New forward URL: {:32}", new_url.as_str());
 
-        let mut proxy_request = reqwest::Client::new().request(
-            reqwest::Method::from_str(req.method.as_ref())
-                .expect("Iron method should convert to Reqwest method"),
-            new_url,
-        );
+        let mut headers = reqwest::header::HeaderMap::new();
+        let connection_headers = get_connection_headers(&req.headers);
 
-        for header in req.headers.iter() {
-            if header.name() == "host" {
-                trace!(target: "forward", "Skipped header: {}: {}", header.name(), header.value_string());
+        for (name, value) in req.headers.iter() {
+            if name == reqwest::header::HOST
+                || HOP_BY_HOP_HEADERS.contains(&name.as_str())
+                || connection_headers.contains(name)
+            {
+                trace!(target: "forward", "Skipped header: {}: {:?}", name, value);
                 continue;
             }
 
-            trace!(target: "forward", "Setting header: {}: {}", header.name(), header.value_string());
-            proxy_request = proxy_request.header(header.name(), header.value_string());
+            trace!(target: "forward", "Setting header: {}: {:?}", name, value);
+            headers.append(name.clone(), value.clone());
         }
 
-        let host = self
-            .upstream_url
-            .host_str()
-            .expect("There should be a host string in the upstream URL");
-
         trace!(target: "forward", "Setting header: host: {}", host);
-        proxy_request = proxy_request.header("host", host);
+        headers.insert(
+            reqwest::header::HOST,
+            host.parse()
+                .map_err(|error| HandlerError::new(error, http::StatusCode::BAD_REQUEST))?,
+        );
 
-        let mut body_file: File = tempfile::tempfile().expect("Temporary file should be created");
-        std::io::copy(&mut req.body, &mut body_file).expect("Should never happen when testing");
-        body_file
-            .seek(std::io::SeekFrom::Start(0))
-            .expect("Should neven happen when testing");
+        // The body is read only if the request is forwarded, see `ProxyLoad::load`
+        req.extensions.insert(ProxyResponse(ProxyRequest {
+            client: self.client.clone(),
+            method: req.method.clone(),
+            url: new_url,
+            has_body: has_body(&req.headers),
+            headers,
+            location_base,
+        }));
 
-        proxy_request = proxy_request.body(body_file);
+        Ok(())
+    }
+}
 
-        req.extensions.insert::<ProxyResponse>(
-            proxy_request
-                .build()
-                .expect("Request conversion should never fail"),
-        );
+/// Headers the `Connection` header names as describing the client connection only
+fn get_connection_headers(headers: &http::HeaderMap) -> Vec<http::HeaderName> {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| http::HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect()
+}
 
-        Ok(())
+/// Whether the request has a body, bodies are forwarded only then
+fn has_body(headers: &http::HeaderMap) -> bool {
+    if headers.contains_key(http::header::TRANSFER_ENCODING) {
+        return true;
     }
+
+    headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.trim().parse::<u64>().ok())
+        .map(|length| length > 0)
+        .unwrap_or(false)
 }
@@ -1,208 +1,186 @@
 use super::*;
-use crate::iron::Set;
-use iron::{typemap::Key, BeforeMiddleware, Chain, Iron, IronResult, Plugin};
-use persistent::Read;
-
-#[derive(Clone, Copy)]
-struct TestStatus;
-impl Key for TestStatus {
-    type Value = iron::status::Status;
-}
-
-#[derive(Clone, Copy)]
-struct TestHeaders;
-impl Key for TestHeaders {
-    type Value = Vec<(&'static str, &'static str)>;
-}
-
-#[derive(Clone, Copy)]
-struct TestBody;
-impl Key for TestBody {
-    type Value = Vec<u8>;
+use crate::{
+    response::{Response, WriteBody},
+    server::{body, Chain, HandlerResult, Server},
+    Parody,
+};
+use std::{
+    io::Write,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Longer than a few chunks of any reader or writer on the way
+const LARGE_BODY_LENGTH: usize = 3 * 64 * 1024 + 17;
+
+fn serve(chain: Chain) -> Parody {
+    Parody::run(Server::new(Vec::new(), chain).expect("Test server should be created"))
+        .expect("Test server should always start successfully")
 }
 
-fn respond_from_environment(req: &mut iron::Request) -> IronResult<iron::Response> {
-    trace!(target: "test", "Entered respond_from_environment");
+fn start_upstream(status: http::StatusCode, headers: Vec<(&'static str, &'static str)>) -> Parody {
+    let body = b"{\"Lorem\": \"ipsum\"}".to_vec();
 
-    let a_status = req
-        .get::<Read<TestStatus>>()
-        .expect("Cannot get status for test request");
-    let a_headers_raw = req
-        .get::<Read<TestHeaders>>()
-        .expect("Cannot get response headers");
-    let a_body = req
-        .get::<Read<TestBody>>()
-        .expect("Cannot get response headers");
+    serve(Chain::new(move |_req: Request| {
+        trace!(target: "test", "Responding from upstream");
 
-    let mut response = iron::Response::with((*a_status, (*a_body).clone()));
-
-    for (key, value) in a_headers_raw.iter() {
-        trace!("Setting header: {}={}", key, value);
+        let mut response = Response::with_status(status);
         response
             .headers
-            .append_raw(*key, (*value).as_bytes().to_vec());
-    }
+            .insert(http::header::CONTENT_LENGTH, body.len().into());
 
-    Ok(response)
+        for (name, value) in headers.iter() {
+            trace!("Setting header: {}={}", name, value);
+            response
+                .headers
+                .append(*name, http::HeaderValue::from_static(value));
+        }
+
+        response.body = Some(Box::new(body.clone()));
+        async move { Ok(response) }
+    }))
 }
 
-fn start_upstream(
-    status: iron::status::Status,
-    headers: Vec<(&'static str, &'static str)>,
-) -> Result<iron::Listening> {
-    let mut chain = Chain::new(&respond_from_environment);
-    chain.link(Read::<TestStatus>::both(status));
-    chain.link(Read::<TestHeaders>::both(headers));
-    chain.link(Read::<TestBody>::both(
-        "{\"Lorem\": \"ipsum\"}".as_bytes().to_vec(),
-    ));
-    Iron::new(chain)
-        .http("localhost:3000")
-        .map_err(|err| err.into())
+fn start_test_service(middleware: ForwardMiddleware) -> Parody {
+    let mut test_chain = Chain::new(forward_from_environment);
+    test_chain.link_before(middleware);
+    serve(test_chain)
 }
 
-fn to_iron_response(response: reqwest::Response) -> iron::Response {
-    let mut result = iron::Response::with(iron::status::Unregistered(response.status().as_u16()));
+/// Reads the body, so it must be called on a blocking thread
+fn to_response(mut response: UpstreamResponse) -> Response {
+    let mut result = Response::with_status(
+        http::StatusCode::from_u16(response.get_status()).expect("Upstream status is valid"),
+    );
 
-    for (name, value) in response.headers() {
-        result
-            .headers
-            .append_raw(name.as_str().to_owned(), value.as_bytes().to_vec());
+    for (name, value) in response.get_headers() {
+        if !name.eq_ignore_ascii_case("transfer-encoding") {
+            result.headers.append(
+                http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                http::HeaderValue::from_bytes(&value).unwrap(),
+            );
+        }
     }
 
-    result = result.set(iron::response::BodyReader(response));
+    let mut body = Vec::new();
+    response
+        .get_body_reader()
+        .read_to_end(&mut body)
+        .expect("Upstream body should be read in tests");
 
+    result.body = Some(Box::new(body));
     result
 }
 
-fn forward_from_environment(req: &mut iron::Request) -> IronResult<iron::Response> {
-    let proxy_response = req
+async fn forward_from_environment(mut req: Request) -> HandlerResult {
+    let ProxyResponse(proxy_request) = req
         .extensions
         .remove::<ProxyResponse>()
         .expect("Proxy response should exist in tests");
 
-    proxy_response
-        .load()
-        .map(to_iron_response)
-        .map_err(|error| iron::IronError::new(error, iron::status::InternalServerError))
+    let response = proxy_request.load(req.body).await?;
+
+    Ok(tokio::task::spawn_blocking(move || to_response(response))
+        .await
+        .expect("Upstream body should be read in tests"))
 }
 
 fn init() {
     let _ = env_logger::builder().is_test(true).try_init();
 }
 
-struct DummyNetworkStream {
-    response: std::io::Cursor<String>,
-}
-
-impl DummyNetworkStream {
-    fn new() -> Self {
-        let content = String::from("GET / HTTP/1.1\nHost: localhost\n\n");
-
-        Self {
-            response: std::io::Cursor::new(content),
-        }
+fn new_request(headers: http::HeaderMap) -> Request {
+    Request {
+        method: http::Method::GET,
+        url: url::Url::parse("http://localhost/").unwrap(),
+        headers,
+        body: body::empty(),
+        extensions: http::Extensions::new(),
+        local_addr: SocketAddr::from(([127, 0, 0, 1], 80)),
     }
 }
 
-impl std::io::Write for DummyNetworkStream {
-    fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
-        unimplemented!()
-    }
-    fn write(&mut self, _: &[u8]) -> std::result::Result<usize, std::io::Error> {
-        unimplemented!()
-    }
-}
+#[test]
+fn forward_middleware_before_should_replace_request_schema_with_upstream_schema() {
+    init();
+    let middleware =
+        ForwardMiddleware::new(url::Url::from_str("https://example.com").expect("URL is valid"));
+    let mut request = new_request(http::HeaderMap::new());
 
-impl std::io::Read for DummyNetworkStream {
-    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
-        std::io::copy(&mut self.response, &mut std::io::Cursor::new(buf)).map(|n| n as usize)
-    }
-}
+    middleware
+        .before(&mut request)
+        .expect("Before middleware should succeed");
 
-impl hyper::net::NetworkStream for DummyNetworkStream {
-    fn set_write_timeout(
-        &self,
-        _: std::option::Option<std::time::Duration>,
-    ) -> std::result::Result<(), std::io::Error> {
-        unimplemented!()
-    }
-    fn set_read_timeout(
-        &self,
-        _: std::option::Option<std::time::Duration>,
-    ) -> std::result::Result<(), std::io::Error> {
-        unimplemented!()
-    }
-    fn peer_addr(&mut self) -> std::result::Result<std::net::SocketAddr, std::io::Error> {
-        unimplemented!()
-    }
+    let ProxyResponse(cached_request) = request
+        .extensions
+        .get::<ProxyResponse>()
+        .expect("'before' method shoud set proxy response");
+
+    assert_eq!(request.url.scheme(), "http");
+    assert_eq!(cached_request.url().scheme(), "https");
 }
 
 #[test]
-fn forward_middleware_before_should_replace_request_schema_with_upstream_schema() {
+fn forward_middleware_before_should_strip_hop_by_hop_headers() {
     init();
     let middleware =
         ForwardMiddleware::new(url::Url::from_str("https://example.com").expect("URL is valid"));
-
-    let addr = std::net::SocketAddr::new(
-        std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
-        80,
-    );
-
-    let mut network_stream = DummyNetworkStream::new();
-    let mut stream =
-        hyper::buffer::BufReader::new(&mut network_stream as &mut dyn hyper::net::NetworkStream);
-    let http_request = match iron::request::HttpRequest::new(&mut stream, addr) {
-        Ok(request) => request,
-        Err(error) => panic!("Cannot create HttpRequest: {}", error),
-    };
-    let mut request =
-        iron::Request::from_http(http_request, addr, &iron::Protocol::http()).unwrap();
+    let mut headers = http::HeaderMap::new();
+    for (name, value) in [
+        ("accept", "text/plain"),
+        ("connection", "keep-alive, X-Hop"),
+        ("keep-alive", "timeout=5"),
+        ("proxy-authorization", "Basic bG9yZW06aXBzdW0="),
+        ("proxy-connection", "keep-alive"),
+        ("te", "trailers"),
+        ("trailer", "x-checksum"),
+        ("upgrade", "h2c"),
+        ("x-hop", "1"),
+    ] {
+        headers.append(name, http::HeaderValue::from_static(value));
+    }
+    let mut request = new_request(headers);
 
     middleware
         .before(&mut request)
         .expect("Before middleware should succeed");
 
-    let cached_request: &reqwest::Request = request
+    let ProxyResponse(cached_request) = request
         .extensions
         .get::<ProxyResponse>()
         .expect("'before' method shoud set proxy response");
+    let forwarded: Vec<(&str, &str)> = cached_request
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+        .collect();
 
-    assert_eq!(request.url.scheme(), "http");
-    assert_eq!(cached_request.url().scheme(), "https");
+    assert_eq!(
+        forwarded,
+        vec![("accept", "text/plain"), ("host", "example.com")]
+    );
 }
 
 #[test]
 fn forward_middleware_should_fill_environment_with_response() {
     init();
-    let mut upstream_guard = start_upstream(
-        iron::status::Accepted,
+    let upstream_guard = start_upstream(
+        http::StatusCode::ACCEPTED,
         vec![("content-type", "application/json")],
-    )
-    .expect("Upstream service should start");
+    );
 
-    let mut test_chain = iron::Chain::new(&forward_from_environment);
-    test_chain.link_before(ForwardMiddleware::new(
-        url::Url::from_str("http://localhost:3000").expect("Test URL is valid"),
+    let test_guard = start_test_service(ForwardMiddleware::new(
+        url::Url::from_str(&format!("http://localhost:{}", upstream_guard.port()))
+            .expect("Test URL is valid"),
     ));
-    let mut test_guard = Iron::new(test_chain)
-        .listen(
-            hyper::net::HttpListener::new(std::net::SocketAddr::new(
-                std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
-                0,
-            ))
-            .expect("HTTP listener should be created in tests"),
-            iron::Protocol::http(),
-        )
-        .expect("Test service should always start successfully");
 
-    let mut response = reqwest::get(&format!("http://localhost:{}", test_guard.socket.port()))
+    let response = reqwest::blocking::get(format!("http://localhost:{}", test_guard.port()))
         .expect("Cache request succeeded");
 
-    upstream_guard.close().unwrap();
-    test_guard.close().unwrap();
-
-    assert_eq!(response.status(), iron::status::Accepted.to_u16());
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
     let headers_raw: Vec<(&str, &str)> = response
         .headers()
         .iter()
@@ -224,3 +202,817 @@ fn forward_middleware_should_fill_environment_with_response() {
         "{\"Lorem\": \"ipsum\"}"
     );
 }
+
+#[test]
+fn forward_middleware_in_proxy_mode_should_forward_to_absolute_uri_host() {
+    init();
+    let upstream_guard = start_upstream(
+        http::StatusCode::ACCEPTED,
+        vec![("content-type", "application/json")],
+    );
+    let test_guard = start_test_service(ForwardMiddleware::proxy());
+
+    let client = reqwest::blocking::Client::builder()
+        .proxy(
+            reqwest::Proxy::http(format!("http://127.0.0.1:{}", test_guard.port()))
+                .expect("Proxy URL is valid"),
+        )
+        .build()
+        .expect("Proxy client should be built");
+
+    let response = client
+        .get(format!("http://127.0.0.1:{}/", upstream_guard.port()))
+        .send()
+        .expect("Proxy request succeeded");
+
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    assert_eq!(
+        response.text().expect("Response should have text body"),
+        "{\"Lorem\": \"ipsum\"}"
+    );
+}
+
+#[test]
+fn forward_middleware_in_proxy_mode_when_request_points_to_itself_should_fail() {
+    init();
+    let test_guard = start_test_service(ForwardMiddleware::proxy());
+
+    let response = reqwest::blocking::get(format!("http://127.0.0.1:{}/", test_guard.port()))
+        .expect("Request succeeded");
+
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+fn start_tls_upstream() -> crate::Parody {
+    crate::start_tls(
+        url::Url::from_str("http://example.com").unwrap(),
+        crate::storage::Config::default().with_root_dir(
+            std::path::Path::new(file!())
+                .parent()
+                .expect("source file always has a parent directory")
+                .parent()
+                .expect("module directory always has a parent directory")
+                .join("tls")
+                .join("test_files")
+                .join("localhost"),
+        ),
+        crate::ServerCertificate::SelfSigned,
+    )
+    .expect("TLS upstream should start")
+}
+
+#[test]
+fn forward_middleware_with_root_certificate_should_trust_upstream() {
+    init();
+    let upstream = start_tls_upstream();
+    let config = Config::default().with_root_certificate_pem(
+        upstream
+            .certificate_pem()
+            .expect("TLS upstream should have a certificate")
+            .as_bytes(),
+    );
+    let test_guard = start_test_service(
+        ForwardMiddleware::new(
+            url::Url::parse(&format!("https://localhost:{}", upstream.port())).unwrap(),
+        )
+        .with_config(&config)
+        .expect("Upstream client should be built"),
+    );
+
+    let response = reqwest::blocking::get(format!("http://127.0.0.1:{}/", test_guard.port()))
+        .expect("Request succeeded");
+
+    assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+    assert_eq!(
+        response.text().expect("Response should have text body"),
+        "{\"lorem\": \"ipsum\"}\n"
+    );
+}
+
+#[test]
+fn forward_middleware_without_root_certificate_should_reject_self_signed_upstream() {
+    init();
+    let upstream = start_tls_upstream();
+    let test_guard = start_test_service(ForwardMiddleware::new(
+        url::Url::parse(&format!("https://localhost:{}", upstream.port())).unwrap(),
+    ));
+
+    let response = reqwest::blocking::get(format!("http://127.0.0.1:{}/", test_guard.port()))
+        .expect("Request succeeded");
+
+    assert_eq!(
+        response.status(),
+        reqwest::StatusCode::INTERNAL_SERVER_ERROR
+    );
+}
+
+#[test]
+fn config_with_invalid_root_certificate_should_fail_to_build_client() {
+    let config = Config::default().with_root_certificate_pem(b"not a certificate");
+
+    assert!(config.build_client().is_err());
+}
+
+#[test]
+fn forward_middleware_should_not_follow_redirects() {
+    init();
+    let upstream_guard = start_upstream(
+        http::StatusCode::FOUND,
+        vec![("location", "http://example.com/dashboard")],
+    );
+    let test_guard = start_test_service(ForwardMiddleware::new(
+        url::Url::parse(&format!("http://localhost:{}", upstream_guard.port())).unwrap(),
+    ));
+
+    let response = reqwest::blocking::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("Test client should be built")
+        .get(format!("http://127.0.0.1:{}/login", test_guard.port()))
+        .send()
+        .expect("Request succeeded");
+
+    assert_eq!(response.status(), reqwest::StatusCode::FOUND);
+    assert_eq!(
+        response.headers()[reqwest::header::LOCATION],
+        "http://example.com/dashboard"
+    );
+}
+
+#[test]
+fn get_relative_location_when_location_points_to_upstream_should_strip_origin() {
+    let base = url::Url::parse("https://example.com/api/").unwrap();
+
+    assert_eq!(
+        get_relative_location("https://example.com/api/dashboard?tab=1#top", &base),
+        Some("/dashboard?tab=1#top".to_owned())
+    );
+    assert_eq!(
+        get_relative_location("https://example.com:443/api", &base),
+        Some("/".to_owned())
+    );
+}
+
+#[test]
+fn get_relative_location_when_location_points_elsewhere_should_keep_it() {
+    let base = url::Url::parse("https://example.com/api").unwrap();
+
+    assert_eq!(
+        get_relative_location("http://example.com/api/x", &base),
+        None
+    );
+    assert_eq!(
+        get_relative_location("https://example.org/api/x", &base),
+        None
+    );
+    assert_eq!(
+        get_relative_location("https://example.com/apix", &base),
+        None
+    );
+    assert_eq!(get_relative_location("https://example.com/x", &base), None);
+    assert_eq!(get_relative_location("/api/x", &base), None);
+}
+
+async fn echo_body(req: Request) -> HandlerResult {
+    let body = req
+        .body
+        .collect()
+        .await
+        .expect("Request body should be read in tests")
+        .to_bytes()
+        .to_vec();
+
+    let mut response = Response::with_status(http::StatusCode::OK);
+    response
+        .headers
+        .insert(http::header::CONTENT_LENGTH, body.len().into());
+    response.body = Some(Box::new(body));
+    Ok(response)
+}
+
+fn start_echo_test_service() -> (Parody, Parody) {
+    let upstream_guard = serve(Chain::new(echo_body));
+    let test_guard = start_test_service(ForwardMiddleware::new(
+        url::Url::parse(&format!("http://localhost:{}", upstream_guard.port())).unwrap(),
+    ));
+
+    (upstream_guard, test_guard)
+}
+
+fn get_large_body() -> Vec<u8> {
+    (0..LARGE_BODY_LENGTH)
+        .map(|index| (index % 251) as u8)
+        .collect()
+}
+
+#[test]
+fn forward_middleware_should_stream_sized_request_body() {
+    init();
+    let (_upstream_guard, test_guard) = start_echo_test_service();
+    let body = get_large_body();
+
+    let mut response = reqwest::blocking::Client::new()
+        .post(format!("http://127.0.0.1:{}/", test_guard.port()))
+        .body(body.clone())
+        .send()
+        .expect("Request succeeded");
+
+    let mut echoed = Vec::new();
+    response.copy_to(&mut echoed).unwrap();
+
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(echoed, body);
+}
+
+#[test]
+fn forward_middleware_should_stream_chunked_request_body() {
+    init();
+    let (_upstream_guard, test_guard) = start_echo_test_service();
+    let body = get_large_body();
+
+    let mut response = reqwest::blocking::Client::new()
+        .post(format!("http://127.0.0.1:{}/", test_guard.port()))
+        .body(reqwest::blocking::Body::new(std::io::Cursor::new(
+            body.clone(),
+        )))
+        .send()
+        .expect("Request succeeded");
+
+    let mut echoed = Vec::new();
+    response.copy_to(&mut echoed).unwrap();
+
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(echoed, body);
+}
+
+#[test]
+fn proxy_request_load_when_body_cannot_be_read_should_fail() {
+    struct BrokenBody;
+
+    impl http_body::Body for BrokenBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_frame(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<std::io::Result<http_body::Frame<Bytes>>>> {
+            std::task::Poll::Ready(Some(Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "client is gone",
+            ))))
+        }
+    }
+
+    init();
+    let (upstream_guard, _test_guard) = start_echo_test_service();
+    let mut headers = http::HeaderMap::new();
+    headers.insert(http::header::CONTENT_LENGTH, 10.into());
+    let mut request = new_request(headers);
+
+    ForwardMiddleware::new(
+        url::Url::parse(&format!("http://localhost:{}", upstream_guard.port())).unwrap(),
+    )
+    .before(&mut request)
+    .expect("Before middleware should succeed");
+
+    let ProxyResponse(proxy_request) = request
+        .extensions
+        .remove::<ProxyResponse>()
+        .expect("'before' method shoud set proxy response");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    assert!(runtime
+        .block_on(proxy_request.load(BrokenBody.boxed_unsync()))
+        .is_err());
+}
+
+#[tokio::test]
+async fn start_async_should_record_and_replay_without_blocking_runtime() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let upstream = start_upstream(http::StatusCode::OK, vec![]);
+    let parody = crate::start_async(
+        url::Url::parse(&format!("http://localhost:{}", upstream.port())).unwrap(),
+        crate::storage::Config::default().with_root_dir(storage_root.path().into()),
+    )
+    .await
+    .expect("Parody should start in an async test");
+    let url = format!("http://localhost:{}/a", parody.port());
+
+    let recorded = reqwest::get(&url).await.unwrap().text().await.unwrap();
+    drop(upstream);
+    let replayed = reqwest::get(&url).await.unwrap().text().await.unwrap();
+
+    assert_eq!(recorded, "{\"Lorem\": \"ipsum\"}");
+    assert_eq!(replayed, recorded);
+}
+
+#[tokio::test]
+async fn logged_requests_should_list_requests_in_order() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let upstream = start_upstream(http::StatusCode::OK, vec![]);
+    let parody = crate::start_async(
+        url::Url::parse(&format!("http://localhost:{}", upstream.port())).unwrap(),
+        crate::storage::Config::default().with_root_dir(storage_root.path().into()),
+    )
+    .await
+    .expect("Parody should start in an async test");
+
+    for path in &["a", "b"] {
+        reqwest::get(format!("http://localhost:{}/{}", parody.port(), path))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+    }
+
+    let requests = parody
+        .logged_requests()
+        .await
+        .expect("Requests should be logged");
+    assert_eq!(parody.request_count().await, Some(2));
+    assert_eq!(requests[0].method(), "GET");
+    assert_eq!(requests[0].url().path(), "/a");
+    assert_eq!(requests[1].url().path(), "/b");
+}
+
+#[tokio::test]
+async fn shutdown_should_close_port() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let parody = crate::start_async(
+        url::Url::parse("http://localhost").unwrap(),
+        crate::storage::Config::default().with_root_dir(storage_root.path().into()),
+    )
+    .await
+    .expect("Parody should start in an async test");
+    let port = parody.port();
+
+    parody.shutdown().await;
+
+    assert!(tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .is_err());
+}
+
+/// Writes the body after a delay, so recording it takes a while
+struct SlowBody {
+    body: &'static [u8],
+    /// Fails after the delay instead of writing the body
+    fails: bool,
+}
+
+impl WriteBody for SlowBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> std::io::Result<()> {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        if self.fails {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "upstream is gone",
+            ));
+        }
+
+        res.write_all(self.body)
+    }
+}
+
+/// Upstream responding slowly, with a failing body to the given first calls
+fn start_slow_upstream(calls: Arc<AtomicUsize>, failing_calls: usize) -> Parody {
+    serve(Chain::new(move |_req: Request| {
+        let call = calls.fetch_add(1, Ordering::SeqCst);
+
+        let mut response = Response::with_status(http::StatusCode::OK);
+        response.body = Some(Box::new(SlowBody {
+            body: b"lorem ipsum",
+            fails: call < failing_calls,
+        }));
+        async move { Ok(response) }
+    }))
+}
+
+/// Sends the same request from many clients at once, returns bodies received in full
+fn get_concurrently(url: &str, clients: usize) -> Vec<String> {
+    let client = reqwest::blocking::Client::new();
+    let clients: Vec<_> = (0..clients)
+        .map(|_| {
+            let client = client.clone();
+            let url = url.to_owned();
+            std::thread::spawn(move || {
+                client
+                    .get(url)
+                    .send()
+                    .ok()
+                    .and_then(|response| response.text().ok())
+            })
+        })
+        .collect();
+
+    clients
+        .into_iter()
+        .filter_map(|client| client.join().expect("Client should not panic"))
+        .collect()
+}
+
+fn start_parody_for(upstream: &Parody, storage_root: &tempfile::TempDir) -> Parody {
+    crate::start(
+        url::Url::parse(&format!("http://localhost:{}", upstream.port())).unwrap(),
+        crate::storage::Config::default().with_root_dir(storage_root.path().into()),
+    )
+    .expect("Parody should start")
+}
+
+#[test]
+fn concurrent_requests_on_cold_cache_should_call_upstream_once() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let upstream = start_slow_upstream(calls.clone(), 0);
+    let parody = start_parody_for(&upstream, &storage_root);
+
+    let bodies = get_concurrently(&format!("http://localhost:{}/cold", parody.port()), 50);
+
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(bodies.len(), 50);
+    assert!(
+        bodies.iter().all(|body| body == "lorem ipsum"),
+        "{:?}",
+        bodies
+    );
+}
+
+#[test]
+fn concurrent_requests_when_recording_fails_should_forward_again() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let upstream = start_slow_upstream(calls.clone(), 1);
+    let parody = start_parody_for(&upstream, &storage_root);
+
+    let bodies = get_concurrently(&format!("http://localhost:{}/flaky", parody.port()), 10);
+
+    // The first recording is lost, one of the waiting requests records it anew
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert!(bodies.len() >= 9, "{:?}", bodies);
+    assert!(
+        bodies.iter().all(|body| body == "lorem ipsum"),
+        "{:?}",
+        bodies
+    );
+}
+
+#[test]
+fn recording_should_not_make_requests_with_the_same_key_wait_for_a_stalled_client() {
+    use std::io::Read;
+
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    // More than socket buffers hold, so the stalled client holds up the upstream body
+    let body = vec![b'a'; 32 * 1024 * 1024];
+    let upstream_body = body.clone();
+    let upstream = serve(Chain::new(move |_req: Request| {
+        let mut response = Response::with_status(http::StatusCode::OK);
+        response.body = Some(Box::new(upstream_body.clone()));
+        async move { Ok(response) }
+    }));
+    let parody = start_parody_for(&upstream, &storage_root);
+
+    let mut stalled = std::net::TcpStream::connect(("127.0.0.1", parody.port())).unwrap();
+    stalled
+        .write_all(b"GET /large HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    // The response has started, so the recording holds the key
+    stalled.read_exact(&mut [0; 1]).unwrap();
+
+    let response = reqwest::blocking::Client::builder()
+        .timeout(std::time::Duration::from_secs(30))
+        .build()
+        .unwrap()
+        .get(format!("http://localhost:{}/large", parody.port()))
+        .send()
+        .expect("Request should not wait for the stalled client");
+
+    assert_eq!(response.bytes().unwrap().len(), body.len());
+    drop(stalled);
+}
+
+#[test]
+fn strict_replay_when_recording_is_missing_should_explain_without_forwarding() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = crate::storage::Config::default()
+        .with_root_dir(storage_root.path().into())
+        .with_strict_replay_status(418);
+    crate::storage::DirectoryStorage::new_with_config(&"http://localhost/users/42", config.clone())
+        .unwrap()
+        .save(&mut (200, &[], std::io::Cursor::new("lorem ipsum".as_bytes())))
+        .expect("Cannot save response");
+    let calls = Arc::new(AtomicUsize::new(0));
+    let upstream = start_slow_upstream(calls.clone(), 0);
+    let parody = crate::start(
+        url::Url::parse(&format!("http://localhost:{}", upstream.port())).unwrap(),
+        config,
+    )
+    .expect("Parody should start");
+
+    let recorded =
+        reqwest::blocking::get(format!("http://localhost:{}/users/42", parody.port())).unwrap();
+    assert_eq!(recorded.status(), reqwest::StatusCode::OK);
+    assert_eq!(recorded.text().unwrap(), "lorem ipsum");
+
+    let unmatched =
+        reqwest::blocking::get(format!("http://localhost:{}/users/43", parody.port())).unwrap();
+    assert_eq!(unmatched.status(), reqwest::StatusCode::IM_A_TEAPOT);
+    let text = unmatched.text().unwrap();
+
+    assert!(
+        text.starts_with("No recording for: GET http://localhost:"),
+        "{}",
+        text
+    );
+    assert!(text.contains("users/43/GET.meta.yaml"), "{}", text);
+    assert!(text.contains("Query in path: all"), "{}", text);
+    assert!(
+        text.contains("  GET users/42 (one segment off)\n"),
+        "{}",
+        text
+    );
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    let misses = parody.misses();
+    assert_eq!(misses.len(), 1);
+    assert!(
+        misses[0].request.ends_with("/users/43"),
+        "{}",
+        misses[0].request
+    );
+    assert_eq!(
+        misses[0]
+            .report
+            .near_misses()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        vec!["GET users/42 (one segment off)"]
+    );
+}
+
+#[test]
+fn misses_should_list_recorded_requests_with_their_near_misses() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = crate::storage::Config::default().with_root_dir(storage_root.path().into());
+    crate::storage::DirectoryStorage::new_with_config(&"http://localhost/users/42", config.clone())
+        .unwrap()
+        .save(&mut (200, &[], std::io::Cursor::new("lorem ipsum".as_bytes())))
+        .expect("Cannot save response");
+    let calls = Arc::new(AtomicUsize::new(0));
+    let upstream = start_slow_upstream(calls.clone(), 0);
+    let parody = crate::start(
+        url::Url::parse(&format!("http://localhost:{}", upstream.port())).unwrap(),
+        config,
+    )
+    .expect("Parody should start");
+
+    for path in &["users/42", "users/42?page=2", "users/43"] {
+        reqwest::blocking::get(format!("http://localhost:{}/{}", parody.port(), path)).unwrap();
+    }
+
+    // Misses are reported after the responses
+    let started_at = std::time::Instant::now();
+    while parody.misses().len() < 2 && started_at.elapsed() < std::time::Duration::from_secs(5) {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let mut misses: Vec<(String, Vec<String>)> = parody
+        .misses()
+        .into_iter()
+        .map(|miss| {
+            let near_misses = miss.report.near_misses().map(ToString::to_string).collect();
+            (
+                miss.request.rsplit('/').next().unwrap().to_owned(),
+                near_misses,
+            )
+        })
+        .collect();
+    misses.sort();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        misses,
+        vec![
+            (
+                "42?page=2".to_owned(),
+                vec!["GET users/42 (different query)".to_owned()]
+            ),
+            (
+                "43".to_owned(),
+                vec!["GET users/42 (one segment off)".to_owned()]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn unused_recordings_should_list_recordings_not_served() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = crate::storage::Config::default().with_root_dir(storage_root.path().into());
+    crate::storage::DirectoryStorage::new_with_config(&"http://localhost/users/42", config)
+        .unwrap()
+        .save(&mut (200, &[], std::io::Cursor::new("lorem ipsum".as_bytes())))
+        .expect("Cannot save response");
+    let upstream = start_slow_upstream(Arc::new(AtomicUsize::new(0)), 0);
+    let parody = start_parody_for(&upstream, &storage_root);
+
+    let response =
+        reqwest::blocking::get(format!("http://localhost:{}/users/43", parody.port())).unwrap();
+    assert_eq!(response.text().unwrap(), "lorem ipsum");
+
+    // Recordings count as served once saved, the client may have the body first
+    let started_at = std::time::Instant::now();
+    while parody.served_recordings().is_empty()
+        && started_at.elapsed() < std::time::Duration::from_secs(5)
+    {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let served: Vec<String> = parody
+        .served_recordings()
+        .iter()
+        .map(|recording| recording.to_string())
+        .collect();
+    assert_eq!(served, vec!["GET users/43"]);
+    let unused: Vec<String> = parody
+        .unused_recordings()
+        .expect("Cannot list unused recordings")
+        .iter()
+        .map(|recording| recording.to_string())
+        .collect();
+    assert_eq!(unused, vec!["GET users/42"]);
+}
+
+#[test]
+fn replayed_recordings_should_be_in_journal_once_the_client_has_the_response() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let journal_path = storage_root.path().join("journal.txt");
+    let config = crate::storage::Config::default().with_root_dir(storage_root.path().into());
+    crate::storage::DirectoryStorage::new_with_config(&"http://localhost/users/42", config.clone())
+        .unwrap()
+        .save(&mut (200, &[], std::io::Cursor::new("lorem ipsum".as_bytes())))
+        .expect("Cannot save response");
+
+    // Nothing listens there, the response is replayed
+    let parody = crate::ParodyBuilder::new()
+        .with_storage_config(config)
+        .with_journal_file(journal_path.clone())
+        .start(url::Url::parse("http://127.0.0.1:9").unwrap())
+        .expect("Parody should start");
+
+    let response =
+        reqwest::blocking::get(format!("http://localhost:{}/users/42", parody.port())).unwrap();
+    assert_eq!(response.text().unwrap(), "lorem ipsum");
+    drop(parody);
+
+    assert_eq!(
+        std::fs::read_to_string(&journal_path).expect("Journal should be written"),
+        "GET users/42\n"
+    );
+}
+
+#[test]
+fn config_debug_should_not_print_client_identity_password() {
+    let config = Config::default().with_client_identity_pkcs12(b"certificate", "hunter2");
+
+    let debug = format!("{:?}", config);
+    assert!(!debug.contains("hunter2"), "{}", debug);
+    assert!(debug.contains("[REDACTED]"), "{}", debug);
+}
+
+#[test]
+fn recording_should_redact_secrets_in_upstream_url() {
+    init();
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = crate::storage::Config::default()
+        .with_root_dir(storage_root.path().into())
+        .with_no_query_path()
+        .with_redacted_query_param("api_key")
+        .with_redaction_pattern(regex::Regex::new("token-[a-z0-9]+").unwrap());
+    let upstream = start_upstream(http::StatusCode::OK, vec![]);
+    let parody = crate::start(
+        url::Url::parse(&format!("http://localhost:{}", upstream.port())).unwrap(),
+        config.clone(),
+    )
+    .expect("Parody should start");
+
+    let response = reqwest::blocking::get(format!(
+        "http://localhost:{}/users?api_key=secret123&session=token-abc&page=2",
+        parody.port()
+    ))
+    .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.text().unwrap(), "{\"Lorem\": \"ipsum\"}");
+
+    // Metadata is saved once the whole body is, the client may have it first
+    let metadata_path = storage_root.path().join("users/GET.meta.yaml");
+    let started_at = std::time::Instant::now();
+    while !metadata_path.exists() && started_at.elapsed() < std::time::Duration::from_secs(5) {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let metadata = std::fs::read_to_string(&metadata_path).expect("Metadata should be saved");
+    assert!(!metadata.contains("secret123"), "{}", metadata);
+    assert!(!metadata.contains("token-abc"), "{}", metadata);
+    assert!(
+        metadata.contains("api_key=PARODY-REDACTED&session=PARODY-REDACTED&page=2"),
+        "{}",
+        metadata
+    );
+
+    let findings = config
+        .redaction
+        .find_unredacted(storage_root.path())
+        .expect("Recordings should be checked");
+    assert!(findings.is_empty(), "{:?}", findings);
+}
+
+/// A proxy tunnelling `CONNECT` requests, returns its address and the authorities asked for
+pub(crate) fn start_tunnel_proxy() -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+    use std::io::Read;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Proxy should listen");
+    let addr = listener.local_addr().unwrap();
+    let authorities = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let tunnelled = authorities.clone();
+
+    std::thread::spawn(move || {
+        for client in listener.incoming() {
+            let mut client = client.expect("Proxy should accept");
+            let mut head = Vec::new();
+            let mut byte = [0];
+
+            while !head.ends_with(b"\r\n\r\n") && client.read_exact(&mut byte).is_ok() {
+                head.push(byte[0]);
+            }
+
+            let head = String::from_utf8_lossy(&head).into_owned();
+            let authority = head.split(' ').nth(1).unwrap_or_default().to_owned();
+            tunnelled.lock().unwrap().push(authority.clone());
+
+            let mut upstream = std::net::TcpStream::connect(authority.as_str())
+                .expect("Proxy should reach the upstream");
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+
+            let (mut client_reader, mut upstream_writer) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            std::thread::spawn(move || {
+                let _ = std::io::copy(&mut client_reader, &mut upstream_writer);
+                let _ = upstream_writer.shutdown(std::net::Shutdown::Write);
+            });
+            std::thread::spawn(move || {
+                let _ = std::io::copy(&mut upstream, &mut client);
+                let _ = client.shutdown(std::net::Shutdown::Write);
+            });
+        }
+    });
+
+    (addr, authorities)
+}
+
+#[test]
+fn connect_with_proxy_should_tunnel_to_the_host() {
+    use std::io::Read;
+
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let (mut stream, _) = upstream.accept().unwrap();
+        stream.write_all(b"lorem").unwrap();
+    });
+    let (proxy_addr, authorities) = start_tunnel_proxy();
+    let config =
+        Config::default().with_proxy(url::Url::parse(&format!("http://{}", proxy_addr)).unwrap());
+
+    let stream = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap()
+        .block_on(connect::connect(&config, "127.0.0.1", upstream_port))
+        .expect("Tunnel should be opened")
+        .into_std()
+        .unwrap();
+    stream.set_nonblocking(false).unwrap();
+    let mut received = String::new();
+    (&stream).read_to_string(&mut received).unwrap();
+
+    assert_eq!(received, "lorem");
+    assert_eq!(
+        *authorities.lock().unwrap(),
+        vec![format!("127.0.0.1:{}", upstream_port)]
+    );
+}
@@ -0,0 +1,503 @@
+//! Recording and replay of unary gRPC calls
+
+use crate::{
+    forward_middleware::{self, connect},
+    request::ParodyRequest,
+    result::Result,
+    server::{Accepting, Connection, Listener, Stream},
+    storage::{self, DirectoryStorage, RecordingKind},
+    ServedRecordings,
+};
+use bytes::Bytes;
+use h2::{client::SendRequest, server::SendResponse, RecvStream};
+use recording::Recording;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+pub(crate) mod recording;
+#[cfg(test)]
+mod test;
+
+/// What HTTP/2 clients with prior knowledge send first
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// How long to wait for the rest of the preface before leaving the connection to the server
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for more of the preface to arrive before peeking again
+const PREFACE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// `grpc-status` of calls which could be neither replayed nor recorded
+const UNAVAILABLE: &str = "14";
+/// Connection-specific headers of the client request, the upstream one gets its own
+const CONNECTION_HEADERS: &[&str] = &["host", "connection"];
+/// Bytes percent-encoded in `grpc-message`, besides non-ASCII ones
+const GRPC_MESSAGE_ENCODE_SET: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS.add(b'%');
+
+/// Takes over cleartext HTTP/2 connections as gRPC calls
+///
+/// The server speaks HTTP/1 only, so connections starting with the HTTP/2
+/// preface are detected before the server sees them and served with h2.
+/// Each call is stored under its service and method plus a hash of the request
+/// messages: without a recording it's proxied to the upstream and recorded,
+/// otherwise recorded response messages and trailers are replayed.
+#[derive(Clone)]
+pub struct GrpcListener {
+    calls: Arc<Calls>,
+}
+
+/// What calls need to find, record and replay recordings
+struct Calls {
+    upstream_url: url::Url,
+    storage_config: storage::Config,
+    /// Timeouts and proxy of upstream connections
+    upstream_config: forward_middleware::Config,
+    /// Connects to `https` upstreams with the TLS settings of the upstream config
+    tls_connector: tokio_native_tls::TlsConnector,
+    /// Connection to the upstream shared by recorded calls, opened on the first one
+    upstream: Mutex<Option<SendRequest<Bytes>>>,
+    served: ServedRecordings,
+}
+
+/// A response as sent over the wire
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrpcResponse {
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    /// Length-prefixed messages
+    body: Vec<u8>,
+    trailers: Vec<(String, Vec<u8>)>,
+}
+
+/// A call as the storage sees it
+struct GrpcRequest {
+    url: url::Url,
+    headers: http::HeaderMap,
+}
+
+impl ParodyRequest for GrpcRequest {
+    fn get_url(&self) -> url::Url {
+        self.url.clone()
+    }
+
+    fn get_method(&self) -> String {
+        "POST".to_owned()
+    }
+
+    fn get_header(&self, name: &str) -> Option<String> {
+        let values: Vec<String> = self
+            .headers
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .collect();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+}
+
+impl GrpcRequest {
+    fn new(parts: &http::request::Parts, body: &[u8]) -> Result<Self> {
+        let authority = parts
+            .uri
+            .authority()
+            .map(|authority| authority.as_str())
+            .unwrap_or("localhost");
+
+        Ok(Self {
+            url: url::Url::parse(&format!(
+                "http://{}{}/{}",
+                authority,
+                parts.uri.path().trim_end_matches('/'),
+                recording::hash_request(body)
+            ))?,
+            headers: parts.headers.clone(),
+        })
+    }
+}
+
+impl GrpcResponse {
+    /// Trailers-only response failing the call
+    fn error(grpc_status: &str, message: &str) -> Self {
+        Self {
+            status: 200,
+            headers: vec![
+                ("content-type".to_owned(), b"application/grpc".to_vec()),
+                ("grpc-status".to_owned(), grpc_status.as_bytes().to_vec()),
+                (
+                    "grpc-message".to_owned(),
+                    percent_encoding::utf8_percent_encode(message, GRPC_MESSAGE_ENCODE_SET)
+                        .to_string()
+                        .into_bytes(),
+                ),
+            ],
+            body: Vec::new(),
+            trailers: Vec::new(),
+        }
+    }
+}
+
+impl GrpcListener {
+    pub fn new(
+        upstream_url: url::Url,
+        storage_config: storage::Config,
+        upstream_config: &forward_middleware::Config,
+        served: ServedRecordings,
+    ) -> Result<Self> {
+        Ok(Self {
+            calls: Arc::new(Calls {
+                upstream_url,
+                storage_config,
+                upstream_config: upstream_config.clone(),
+                tls_connector: upstream_config.build_http2_tls_connector()?.into(),
+                upstream: Mutex::new(None),
+                served,
+            }),
+        })
+    }
+}
+
+impl Listener for GrpcListener {
+    fn accept(&self, connection: Connection) -> Accepting<'_> {
+        Box::pin(async move {
+            let is_http2 = match connection.stream.tcp() {
+                Some(stream) => is_http2_connection(stream).await,
+                None => false,
+            };
+
+            match connection.stream {
+                Stream::Tcp(stream) if is_http2 => {
+                    self.calls.clone().serve(stream).await?;
+                    Ok(None)
+                }
+                stream => Ok(Some(Connection {
+                    stream,
+                    ..connection
+                })),
+            }
+        })
+    }
+}
+
+impl Calls {
+    /// Handles calls of the connection until the client closes it
+    async fn serve(self: Arc<Self>, stream: TcpStream) -> Result<()> {
+        let mut connection = h2::server::handshake(stream).await?;
+
+        while let Some(call) = connection.accept().await {
+            let (request, respond) = call?;
+            tokio::spawn(self.clone().handle(request, respond));
+        }
+
+        Ok(())
+    }
+
+    async fn handle(
+        self: Arc<Self>,
+        request: http::Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+    ) {
+        let path = request.uri().path().to_owned();
+        let response = match self.call(request).await {
+            Ok(response) => response,
+            Err(error) => {
+                warn!(target: "grpc", "Call {} failed: {}", path, error);
+                GrpcResponse::error(UNAVAILABLE, &error.to_string())
+            }
+        };
+
+        if let Err(error) = send(&mut respond, response) {
+            warn!(target: "grpc", "Cannot respond to {}: {}", path, error);
+        }
+    }
+
+    /// Replays the recorded call or forwards it to the upstream to record one
+    ///
+    /// Calls take the lock of their key like HTTP requests, so the same call
+    /// made concurrently is forwarded once and replayed to the others.
+    async fn call(&self, request: http::Request<RecvStream>) -> Result<GrpcResponse> {
+        let (parts, mut body) = request.into_parts();
+        let body = read_body(&mut body, None).await?;
+        let storage = DirectoryStorage::new_with_config(
+            &GrpcRequest::new(&parts, &body)?,
+            self.storage_config.clone(),
+        )?;
+        let recording_path = storage.get_grpc_file_path();
+        let recording = storage.recording_of(RecordingKind::Grpc);
+        let lock = storage.get_lock();
+
+        let (replaying, _) = storage::lock::read(lock.clone()).await;
+        if let Some(response) = load(storage.clone()).await? {
+            self.served.insert(recording);
+            return Ok(response);
+        }
+        drop(replaying);
+
+        if storage.strict_replay_status().is_some() {
+            // Looking for close recordings may walk the storage directory
+            let report =
+                tokio::task::spawn_blocking(move || storage.miss_report_of(RecordingKind::Grpc))
+                    .await
+                    .map_err(std::io::Error::from)??;
+            let request = format!("POST {}", parts.uri.path());
+            let message = format!("No recording for: {}\n{}", request, report);
+            warn!(target: "grpc", "{}", message.trim_end());
+            self.served.insert_miss(storage::Miss { request, report });
+
+            return Ok(GrpcResponse::error(UNAVAILABLE, &message));
+        }
+
+        let (_recording_lock, _) = storage::lock::write(lock).await;
+
+        // Another call with the same key may have recorded the response meanwhile
+        if let Some(response) = load(storage.clone()).await? {
+            self.served.insert(recording);
+            return Ok(response);
+        }
+
+        debug!(target: "grpc", "Recording call: {}", parts.uri.path());
+        let response = self.forward(parts, body).await?;
+        let saved_response = response.clone();
+
+        // Saving writes files, which would block the runtime
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            std::fs::create_dir_all(storage.get_absolute_storage_path())?;
+            Recording::new(&saved_response, &storage)?.save(&recording_path)?;
+            storage.invalidate_recordings_index();
+            info!(target: "grpc", "Saved call to: {}", recording_path.to_string_lossy());
+            Ok(())
+        })
+        .await
+        .map_err(std::io::Error::from)??;
+        self.served.insert(recording);
+
+        Ok(response)
+    }
+
+    /// Makes the call to the upstream over HTTP/2, within the timeout of the upstream config
+    async fn forward(&self, parts: http::request::Parts, body: Bytes) -> Result<GrpcResponse> {
+        let forwarding = self.exchange(parts, body);
+
+        match self.upstream_config.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, forwarding)
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::TimedOut, "The upstream call timed out")
+                })?,
+            None => forwarding.await,
+        }
+    }
+
+    async fn exchange(&self, parts: http::request::Parts, body: Bytes) -> Result<GrpcResponse> {
+        let mut client = self.get_client().await?;
+        let read_timeout = self.upstream_config.read_timeout;
+
+        let mut request = http::Request::new(());
+        *request.method_mut() = parts.method;
+        *request.uri_mut() = get_upstream_uri(&self.upstream_url, &parts.uri)?;
+        *request.headers_mut() = parts.headers;
+
+        for name in CONNECTION_HEADERS {
+            request.headers_mut().remove(*name);
+        }
+
+        let (response, mut request_body) = client.send_request(request, body.is_empty())?;
+
+        if !body.is_empty() {
+            request_body.send_data(body, true)?;
+        }
+
+        let (head, mut response_body) = connect::read(read_timeout, response).await??.into_parts();
+        let body = read_body(&mut response_body, read_timeout).await?;
+        let trailers = connect::read(read_timeout, response_body.trailers()).await??;
+
+        Ok(GrpcResponse {
+            status: head.status.as_u16(),
+            headers: from_header_map(&head.headers),
+            body: body.to_vec(),
+            trailers: trailers
+                .map(|trailers| from_header_map(&trailers))
+                .unwrap_or_default(),
+        })
+    }
+
+    /// The pooled upstream connection ready for a call, reconnecting once it's gone
+    async fn get_client(&self) -> Result<SendRequest<Bytes>> {
+        let mut upstream = self.upstream.lock().await;
+
+        if let Some(client) = upstream.clone() {
+            match client.ready().await {
+                Ok(client) => return Ok(client),
+                Err(error) => debug!(target: "grpc", "Reconnecting to the upstream: {}", error),
+            }
+        }
+
+        // Hosts of URLs keep the brackets of IPv6 addresses
+        let host = self
+            .upstream_url
+            .host_str()
+            .unwrap_or("localhost")
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = self.upstream_url.port_or_known_default().unwrap_or(80);
+        let stream = connect::connect(&self.upstream_config, host, port).await?;
+        let client = match self.upstream_url.scheme() {
+            "https" => handshake(self.tls_connector.connect(host, stream).await?).await?,
+            _ => handshake(stream).await?,
+        };
+
+        *upstream = Some(client.clone());
+        Ok(client.ready().await?)
+    }
+}
+
+/// The recorded response of the call, `None` if there is none or it's stale
+async fn load(storage: DirectoryStorage) -> Result<Option<GrpcResponse>> {
+    // Loading reads a file, which would block the runtime
+    tokio::task::spawn_blocking(move || {
+        let recording_path = storage.get_grpc_file_path();
+
+        if !recording_path.exists() {
+            return Ok(None);
+        }
+
+        let recording = Recording::load(&recording_path)?;
+
+        if storage.is_stale(recording.age()) {
+            info!(
+                target: "grpc",
+                "Recording is stale, recording again: {}",
+                recording_path.to_string_lossy()
+            );
+            return Ok(None);
+        }
+
+        debug!(target: "grpc", "Replaying call from: {}", recording_path.to_string_lossy());
+        recording.into_response().map(Some)
+    })
+    .await
+    .map_err(std::io::Error::from)?
+}
+
+/// Sends headers, messages and trailers, whichever the response has
+fn send(respond: &mut SendResponse<Bytes>, response: GrpcResponse) -> Result<()> {
+    let mut head = http::Response::new(());
+    *head.status_mut() = http::StatusCode::from_u16(response.status).map_err(http::Error::from)?;
+    *head.headers_mut() = to_header_map(response.headers)?;
+
+    let is_headers_only = response.body.is_empty() && response.trailers.is_empty();
+    let mut stream = respond.send_response(head, is_headers_only)?;
+
+    if !response.body.is_empty() {
+        stream.send_data(response.body.into(), response.trailers.is_empty())?;
+    }
+
+    if !response.trailers.is_empty() {
+        stream.send_trailers(to_header_map(response.trailers)?)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the whole body, giving the capacity back to the peer as it goes
+///
+/// Each read fails after the read timeout, if there is one.
+async fn read_body(body: &mut RecvStream, read_timeout: Option<Duration>) -> Result<Bytes> {
+    let mut data = Vec::new();
+
+    while let Some(chunk) = connect::read(read_timeout, body.data()).await? {
+        let chunk = chunk?;
+        body.flow_control().release_capacity(chunk.len())?;
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data.into())
+}
+
+/// Starts an HTTP/2 connection to the upstream, driven in the background until it's closed
+async fn handshake<S>(stream: S) -> Result<SendRequest<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, connection) = h2::client::handshake(stream).await?;
+
+    tokio::spawn(async move {
+        if let Err(error) = connection.await {
+            debug!(target: "grpc", "Upstream connection failed: {}", error);
+        }
+    });
+
+    Ok(client)
+}
+
+/// The upstream URL with the call path appended
+fn get_upstream_uri(upstream_url: &url::Url, uri: &http::Uri) -> Result<http::Uri> {
+    let mut url = upstream_url.clone();
+
+    url.set_path(&format!(
+        "{}/{}",
+        url.path().trim_end_matches('/'),
+        uri.path().trim_start_matches('/')
+    ));
+
+    Ok(url
+        .as_str()
+        .parse::<http::Uri>()
+        .map_err(http::Error::from)?)
+}
+
+fn from_header_map(headers: &http::HeaderMap) -> Vec<(String, Vec<u8>)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+fn to_header_map(headers: Vec<(String, Vec<u8>)>) -> Result<http::HeaderMap> {
+    let mut header_map = http::HeaderMap::new();
+
+    for (name, value) in headers {
+        header_map.append(
+            http::header::HeaderName::from_bytes(name.as_bytes()).map_err(http::Error::from)?,
+            http::HeaderValue::from_bytes(&value).map_err(http::Error::from)?,
+        );
+    }
+
+    Ok(header_map)
+}
+
+async fn is_http2_connection(stream: &TcpStream) -> bool {
+    let mut head = [0; PREFACE.len()];
+    let started_at = Instant::now();
+
+    // Peeking returns what has arrived so far, the preface may come in several segments
+    loop {
+        let length = match stream.peek(&mut head).await {
+            Ok(length) => length,
+            Err(error) => {
+                trace!(target: "grpc", "Cannot peek connection: {}", error);
+                return false;
+            }
+        };
+
+        // Other connections go to the server as soon as they differ from the preface
+        if length == PREFACE.len() || !PREFACE.starts_with(&head[..length]) {
+            return head[..length] == *PREFACE;
+        }
+
+        if length == 0 || started_at.elapsed() >= PREFACE_TIMEOUT {
+            trace!(target: "grpc", "Preface incomplete after {} bytes", length);
+            return false;
+        }
+
+        tokio::time::sleep(PREFACE_POLL_INTERVAL).await;
+    }
+}
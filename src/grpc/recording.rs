@@ -0,0 +1,150 @@
+//! Response messages and trailers of a recorded gRPC call
+
+use super::GrpcResponse;
+use crate::{
+    error::Error,
+    result::Result,
+    storage::{DirectoryStorage, HeaderValue},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// Version of the recording format written by this Parody
+pub const CURRENT_VERSION: u32 = 1;
+/// Compression flag and length before each message
+const MESSAGE_PREFIX_LENGTH: usize = 5;
+/// Hex digits of the request hash naming the call directory
+const REQUEST_HASH_LENGTH: usize = 16;
+
+/// One length-prefixed message of a call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Whether the message is compressed with the call's `grpc-encoding`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+    /// Base64-encoded protobuf
+    pub data: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub version: u32,
+    pub status: u16,
+    /// Headers in the order they were received, duplicates included
+    #[serde(default)]
+    pub headers: Vec<(String, HeaderValue)>,
+    #[serde(default)]
+    pub messages: Vec<RecordedMessage>,
+    /// Trailers ending the call, `grpc-status` among them
+    #[serde(default)]
+    pub trailers: Vec<(String, HeaderValue)>,
+    /// RFC 3339 time of recording
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recorded_at: Option<String>,
+}
+
+impl Recording {
+    /// The upstream response with headers filtered and redacted as the storage is configured
+    pub fn new(response: &GrpcResponse, storage: &DirectoryStorage) -> Result<Self> {
+        Ok(Self {
+            version: CURRENT_VERSION,
+            status: response.status,
+            headers: storage.prepare_recorded_headers(response.headers.clone()),
+            messages: decode_messages(&response.body)?,
+            trailers: storage.prepare_recorded_headers(response.trailers.clone()),
+            recorded_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let recording: Self = serde_yaml::from_reader(File::open(path)?)?;
+
+        if recording.version > CURRENT_VERSION {
+            return Err(Error::UnsupportedMetadataVersion(recording.version));
+        }
+
+        Ok(recording)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        crate::storage::save_yaml(path, self)
+    }
+
+    /// Time since recording, `None` if unknown
+    pub fn age(&self) -> Option<Duration> {
+        crate::storage::get_age(self.recorded_at.as_deref())
+    }
+
+    pub fn into_response(self) -> Result<GrpcResponse> {
+        Ok(GrpcResponse {
+            status: self.status,
+            headers: into_raw_headers(self.headers)?,
+            body: encode_messages(&self.messages)?,
+            trailers: into_raw_headers(self.trailers)?,
+        })
+    }
+}
+
+/// Splits a body into its length-prefixed messages
+pub fn decode_messages(mut body: &[u8]) -> Result<Vec<RecordedMessage>> {
+    let mut messages = Vec::new();
+
+    while !body.is_empty() {
+        if body.len() < MESSAGE_PREFIX_LENGTH {
+            return Err(truncated_message());
+        }
+
+        let length = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        let data = body
+            .get(MESSAGE_PREFIX_LENGTH..MESSAGE_PREFIX_LENGTH + length)
+            .ok_or_else(truncated_message)?;
+
+        messages.push(RecordedMessage {
+            compressed: body[0] != 0,
+            data: STANDARD.encode(data),
+        });
+        body = &body[MESSAGE_PREFIX_LENGTH + length..];
+    }
+
+    Ok(messages)
+}
+
+pub fn encode_messages(messages: &[RecordedMessage]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    for message in messages {
+        let data = STANDARD.decode(&message.data)?;
+
+        body.push(message.compressed as u8);
+        body.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        body.extend_from_slice(&data);
+    }
+
+    Ok(body)
+}
+
+/// Names the directory of calls with the request body
+pub fn hash_request(body: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, body)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>()[..REQUEST_HASH_LENGTH]
+        .to_owned()
+}
+
+fn into_raw_headers(headers: Vec<(String, HeaderValue)>) -> Result<Vec<(String, Vec<u8>)>> {
+    headers
+        .into_iter()
+        .map(|(name, value)| Ok((name, value.into_bytes()?)))
+        .collect()
+}
+
+fn truncated_message() -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Truncated gRPC message").into()
+}
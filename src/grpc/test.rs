@@ -0,0 +1,493 @@
+use super::{recording::*, *};
+use crate::storage::HeaderValue;
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Runtime should start")
+}
+
+fn encode(texts: &[&str]) -> Bytes {
+    let messages: Vec<RecordedMessage> = texts
+        .iter()
+        .map(|text| RecordedMessage {
+            compressed: false,
+            data: base64::Engine::encode(&base64::engine::general_purpose::STANDARD, text),
+        })
+        .collect();
+
+    encode_messages(&messages).unwrap().into()
+}
+
+fn decode(body: &[u8]) -> Vec<String> {
+    decode_messages(body)
+        .unwrap()
+        .into_iter()
+        .map(|message| {
+            let data =
+                base64::Engine::decode(&base64::engine::general_purpose::STANDARD, message.data)
+                    .unwrap();
+            String::from_utf8(data).unwrap()
+        })
+        .collect()
+}
+
+/// Calls and connections an upstream received
+#[derive(Default)]
+struct UpstreamCounts {
+    calls: AtomicUsize,
+    connections: AtomicUsize,
+}
+
+/// Answers calls with their messages in upper case after the delay, counting them
+fn start_upstream(counts: Arc<UpstreamCounts>, delay: std::time::Duration) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Upstream should listen");
+    let addr = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.expect("Upstream should accept");
+            let counts = counts.clone();
+            counts.connections.fetch_add(1, Ordering::SeqCst);
+
+            std::thread::spawn(move || {
+                runtime().block_on(async move {
+                    stream.set_nonblocking(true).unwrap();
+                    serve_upstream(TcpStream::from_std(stream).unwrap(), &counts, delay).await;
+                });
+            });
+        }
+    });
+
+    addr
+}
+
+/// Like [`start_upstream`], over TLS with a certificate for `localhost` issued by the authority
+///
+/// Connections which don't negotiate HTTP/2 are closed, as gRPC servers do.
+fn start_tls_upstream(
+    counts: Arc<UpstreamCounts>,
+    certificate_authority: &crate::CertificateAuthority,
+) -> SocketAddr {
+    let mut server_config = (*certificate_authority
+        .issue_server_config(vec!["localhost".to_owned()])
+        .unwrap())
+    .clone();
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Upstream should listen");
+    let addr = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.expect("Upstream should accept");
+            let (counts, acceptor) = (counts.clone(), acceptor.clone());
+            counts.connections.fetch_add(1, Ordering::SeqCst);
+
+            std::thread::spawn(move || {
+                runtime().block_on(async move {
+                    stream.set_nonblocking(true).unwrap();
+                    let stream = acceptor
+                        .accept(TcpStream::from_std(stream).unwrap())
+                        .await
+                        .expect("Upstream TLS handshake should succeed");
+
+                    if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                        serve_upstream(stream, &counts, Default::default()).await;
+                    }
+                });
+            });
+        }
+    });
+
+    addr
+}
+
+async fn serve_upstream<S>(stream: S, counts: &UpstreamCounts, delay: std::time::Duration)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = h2::server::handshake(stream).await.unwrap();
+
+    while let Some(Ok((request, mut respond))) = connection.accept().await {
+        counts.calls.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(delay);
+        let mut body = request.into_body();
+        let texts = decode(&read_body(&mut body, None).await.unwrap());
+        let upper: Vec<String> = texts.iter().map(|text| text.to_uppercase()).collect();
+        let upper: Vec<&str> = upper.iter().map(String::as_str).collect();
+
+        let response = http::Response::builder()
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap();
+        let mut stream = respond.send_response(response, false).unwrap();
+        stream.send_data(encode(&upper), false).unwrap();
+
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        stream.send_trailers(trailers).unwrap();
+    }
+}
+
+/// Response messages and `grpc-status` of a unary call
+fn call(port: u16, path: &str, text: &str) -> (Vec<String>, String) {
+    let (messages, trailers) = call_with_trailers(port, path, text);
+
+    (
+        messages,
+        trailers["grpc-status"].to_str().unwrap().to_owned(),
+    )
+}
+
+/// Response messages and trailers of a unary call, headers of trailers-only responses
+fn call_with_trailers(port: u16, path: &str, text: &str) -> (Vec<String>, http::HeaderMap) {
+    runtime().block_on(async move {
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream)
+            .await
+            .expect("Parody handshake should succeed");
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+
+        let request = http::Request::post(format!("http://localhost{}", path))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(())
+            .unwrap();
+        let (response, mut request_body) = client
+            .ready()
+            .await
+            .unwrap()
+            .send_request(request, false)
+            .unwrap();
+        request_body.send_data(encode(&[text]), true).unwrap();
+
+        let (head, mut body) = response.await.unwrap().into_parts();
+        let messages = decode(&read_body(&mut body, None).await.unwrap());
+        let trailers = body.trailers().await.unwrap().unwrap_or(head.headers);
+
+        (messages, trailers)
+    })
+}
+
+fn count_calls(dir: &Path) -> usize {
+    std::fs::read_dir(dir).map(Iterator::count).unwrap_or(0)
+}
+
+#[test]
+fn grpc_calls_should_be_recorded_and_replayed() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default().with_root_dir(storage_path.path().to_owned());
+    let method_path = storage_path.path().join("test.Echo").join("Upper");
+    let upstream = Arc::new(UpstreamCounts::default());
+
+    let recording = crate::start(
+        url::Url::parse(&format!(
+            "http://{}",
+            start_upstream(upstream.clone(), Default::default())
+        ))
+        .unwrap(),
+        config.clone(),
+    )
+    .expect("Parody should start");
+
+    assert_eq!(
+        call(recording.port(), "/test.Echo/Upper", "lorem"),
+        (vec!["LOREM".to_owned()], "0".to_owned())
+    );
+    assert_eq!(
+        call(recording.port(), "/test.Echo/Upper", "ipsum"),
+        (vec!["IPSUM".to_owned()], "0".to_owned())
+    );
+    let mut served: Vec<String> = ["lorem", "ipsum"]
+        .iter()
+        .map(|text| {
+            format!(
+                "grpc POST test.Echo/Upper/{}",
+                hash_request(&encode(&[text]))
+            )
+        })
+        .collect();
+    served.sort();
+    assert_eq!(
+        recording
+            .served_recordings()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        served
+    );
+    drop(recording);
+
+    assert_eq!(upstream.calls.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        upstream.connections.load(Ordering::SeqCst),
+        1,
+        "Calls should share the upstream connection"
+    );
+    assert_eq!(
+        count_calls(&method_path),
+        2,
+        "Each request should be stored apart"
+    );
+
+    let saved = Recording::load(
+        &method_path
+            .join(hash_request(&encode(&["lorem"])))
+            .join("POST.grpc.yaml"),
+    )
+    .expect("Recording should be saved");
+    assert_eq!(saved.status, 200);
+    assert_eq!(
+        saved.messages,
+        decode_messages(&encode(&["LOREM"])).unwrap()
+    );
+    assert_eq!(
+        saved.trailers,
+        vec![("grpc-status".to_owned(), HeaderValue::Text("0".to_owned()))]
+    );
+
+    // Nothing listens there, replay must not reach the upstream
+    let replaying = crate::start(url::Url::parse("http://127.0.0.1:9").unwrap(), config)
+        .expect("Parody should start");
+
+    assert_eq!(
+        call(replaying.port(), "/test.Echo/Upper", "ipsum"),
+        (vec!["IPSUM".to_owned()], "0".to_owned())
+    );
+    assert_eq!(
+        call(replaying.port(), "/test.Echo/Upper", "dolor"),
+        (Vec::new(), UNAVAILABLE.to_owned())
+    );
+}
+
+#[test]
+fn stale_grpc_call_should_be_recorded_again() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_max_age(std::time::Duration::from_secs(24 * 60 * 60));
+    let recording_path = storage_path
+        .path()
+        .join("test.Echo/Upper")
+        .join(hash_request(&encode(&["lorem"])));
+    std::fs::create_dir_all(&recording_path).unwrap();
+    Recording {
+        version: CURRENT_VERSION,
+        status: 200,
+        headers: Vec::new(),
+        messages: decode_messages(&encode(&["STALE"])).unwrap(),
+        trailers: vec![("grpc-status".to_owned(), HeaderValue::Text("0".to_owned()))],
+        recorded_at: Some("2020-01-01T00:00:00Z".to_owned()),
+    }
+    .save(&recording_path.join("POST.grpc.yaml"))
+    .unwrap();
+    let upstream = Arc::new(UpstreamCounts::default());
+
+    let parody = crate::start(
+        url::Url::parse(&format!(
+            "http://{}",
+            start_upstream(upstream.clone(), Default::default())
+        ))
+        .unwrap(),
+        config,
+    )
+    .expect("Parody should start");
+
+    assert_eq!(
+        call(parody.port(), "/test.Echo/Upper", "lorem"),
+        (vec!["LOREM".to_owned()], "0".to_owned())
+    );
+    assert_eq!(
+        call(parody.port(), "/test.Echo/Upper", "lorem"),
+        (vec!["LOREM".to_owned()], "0".to_owned())
+    );
+    assert_eq!(
+        upstream.calls.load(Ordering::SeqCst),
+        1,
+        "Calls should be replayed once recorded again"
+    );
+}
+
+#[test]
+fn grpc_calls_should_be_recorded_from_tls_upstream() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let certificate_authority = crate::CertificateAuthority::generate().unwrap();
+    let upstream = Arc::new(UpstreamCounts::default());
+    let upstream_addr = start_tls_upstream(upstream.clone(), &certificate_authority);
+
+    let parody = crate::ParodyBuilder::new()
+        .with_storage_config(
+            storage::Config::default().with_root_dir(storage_path.path().to_owned()),
+        )
+        .with_upstream_config(
+            forward_middleware::Config::default()
+                .with_root_certificate_pem(certificate_authority.certificate_pem().as_bytes()),
+        )
+        .start(url::Url::parse(&format!("https://localhost:{}", upstream_addr.port())).unwrap())
+        .expect("Parody should start");
+
+    assert_eq!(
+        call(parody.port(), "/test.Echo/Upper", "lorem"),
+        (vec!["LOREM".to_owned()], "0".to_owned())
+    );
+    assert_eq!(upstream.calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn grpc_calls_should_be_forwarded_through_upstream_proxy() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let upstream = Arc::new(UpstreamCounts::default());
+    let upstream_addr = start_upstream(upstream.clone(), Default::default());
+    let (proxy_addr, tunnelled) = forward_middleware::test::start_tunnel_proxy();
+
+    let parody = crate::ParodyBuilder::new()
+        .with_storage_config(
+            storage::Config::default().with_root_dir(storage_path.path().to_owned()),
+        )
+        .with_upstream_config(
+            forward_middleware::Config::default()
+                .with_proxy(url::Url::parse(&format!("http://{}", proxy_addr)).unwrap()),
+        )
+        .start(url::Url::parse(&format!("http://{}", upstream_addr)).unwrap())
+        .expect("Parody should start");
+
+    assert_eq!(
+        call(parody.port(), "/test.Echo/Upper", "lorem"),
+        (vec!["LOREM".to_owned()], "0".to_owned())
+    );
+    assert_eq!(*tunnelled.lock().unwrap(), vec![upstream_addr.to_string()]);
+}
+
+#[test]
+fn concurrent_grpc_calls_should_be_forwarded_once() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default().with_root_dir(storage_path.path().to_owned());
+    let upstream = Arc::new(UpstreamCounts::default());
+
+    let parody = crate::start(
+        url::Url::parse(&format!(
+            "http://{}",
+            start_upstream(upstream.clone(), std::time::Duration::from_millis(200))
+        ))
+        .unwrap(),
+        config,
+    )
+    .expect("Parody should start");
+    let port = parody.port();
+
+    let calls: Vec<_> = (0..3)
+        .map(|_| std::thread::spawn(move || call(port, "/test.Echo/Upper", "lorem")))
+        .collect();
+
+    for call in calls {
+        assert_eq!(
+            call.join().unwrap(),
+            (vec!["LOREM".to_owned()], "0".to_owned())
+        );
+    }
+    assert_eq!(upstream.calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn grpc_call_without_recording_should_fail_in_strict_replay() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_strict_replay();
+    let upstream = Arc::new(UpstreamCounts::default());
+
+    let parody = crate::start(
+        url::Url::parse(&format!(
+            "http://{}",
+            start_upstream(upstream.clone(), Default::default())
+        ))
+        .unwrap(),
+        config,
+    )
+    .expect("Parody should start");
+
+    let (messages, trailers) = call_with_trailers(parody.port(), "/test.Echo/Upper", "lorem");
+    let message = percent_encoding::percent_decode(trailers["grpc-message"].as_bytes())
+        .decode_utf8()
+        .unwrap()
+        .into_owned();
+
+    assert!(messages.is_empty());
+    assert_eq!(trailers["grpc-status"], UNAVAILABLE);
+    assert!(
+        message.starts_with("No recording for: POST /test.Echo/Upper\n"),
+        "Unexpected message: {}",
+        message
+    );
+    assert_eq!(upstream.calls.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn http2_connection_should_be_detected_when_its_preface_arrives_in_pieces() {
+    use std::io::{Read, Write};
+
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let parody = crate::start(
+        url::Url::parse("http://127.0.0.1:9").unwrap(),
+        storage::Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .expect("Parody should start");
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", parody.port())).unwrap();
+
+    stream.write_all(&PREFACE[..10]).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    stream.write_all(&PREFACE[10..]).unwrap();
+    // An empty SETTINGS frame
+    stream.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+
+    // The HTTP/1 server would answer with a status line instead
+    let mut frame_head = [0; 9];
+    stream.read_exact(&mut frame_head).unwrap();
+    assert_eq!(frame_head[3], 4, "Server should send its SETTINGS frame");
+}
+
+#[test]
+fn messages_should_round_trip_through_recording() {
+    let mut body = encode(&["lorem", ""]).to_vec();
+    body.extend_from_slice(&[1, 0, 0, 0, 2, 0xff, 0x00]);
+
+    let messages = decode_messages(&body).unwrap();
+
+    assert_eq!(messages.len(), 3);
+    assert!(messages[2].compressed);
+    assert_eq!(encode_messages(&messages).unwrap(), body);
+}
+
+#[test]
+fn truncated_message_should_fail_to_decode() {
+    assert!(decode_messages(&[0, 0, 0, 0, 5, b'a']).is_err());
+    assert!(decode_messages(&[0, 0]).is_err());
+}
+
+#[test]
+fn request_hash_should_depend_on_messages() {
+    assert_eq!(hash_request(b"lorem").len(), 16);
+    assert_eq!(hash_request(b"lorem"), hash_request(b"lorem"));
+    assert_ne!(hash_request(b"lorem"), hash_request(b"ipsum"));
+}
@@ -4,131 +4,424 @@ extern crate url;
 #[macro_use]
 extern crate log;
 extern crate http;
-extern crate iron;
 extern crate regex;
 extern crate serde_json;
-#[cfg(test)]
-#[macro_use]
-extern crate hyper;
 
+mod builder;
 mod cache_middleware;
+mod certificate_authority;
 mod error;
 mod forward_middleware;
+mod grpc;
 mod log_middleware;
+mod proxy_listener;
 mod request;
 mod response;
 mod result;
+mod server;
 pub mod storage;
+mod tls;
+mod websocket;
 
 pub use crate::{
+    builder::ParodyBuilder,
     cache_middleware::{CacheMiddleware, ResponseCache},
+    certificate_authority::CertificateAuthority,
     forward_middleware::{ForwardMiddleware, ProxyResponse},
+    proxy_listener::ProxyListener,
+    request::RequestLogItem,
+    tls::ServerCertificate,
 };
+
+/// Settings of requests to the upstream
+pub mod upstream {
+    pub use crate::forward_middleware::{ClientIdentity, Config, RedirectPolicy};
+}
+
 use crate::{
     error::{Error, UtilError},
     forward_middleware::ProxyLoad,
-    request::{ParodyRequest, RequestLogItem},
+    request::ParodyRequest,
+    response::Response,
     result::Result,
+    server::{HandlerError, HandlerResult, Server},
+    storage::DirectoryStorage,
 };
-use hyper::net::HttpListener;
 use std::{
+    collections::{BTreeSet, VecDeque},
     net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     str::FromStr,
     sync::{Arc, Mutex},
 };
 
-fn handle_request(req: &mut iron::Request) -> iron::IronResult<iron::Response> {
+/// How many requests without a recording are kept for [`Parody::misses`](struct.Parody.html#method.misses)
+const MAX_MISSES: usize = 100;
+
+async fn handle_request(mut req: server::Request) -> HandlerResult {
     trace!("Handling request: {} {}", req.method, req.url);
 
-    req.extensions
-        .get::<persistent::Write<RequestStorage>>()
-        .map(|a_storage| {
-            a_storage
-                .lock()
-                .unwrap()
-                .push(Box::new(RequestLogItem::from(req as &iron::Request)));
-            debug!("Logged request: {} {}", req.method, req.url);
-        });
+    if let Some(RequestStorage(a_storage)) = req.extensions.get::<RequestStorage>() {
+        a_storage
+            .lock()
+            .unwrap()
+            .push(Box::new(RequestLogItem::from(&req)));
+        debug!("Logged request: {} {}", req.method, req.url);
+    }
 
-    let proxy = req
+    let ProxyResponse(proxy) = req
         .extensions
         .remove::<ProxyResponse>()
         .expect("Proxy response should be always found");
 
-    let response_storage = req
+    let ResponseCache(response_storage) = req
         .extensions
-        .get::<ResponseCache>()
+        .remove::<ResponseCache>()
         .expect("Response cache should be always found");
 
-    match response_storage.load() {
-        Ok(cached_response) => {
-            warn!("Found cached response for: {} {}", req.method, req.url);
-            return Ok(cached_response.into());
+    let served = req
+        .extensions
+        .get::<ServedRecordings>()
+        .cloned()
+        .unwrap_or_default();
+
+    let lock = response_storage.get_lock();
+    let (replaying, mut is_coalesced) = storage::lock::read(lock.clone()).await;
+
+    if is_coalesced {
+        info!(
+            "Waiting for in-flight recording of: {} {}",
+            req.method, req.url
+        );
+    }
+
+    let (response_storage, cached_response) = load_cached(response_storage).await?;
+
+    if let Some(mut cached_response) = cached_response {
+        match is_coalesced {
+            true => info!(
+                "Coalesced with in-flight recording: {} {}",
+                req.method, req.url
+            ),
+            false => warn!("Found cached response for: {} {}", req.method, req.url),
         }
-        Err(Error::CacheMiss) => {
-            debug!("Cache miss for: {} {}", req.method, req.url);
+        served.insert(response_storage.recording());
+        storage::lock::hold(&mut cached_response, replaying);
+        return Ok(cached_response);
+    }
+
+    debug!("Cache miss for: {} {}", req.method, req.url);
+    drop(replaying);
+
+    if let Some(status) = response_storage.strict_replay_status() {
+        let request = format!("{} {}", req.method, req.url);
+        return respond_unmatched(request, response_storage, status, &served).await;
+    }
+
+    let (recording, is_waiting) = storage::lock::write(lock).await;
+
+    if is_waiting {
+        debug!(
+            "Waiting for concurrent requests of: {} {}",
+            req.method, req.url
+        );
+        is_coalesced = true;
+    }
+
+    // Another request with the same key may have recorded the response meanwhile
+    let (response_storage, cached_response) = load_cached(response_storage).await?;
+
+    if let Some(mut cached_response) = cached_response {
+        info!(
+            "Coalesced with in-flight recording: {} {}",
+            req.method, req.url
+        );
+        served.insert(response_storage.recording());
+        storage::lock::hold(&mut cached_response, recording.downgrade());
+        return Ok(cached_response);
+    }
+
+    if is_coalesced {
+        info!(
+            "In-flight recording failed, forwarding: {} {}",
+            req.method, req.url
+        );
+    }
+
+    info!("Recording: {} {}", req.method, req.url);
+
+    {
+        let request = format!("{} {}", req.method, req.url);
+        let response_storage = response_storage.clone();
+        let served = served.clone();
+
+        // Looking for near misses may walk the storage directory, the request doesn't wait for it
+        tokio::task::spawn_blocking(move || report_miss(request, &response_storage, &served));
+    }
+
+    let served_recording = response_storage.recording();
+    let response = proxy.load(req.body).await?;
+
+    // Requests with the same key wait until the recording is saved, not until the client has it
+    let response = response_storage.record_then(response, move || {
+        served.insert(served_recording);
+        drop(recording);
+    })?;
+
+    Ok(response)
+}
+
+/// Explains why the request has no recording instead of forwarding it
+async fn respond_unmatched(
+    request: String,
+    response_storage: DirectoryStorage,
+    status: u16,
+    served: &ServedRecordings,
+) -> HandlerResult {
+    // Looking for close recordings may walk the storage directory
+    let report = tokio::task::spawn_blocking(move || response_storage.miss_report())
+        .await
+        .map_err(|error| HandlerError::new(error, http::StatusCode::INTERNAL_SERVER_ERROR))??;
+    let body = format!("No recording for: {}\n{}", request, report);
+    warn!("{}", body.trim_end());
+    served.insert_miss(storage::Miss { request, report });
+
+    let mut response = Response::with_status(
+        http::StatusCode::from_u16(status)
+            .map_err(|error| HandlerError::new(error, http::StatusCode::INTERNAL_SERVER_ERROR))?,
+    );
+    response.headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+        .headers
+        .insert(http::header::CONTENT_LENGTH, body.len().into());
+    response.body = Some(Box::new(body.into_bytes()));
+
+    Ok(response)
+}
+
+/// Keeps the miss and logs recordings differing from the request in a single way, e.g. by a query parameter
+fn report_miss(request: String, response_storage: &DirectoryStorage, served: &ServedRecordings) {
+    match response_storage.miss_report() {
+        Ok(report) => {
+            for near_miss in report.near_misses() {
+                info!("Near miss for {}: {}", request, near_miss);
+            }
+            served.insert_miss(storage::Miss { request, report });
         }
+        Err(error) => debug!("Cannot look for near misses of {}: {}", request, error),
+    }
+}
+
+/// Loads the recorded response, `None` on a cache miss
+async fn load_cached(
+    response_storage: DirectoryStorage,
+) -> std::result::Result<(DirectoryStorage, Option<Response>), HandlerError> {
+    // Storage reads files, which would block the runtime
+    let (response_storage, cached_response) = tokio::task::spawn_blocking(move || {
+        let cached_response = response_storage.load();
+        (response_storage, cached_response)
+    })
+    .await
+    .map_err(|error| HandlerError::new(error, http::StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    match cached_response {
+        Ok(cached_response) => Ok((response_storage, Some(cached_response))),
+        Err(Error::CacheMiss) => Ok((response_storage, None)),
         Err(error) => {
             warn!("Cannot load response from cache: {}", error);
-            return Err(iron::IronError::new(
-                error,
-                iron::status::InternalServerError,
-            ));
+            Err(error.into())
         }
-    };
+    }
+}
 
-    let mut response = match proxy.load() {
-        Ok(upstream_response) => upstream_response,
-        Err(error) => {
-            return Err(iron::IronError::new(
-                error,
-                iron::status::InternalServerError,
-            ))
-        }
-    };
+type Requests = Vec<Box<dyn ParodyRequest + Send + Sync>>;
 
-    response_storage
-        .save(&mut response)
-        .map_err(|error| iron::IronError::new(error, iron::status::InternalServerError))?;
+/// Journal of requests, inserted into request extensions
+#[derive(Clone)]
+struct RequestStorage(Arc<Mutex<Requests>>);
 
-    Ok(iron::Response::with(iron::status::Ok))
+/// Recordings served in the session, inserted into request extensions
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ServedRecordings {
+    recordings: Arc<Mutex<BTreeSet<storage::Recording>>>,
+    /// The latest requests which had no recording
+    misses: Arc<Mutex<VecDeque<storage::Miss>>>,
+    root_dir: PathBuf,
+    /// File the recordings are appended to when first served
+    journal_path: Option<Arc<PathBuf>>,
 }
 
-type Requests = Vec<Box<dyn ParodyRequest + Send + Sync>>;
-struct RequestStorage;
-impl iron::typemap::Key for RequestStorage {
-    type Value = Requests;
+impl ServedRecordings {
+    fn new(root_dir: PathBuf, journal_path: Option<PathBuf>) -> Self {
+        Self {
+            recordings: Default::default(),
+            misses: Default::default(),
+            root_dir,
+            journal_path: journal_path.map(Arc::new),
+        }
+    }
+
+    pub(crate) fn insert_miss(&self, miss: storage::Miss) {
+        let mut misses = self.misses.lock().unwrap();
+
+        if misses.len() == MAX_MISSES {
+            misses.pop_front();
+        }
+        misses.push_back(miss);
+    }
+
+    pub(crate) fn insert(&self, recording: storage::Recording) {
+        let journal_path = match &self.journal_path {
+            Some(journal_path) => journal_path,
+            None => {
+                self.recordings.lock().unwrap().insert(recording);
+                return;
+            }
+        };
+
+        if !self.recordings.lock().unwrap().insert(recording.clone()) {
+            return;
+        }
+
+        // Written right away, a write left to a background task
+        // is lost when the process exits right after its last request
+        if let Err(error) = storage::append_to_journal(journal_path, &recording) {
+            warn!(
+                "Cannot append {} to {}: {}",
+                recording,
+                journal_path.to_string_lossy(),
+                error
+            );
+        }
+    }
 }
 
 /// Represents a running Parody server
 #[derive(Debug)]
 pub struct Parody {
-    listener: iron::Listening,
+    local_addr: SocketAddr,
+    server: tokio::task::JoinHandle<()>,
+    /// Set when the server runs on a runtime of its own
+    runtime: Option<tokio::runtime::Runtime>,
     a_storage: Option<Arc<Mutex<Requests>>>,
+    served: Option<ServedRecordings>,
+    certificate_pem: Option<String>,
 }
 
-/// Stops the listener on destruction
+/// Stops the server on destruction
 impl Drop for Parody {
     fn drop(&mut self) {
-        match self.listener.close() {
-            Ok(_) => {}
-            Err(error) => error!("Cannot close listener: {}", error),
+        self.server.abort();
+
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
         }
     }
 }
 
 impl Parody {
+    /// Runs the server on a runtime of its own, so callers don't need one
+    pub(crate) fn run(server: Server) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let mut parody = Self::spawn(server, runtime.handle())?;
+
+        parody.runtime = Some(runtime);
+        Ok(parody)
+    }
+
+    /// Runs the server on the runtime of the handle
+    pub(crate) fn spawn(server: Server, handle: &tokio::runtime::Handle) -> Result<Self> {
+        Ok(Self {
+            local_addr: server.local_addr()?,
+            server: handle.spawn(server.run()),
+            runtime: None,
+            a_storage: None,
+            served: None,
+            certificate_pem: None,
+        })
+    }
+
     pub fn ip(&self) -> IpAddr {
-        self.listener.socket.ip()
+        self.local_addr.ip()
     }
 
     pub fn port(&self) -> u16 {
-        self.listener.socket.port()
+        self.local_addr.port()
     }
 
     pub fn requests(&self) -> Option<Arc<Mutex<Requests>>> {
-        self.a_storage.clone().map(|a_storage| a_storage.clone())
+        self.a_storage.clone()
+    }
+
+    /// Copies of the logged requests, the journal is never locked across awaits
+    pub async fn logged_requests(&self) -> Option<Vec<RequestLogItem>> {
+        self.a_storage.as_ref().map(|a_storage| {
+            a_storage
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|req| RequestLogItem::from(req.as_ref()))
+                .collect()
+        })
+    }
+
+    pub async fn request_count(&self) -> Option<usize> {
+        self.a_storage
+            .as_ref()
+            .map(|a_storage| a_storage.lock().unwrap().len())
+    }
+
+    /// Recordings served so far, sorted by path
+    pub fn served_recordings(&self) -> Vec<storage::Recording> {
+        self.served
+            .as_ref()
+            .map(|served| served.recordings.lock().unwrap().iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The latest requests which had no recording, with the recordings closest to them
+    pub fn misses(&self) -> Vec<storage::Miss> {
+        self.served
+            .as_ref()
+            .map(|served| served.misses.lock().unwrap().iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Recordings under the root dir not served so far, sorted by path
+    pub fn unused_recordings(&self) -> Result<Vec<storage::Recording>> {
+        match &self.served {
+            Some(served) => {
+                storage::find_unused(&served.root_dir, &served.recordings.lock().unwrap().clone())
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Stops the server, the port is closed once it resolves
+    ///
+    /// Unlike dropping, it waits for the server to stop, so a port can be
+    /// reused right away in async tests.
+    pub async fn shutdown(mut self) {
+        self.server.abort();
+
+        if let Err(error) = (&mut self.server).await {
+            if !error.is_cancelled() {
+                warn!("Server stopped with an error: {}", error);
+            }
+        }
+    }
+
+    /// PEM-encoded certificate clients should trust when the server uses TLS
+    ///
+    /// For a self-signed server it's the certificate of the authority
+    /// which issued the server certificate.
+    pub fn certificate_pem(&self) -> Option<&str> {
+        self.certificate_pem.as_deref()
     }
 }
 
@@ -174,21 +467,111 @@ pub fn start_relative_to_file(upstream_url: &str, file: &str) -> Result<Parody>
 /// println!("PARODY_PORT={}", parody.port());
 /// ```
 pub fn start(upstream_url: url::Url, storage_config: storage::Config) -> Result<Parody> {
-    let mut chain = iron::Chain::new(handle_request);
-    chain.link_before(CacheMiddleware::new().with_storage_config(storage_config));
-    chain.link_before(ForwardMiddleware::new(upstream_url));
+    ParodyBuilder::new()
+        .with_storage_config(storage_config)
+        .start(upstream_url)
+}
 
-    let a_storage = Arc::new(Mutex::new(Vec::new()));
+/// Starts a server at random port at localhost on the current Tokio runtime
+///
+/// Unlike [`start`], the server doesn't get a runtime of its own,
+/// so it can run inside `#[tokio::test]` and other async code.
+///
+/// [`start`]: fn.start.html
+///
+/// # Example
+/// ```
+/// use std::str::FromStr;
+/// use std::path::Path;
+/// tokio::runtime::Runtime::new().unwrap().block_on(async {
+///     let storage_config = parody::storage::Config::default().with_root_dir(Path::new("/tmp/parody/example.com").to_owned());
+///     let upstream_url = url::Url::from_str("http://example.com").unwrap();
+///     let parody = parody::start_async(upstream_url, storage_config).await.unwrap();
+///     println!("PARODY_PORT={}", parody.port());
+///     parody.shutdown().await;
+/// });
+/// ```
+pub async fn start_async(
+    upstream_url: url::Url,
+    storage_config: storage::Config,
+) -> Result<Parody> {
+    ParodyBuilder::new()
+        .with_storage_config(storage_config)
+        .start_async(upstream_url)
+        .await
+}
 
-    chain.link(persistent::Write::<RequestStorage>::both(a_storage.clone()));
+/// Starts a server serving HTTPS at random port at localhost
+///
+/// Clients should trust [`Parody::certificate_pem`] to connect.
+///
+/// [`Parody::certificate_pem`]: struct.Parody.html#method.certificate_pem
+///
+/// # Example
+/// ```
+/// use std::str::FromStr;
+/// use std::path::Path;
+/// let storage_config = parody::storage::Config::default().with_root_dir(Path::new("/tmp/parody/example.com").to_owned());
+/// let upstream_url = url::Url::from_str("http://example.com").unwrap();
+/// let parody = parody::start_tls(upstream_url, storage_config, parody::ServerCertificate::SelfSigned).unwrap();
+/// println!("PARODY_URL=https://localhost:{}", parody.port());
+/// println!("{}", parody.certificate_pem().unwrap());
+/// ```
+pub fn start_tls(
+    upstream_url: url::Url,
+    storage_config: storage::Config,
+    certificate: ServerCertificate,
+) -> Result<Parody> {
+    ParodyBuilder::new()
+        .with_storage_config(storage_config)
+        .with_tls(certificate)
+        .start(upstream_url)
+}
 
-    let listener: HttpListener = HttpListener::new(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+/// Starts a forward HTTP proxy at random port at localhost
+///
+/// Clients should use the server as their HTTP proxy (e.g. via `HTTP_PROXY`),
+/// the upstream is taken from the absolute request URI and responses are stored
+/// in a separate directory for each destination host under the storage root.
+/// `CONNECT` requests are tunneled to their destination without recording.
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// let storage_config = parody::storage::Config::default().with_root_dir(Path::new("/tmp/parody").to_owned());
+/// let parody = parody::start_proxy(storage_config).unwrap();
+/// println!("HTTP_PROXY=http://{}:{}", parody.ip(), parody.port());
+/// ```
+pub fn start_proxy(storage_config: storage::Config) -> Result<Parody> {
+    ParodyBuilder::new()
+        .with_storage_config(storage_config)
+        .start_proxy()
+}
 
-    iron::Iron::new(chain)
-        .listen(listener, iron::Protocol::http())
-        .map(|listener| Parody {
-            listener: listener,
-            a_storage: Some(a_storage),
-        })
-        .map_err(|err| err.into())
+/// Starts a forward HTTP proxy decrypting `CONNECT` tunnels
+///
+/// Works like [`start_proxy`], but HTTPS requests are intercepted too:
+/// the proxy presents certificates issued by the authority, so clients
+/// have to trust [`CertificateAuthority::certificate_pem`].
+///
+/// [`start_proxy`]: fn.start_proxy.html
+/// [`CertificateAuthority::certificate_pem`]: struct.CertificateAuthority.html#method.certificate_pem
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// let certificate_authority = parody::CertificateAuthority::generate().unwrap();
+/// println!("{}", certificate_authority.certificate_pem());
+/// let storage_config = parody::storage::Config::default().with_root_dir(Path::new("/tmp/parody").to_owned());
+/// let parody = parody::start_intercepting_proxy(storage_config, certificate_authority).unwrap();
+/// println!("HTTPS_PROXY=http://{}:{}", parody.ip(), parody.port());
+/// ```
+pub fn start_intercepting_proxy(
+    storage_config: storage::Config,
+    certificate_authority: CertificateAuthority,
+) -> Result<Parody> {
+    ParodyBuilder::new()
+        .with_storage_config(storage_config)
+        .with_certificate_authority(certificate_authority)
+        .start_proxy()
 }
@@ -1,51 +1,418 @@
 extern crate clap;
-extern crate iron;
 extern crate parody; // use parody::ParodyServer;
 
 #[macro_use]
 extern crate log;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use std::str::FromStr;
 
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
 fn main() {
     env_logger::init();
 
     let matches = App::new("parody-server")
         .version("0.1")
         .about("Saves responses from remote server")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("lists recordings with their age")
+                .arg(
+                    Arg::with_name("storage-dir")
+                        .required(true)
+                        .value_name("STORAGE_DIR")
+                        .help("where requests are stored"),
+                )
+                .arg(
+                    Arg::with_name("max-age")
+                        .long("max-age")
+                        .takes_value(true)
+                        .value_name("DAYS")
+                        .help("mark recordings older than this as stale"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("removes recordings never served according to the journals")
+                .arg(
+                    Arg::with_name("storage-dir")
+                        .required(true)
+                        .value_name("STORAGE_DIR")
+                        .help("where requests are stored"),
+                )
+                .arg(
+                    Arg::with_name("journal")
+                        .long("journal")
+                        .takes_value(true)
+                        .required(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("FILE")
+                        .help("journal of served recordings written by --journal"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("only list recordings which would be removed"),
+                ),
+        )
         .arg(
             Arg::with_name("target-url")
-                .required(true)
+                .required_unless_one(&["proxy", "check-redaction", "migrate"])
                 .value_name("TARGET_URL")
                 .help("a proxy we forward requests to"),
         )
         .arg(
             Arg::with_name("storage-dir")
-                .required(true)
+                .required_unless_one(&["proxy", "check-redaction", "migrate"])
                 .value_name("STORAGE_DIR")
                 .help("where to store requests we make"),
         )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .takes_value(true)
+                .value_name("STORAGE_DIR")
+                .conflicts_with_all(&["target-url", "storage-dir"])
+                .help("act as a forward HTTP proxy, storing requests per destination host"),
+        )
+        .arg(
+            Arg::with_name("check-redaction")
+                .long("check-redaction")
+                .takes_value(true)
+                .value_name("STORAGE_DIR")
+                .conflicts_with_all(&["target-url", "storage-dir", "proxy"])
+                .help("list recordings with secrets matching the redaction rules and fail if there are any"),
+        )
+        .arg(
+            Arg::with_name("migrate")
+                .long("migrate")
+                .takes_value(true)
+                .value_name("STORAGE_DIR")
+                .conflicts_with_all(&["target-url", "storage-dir", "proxy", "check-redaction"])
+                .help("convert recordings from separate status and headers files to metadata files"),
+        )
+        .arg(
+            Arg::with_name("journal")
+                .long("journal")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("append recordings served by the server to this file, see the prune subcommand"),
+        )
+        .arg(
+            Arg::with_name("canonical-json")
+                .long("canonical-json")
+                .help("store JSON bodies with sorted keys and consistent indentation"),
+        )
+        .arg(
+            Arg::with_name("mask-json")
+                .long("mask-json")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATH")
+                .help("store the volatile JSON body value at this path (e.g. $.items.*.created_at) as a placeholder"),
+        )
+        .arg(
+            Arg::with_name("timing-scale")
+                .long("timing-scale")
+                .takes_value(true)
+                .value_name("FACTOR")
+                .help("multiply recorded delays between chunks of streamed responses, 0 replays them at once"),
+        )
+        .arg(
+            Arg::with_name("strict-replay")
+                .long("strict-replay")
+                .help("never forward requests, answer those without a recording with 599 and the closest recordings"),
+        )
+        .arg(
+            Arg::with_name("strict-replay-status")
+                .long("strict-replay-status")
+                .takes_value(true)
+                .value_name("STATUS")
+                .requires("strict-replay")
+                .help("status of responses to requests without a recording in the strict replay mode"),
+        )
+        .arg(
+            Arg::with_name("max-age")
+                .long("max-age")
+                .takes_value(true)
+                .value_name("DAYS")
+                .help("record again recordings older than this, or of unknown age, unless replay is strict"),
+        )
+        .arg(
+            Arg::with_name("deny-header")
+                .long("deny-header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME")
+                .help("neither save nor replay this header"),
+        )
+        .arg(
+            Arg::with_name("allow-header")
+                .long("allow-header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME")
+                .help("save and replay this header even if it's denied, e.g. by default"),
+        )
+        .arg(
+            Arg::with_name("all-headers")
+                .long("all-headers")
+                .help("don't deny hop-by-hop and volatile headers by default"),
+        )
+        .arg(
+            Arg::with_name("redact-header")
+                .long("redact-header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME")
+                .help("replace values of this header with a placeholder when saving"),
+        )
+        .arg(
+            Arg::with_name("redact-query")
+                .long("redact-query")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME")
+                .help("replace values of this query parameter with a placeholder when saving"),
+        )
+        .arg(
+            Arg::with_name("redact-json")
+                .long("redact-json")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATH")
+                .help("replace values at this dot-separated path in JSON bodies with a placeholder"),
+        )
+        .arg(
+            Arg::with_name("redact-pattern")
+                .long("redact-pattern")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("REGEX")
+                .help("replace matches (or their `secret` group) in headers and bodies with a placeholder"),
+        )
+        .arg(
+            Arg::with_name("ca-dir")
+                .long("ca-dir")
+                .takes_value(true)
+                .value_name("CA_DIR")
+                .requires("proxy")
+                .help("intercept HTTPS in the proxy mode with a CA loaded from (or saved to) this directory"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .value_name("CERT_FILE")
+                .requires("tls-key")
+                .conflicts_with("proxy")
+                .help("serve HTTPS with a PEM-encoded certificate chain from this file"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .value_name("KEY_FILE")
+                .requires("tls-cert")
+                .help("a PEM-encoded private key for --tls-cert"),
+        )
+        .arg(
+            Arg::with_name("tls-self-signed")
+                .long("tls-self-signed")
+                .takes_value(true)
+                .value_name("CA_FILE")
+                .conflicts_with_all(&["proxy", "tls-cert"])
+                .help("serve HTTPS with a self-signed certificate, saving its CA certificate to this file"),
+        )
+        .arg(
+            Arg::with_name("upstream-ca")
+                .long("upstream-ca")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("CERT_FILE")
+                .help("trust a PEM-encoded upstream CA certificate from this file"),
+        )
+        .arg(
+            Arg::with_name("upstream-insecure")
+                .long("upstream-insecure")
+                .help("accept any upstream certificate"),
+        )
+        .arg(
+            Arg::with_name("rewrite-location")
+                .long("rewrite-location")
+                .help("rewrite redirect locations pointing at the upstream to this server"),
+        )
+        .arg(
+            Arg::with_name("rewrite-origin")
+                .long("rewrite-origin")
+                .conflicts_with("proxy")
+                .help("replace the upstream origin with this server's one in replayed responses"),
+        )
+        .arg(
+            Arg::with_name("upstream-proxy")
+                .long("upstream-proxy")
+                .takes_value(true)
+                .value_name("PROXY_URL")
+                .help("reach the upstream through this HTTP proxy"),
+        )
+        .arg(
+            Arg::with_name("upstream-timeout")
+                .long("upstream-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("timeout of each read from the upstream, streamed responses last as long as data keeps coming"),
+        )
         .get_matches();
 
-    let target_url = match url::Url::from_str(
-        matches
-            .value_of("target-url")
-            .expect("Target URL should be supplied"),
-    ) {
-        Ok(url) => url,
-        Err(error) => {
+    let target_url = match matches.value_of("target-url").map(url::Url::from_str) {
+        Some(Ok(url)) => Some(url),
+        Some(Err(error)) => {
             eprintln!("Target URL is invalid: {}", error);
             std::process::exit(2);
         }
+        None => None,
     };
 
+    if let Some(storage_dir) = matches.value_of("migrate") {
+        match parody::storage::migrate(std::path::Path::new(storage_dir)) {
+            Ok(migrated) => {
+                println!("Migrated {} recordings", migrated);
+                std::process::exit(0);
+            }
+            Err(error) => {
+                eprintln!("Cannot migrate recordings: {}", error);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("list") {
+        list(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("prune") {
+        prune(matches);
+    }
+
     let storage_dir_path = std::path::Path::new(
         matches
             .value_of("storage-dir")
+            .or_else(|| matches.value_of("proxy"))
+            .or_else(|| matches.value_of("check-redaction"))
             .expect("Storage dir should be supplied"),
     );
 
+    let mut config = parody::storage::Config::default().with_root_dir(storage_dir_path.to_owned());
+
+    if matches.is_present("canonical-json") {
+        config.use_canonical_json();
+    }
+
+    for path in matches.values_of("mask-json").into_iter().flatten() {
+        config.use_masked_json_path(path);
+    }
+
+    match matches.value_of("timing-scale").map(f64::from_str) {
+        Some(Ok(timing_scale)) if timing_scale >= 0.0 => {
+            config.use_timing_scale(timing_scale);
+        }
+        Some(Ok(timing_scale)) => {
+            eprintln!("Timing scale should not be negative: {}", timing_scale);
+            std::process::exit(2);
+        }
+        Some(Err(error)) => {
+            eprintln!("Timing scale is invalid: {}", error);
+            std::process::exit(2);
+        }
+        None => {}
+    }
+
+    if matches.is_present("strict-replay") {
+        config.use_strict_replay();
+    }
+
+    match matches.value_of("strict-replay-status").map(u16::from_str) {
+        Some(Ok(status)) if (100..1000).contains(&status) => {
+            config.use_strict_replay_status(status);
+        }
+        Some(Ok(status)) => {
+            eprintln!("Strict replay status should have three digits: {}", status);
+            std::process::exit(2);
+        }
+        Some(Err(error)) => {
+            eprintln!("Strict replay status is invalid: {}", error);
+            std::process::exit(2);
+        }
+        None => {}
+    }
+
+    if let Some(max_age) = matches.value_of("max-age") {
+        config.use_max_age(parse_max_age(max_age));
+    }
+
+    if matches.is_present("all-headers") {
+        config.use_all_headers();
+    }
+
+    for name in matches.values_of("deny-header").into_iter().flatten() {
+        config.use_denied_header(name);
+    }
+
+    for name in matches.values_of("allow-header").into_iter().flatten() {
+        config.use_allowed_header(name);
+    }
+
+    for name in matches.values_of("redact-header").into_iter().flatten() {
+        config.use_redacted_header(name);
+    }
+
+    for name in matches.values_of("redact-query").into_iter().flatten() {
+        config.use_redacted_query_param(name);
+    }
+
+    for path in matches.values_of("redact-json").into_iter().flatten() {
+        config.use_redacted_json_path(path);
+    }
+
+    for pattern in matches.values_of("redact-pattern").into_iter().flatten() {
+        match regex::Regex::new(pattern) {
+            Ok(pattern) => {
+                config.use_redaction_pattern(pattern);
+            }
+            Err(error) => {
+                eprintln!("Redaction pattern is invalid: {}", error);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if matches.is_present("check-redaction") {
+        match config.redaction.find_unredacted(storage_dir_path) {
+            Ok(findings) if findings.is_empty() => std::process::exit(0),
+            Ok(findings) => {
+                for finding in findings {
+                    println!("{}", finding);
+                }
+                std::process::exit(1);
+            }
+            Err(error) => {
+                eprintln!("Cannot check recordings: {}", error);
+                std::process::exit(2);
+            }
+        }
+    }
+
     if !storage_dir_path.exists() {
         if let Err(error) = std::fs::create_dir_all(storage_dir_path) {
             eprintln!("Cannot create target directory: {}", error);
@@ -61,9 +428,117 @@ fn main() {
         debug!("Storage dir path already exists");
     }
 
-    let config = parody::storage::Config::default().with_root_dir(storage_dir_path.to_owned());
-    match parody::start(target_url, config) {
+    let server_certificate = match (
+        matches.value_of("tls-cert"),
+        matches.value_of("tls-key"),
+        matches.value_of("tls-self-signed"),
+    ) {
+        (Some(cert_file), Some(key_file), _) => {
+            match (
+                std::fs::read_to_string(cert_file),
+                std::fs::read_to_string(key_file),
+            ) {
+                (Ok(certificate_pem), Ok(key_pem)) => Some(parody::ServerCertificate::Pem {
+                    certificate_pem,
+                    key_pem,
+                }),
+                (Err(error), _) | (_, Err(error)) => {
+                    eprintln!("Cannot read TLS certificate: {}", error);
+                    std::process::exit(2);
+                }
+            }
+        }
+        (_, _, Some(_)) => Some(parody::ServerCertificate::SelfSigned),
+        _ => None,
+    };
+
+    let mut upstream_config = parody::upstream::Config::default();
+
+    for ca_file in matches.values_of("upstream-ca").into_iter().flatten() {
+        match std::fs::read(ca_file) {
+            Ok(certificate_pem) => {
+                upstream_config = upstream_config.with_root_certificate_pem(&certificate_pem)
+            }
+            Err(error) => {
+                eprintln!("Cannot read upstream CA certificate: {}", error);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if matches.is_present("upstream-insecure") {
+        upstream_config = upstream_config.with_invalid_certs_accepted();
+    }
+
+    if matches.is_present("rewrite-location") {
+        upstream_config = upstream_config.with_location_rewrite();
+    }
+
+    match matches.value_of("upstream-proxy").map(url::Url::from_str) {
+        Some(Ok(proxy_url)) => upstream_config = upstream_config.with_proxy(proxy_url),
+        Some(Err(error)) => {
+            eprintln!("Upstream proxy URL is invalid: {}", error);
+            std::process::exit(2);
+        }
+        None => {}
+    }
+
+    match matches.value_of("upstream-timeout").map(u64::from_str) {
+        Some(Ok(seconds)) => {
+            upstream_config =
+                upstream_config.with_read_timeout(std::time::Duration::from_secs(seconds))
+        }
+        Some(Err(error)) => {
+            eprintln!("Upstream timeout is invalid: {}", error);
+            std::process::exit(2);
+        }
+        None => {}
+    }
+
+    let mut builder = parody::ParodyBuilder::new()
+        .with_storage_config(config)
+        .with_upstream_config(upstream_config);
+
+    if matches.is_present("rewrite-origin") {
+        builder = builder.with_origin_rewrite();
+    }
+
+    if let Some(journal_path) = matches.value_of("journal") {
+        builder = builder.with_journal_file(journal_path.into());
+    }
+
+    if let Some(server_certificate) = server_certificate {
+        builder = builder.with_tls(server_certificate);
+    }
+
+    let result = match (target_url, matches.value_of("ca-dir")) {
+        (Some(target_url), _) => builder.start(target_url),
+        (None, Some(ca_dir)) => {
+            match parody::CertificateAuthority::load_or_generate(std::path::Path::new(ca_dir)) {
+                Ok(certificate_authority) => builder
+                    .with_certificate_authority(certificate_authority)
+                    .start_proxy(),
+                Err(error) => {
+                    eprintln!("Cannot load certificate authority: {}", error);
+                    std::process::exit(2);
+                }
+            }
+        }
+        (None, None) => builder.start_proxy(),
+    };
+
+    let _parody = match result {
         Ok(parody) => {
+            if let (Some(ca_file), Some(certificate_pem)) = (
+                matches.value_of("tls-self-signed"),
+                parody.certificate_pem(),
+            ) {
+                if let Err(error) = std::fs::write(ca_file, certificate_pem) {
+                    eprintln!("Cannot save CA certificate: {}", error);
+                    std::process::exit(2);
+                }
+            }
+
             println!("PARODY_HOST={}", parody.ip());
             println!("PARODY_PORT={}", parody.port());
             parody
@@ -73,4 +548,132 @@ fn main() {
             std::process::exit(2);
         }
     };
+
+    // Server runs in background threads until the process is killed
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Removes recordings missing from all the journals and exits
+fn prune(matches: &clap::ArgMatches) -> ! {
+    let storage_dir_path = std::path::Path::new(
+        matches
+            .value_of("storage-dir")
+            .expect("Storage dir should be supplied"),
+    );
+    let mut used = std::collections::BTreeSet::new();
+
+    for journal_path in matches.values_of("journal").into_iter().flatten() {
+        match parody::storage::read_journal(std::path::Path::new(journal_path)) {
+            Ok(recordings) => used.extend(recordings),
+            Err(error) => {
+                eprintln!("Cannot read journal {}: {}", journal_path, error);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let unused = match parody::storage::find_unused(storage_dir_path, &used) {
+        Ok(unused) => unused,
+        Err(error) => {
+            eprintln!("Cannot list recordings: {}", error);
+            std::process::exit(2);
+        }
+    };
+
+    for recording in &unused {
+        println!("{}", recording);
+
+        if matches.is_present("dry-run") {
+            continue;
+        }
+
+        if let Err(error) = parody::storage::remove_recording(storage_dir_path, recording) {
+            eprintln!("Cannot remove {}: {}", recording, error);
+            std::process::exit(2);
+        }
+    }
+
+    match matches.is_present("dry-run") {
+        true => println!("Found {} unused recordings", unused.len()),
+        false => println!("Removed {} unused recordings", unused.len()),
+    }
+    std::process::exit(0);
+}
+
+/// Lists recordings with their age and exits
+fn list(matches: &clap::ArgMatches) -> ! {
+    let storage_dir_path = std::path::Path::new(
+        matches
+            .value_of("storage-dir")
+            .expect("Storage dir should be supplied"),
+    );
+    let mut config = parody::storage::Config::default();
+
+    if let Some(max_age) = matches.value_of("max-age") {
+        config.use_max_age(parse_max_age(max_age));
+    }
+
+    let recordings = match parody::storage::find_recordings(storage_dir_path) {
+        Ok(recordings) => recordings,
+        Err(error) => {
+            eprintln!("Cannot list recordings: {}", error);
+            std::process::exit(2);
+        }
+    };
+
+    for recording in &recordings {
+        println!("{}", describe(storage_dir_path, recording, &config));
+    }
+    std::process::exit(0);
+}
+
+/// The recording followed by its age, marked stale when older than the max age of the config
+fn describe(
+    storage_dir_path: &std::path::Path,
+    recording: &parody::storage::Recording,
+    config: &parody::storage::Config,
+) -> String {
+    let age = match parody::storage::recording_age(storage_dir_path, recording) {
+        Ok(age) => age,
+        Err(error) => {
+            eprintln!("Cannot read age of {}: {}", recording, error);
+            None
+        }
+    };
+    let description = match age {
+        // Fixtures live for days, finer units are noise
+        Some(age) if age.as_secs() >= SECONDS_PER_DAY => {
+            format!("recorded {} days ago", age.as_secs() / SECONDS_PER_DAY)
+        }
+        Some(age) if age.as_secs() < 60 => format!("recorded {}s ago", age.as_secs()),
+        Some(age) => format!(
+            "recorded {} ago",
+            humantime::format_duration(std::time::Duration::from_secs(age.as_secs() / 60 * 60))
+        ),
+        None => "recorded at unknown time".to_owned(),
+    };
+
+    match config.is_stale(age) {
+        true => format!("{} ({}, stale)", recording, description),
+        false => format!("{} ({})", recording, description),
+    }
+}
+
+/// Max age given in days, exits if invalid
+fn parse_max_age(days: &str) -> std::time::Duration {
+    match f64::from_str(days) {
+        Ok(days) if days >= 0.0 && days.is_finite() => {
+            std::time::Duration::from_secs_f64(days * SECONDS_PER_DAY as f64)
+        }
+        Ok(days) => {
+            eprintln!("Max age should not be negative: {}", days);
+            std::process::exit(2);
+        }
+        Err(error) => {
+            eprintln!("Max age is invalid: {}", error);
+            std::process::exit(2);
+        }
+    }
 }
@@ -0,0 +1,187 @@
+use crate::{
+    certificate_authority::CertificateAuthority,
+    result::Result,
+    server::{Accepting, Connection, Listener, Stream},
+};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+#[cfg(test)]
+mod test;
+
+const CONNECT_PREFIX: &[u8] = b"CONNECT ";
+const CONNECT_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+const MAX_HEAD_LENGTH: usize = 8192;
+
+/// Accepts connections for the forward-proxy mode
+///
+/// Plain HTTP requests (including absolute-form ones) are passed
+/// to the server as is. `CONNECT` requests never reach the server:
+/// by default the listener establishes a tunnel to the requested authority
+/// and copies bytes in both directions. With a certificate authority
+/// the tunnel is decrypted instead and its requests are passed to the server
+/// with the `https` origin of the tunnel.
+#[derive(Clone, Default)]
+pub struct ProxyListener {
+    certificate_authority: Option<Arc<CertificateAuthority>>,
+}
+
+impl ProxyListener {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Intercepts `CONNECT` tunnels presenting certificates issued by the authority
+    pub fn with_certificate_authority(
+        mut self,
+        certificate_authority: Arc<CertificateAuthority>,
+    ) -> Self {
+        self.certificate_authority = Some(certificate_authority);
+        self
+    }
+
+    async fn intercept(
+        &self,
+        certificate_authority: &CertificateAuthority,
+        mut client: TcpStream,
+        authority: &str,
+    ) -> Result<Stream> {
+        debug!(target: "proxy", "Intercepting tunnel to: {}", authority);
+
+        let server_config = certificate_authority.server_config(get_authority_host(authority))?;
+
+        client.write_all(CONNECT_ESTABLISHED).await?;
+
+        Ok(Stream::Tls(Box::new(
+            tokio_rustls::TlsAcceptor::from(server_config)
+                .accept(client)
+                .await?,
+        )))
+    }
+}
+
+impl Listener for ProxyListener {
+    fn accept(&self, connection: Connection) -> Accepting<'_> {
+        Box::pin(async move {
+            let is_connect = match connection.stream.tcp() {
+                Some(stream) => is_connect_request(stream).await,
+                None => false,
+            };
+            let mut stream = match connection.stream {
+                Stream::Tcp(stream) if is_connect => stream,
+                stream => {
+                    return Ok(Some(Connection {
+                        stream,
+                        ..connection
+                    }))
+                }
+            };
+            let authority = read_connect_authority(&mut stream).await?;
+
+            match &self.certificate_authority {
+                Some(certificate_authority) => Ok(Some(Connection {
+                    stream: self
+                        .intercept(certificate_authority, stream, &authority)
+                        .await?,
+                    origin: Some(url::Url::parse(&format!("https://{}", authority))?),
+                    ..connection
+                })),
+                None => {
+                    tunnel(stream, authority).await;
+                    Ok(None)
+                }
+            }
+        })
+    }
+}
+
+/// Host part of a `host:port` authority
+fn get_authority_host(authority: &str) -> &str {
+    let host = match authority.rfind(':') {
+        Some(index) if !authority[index..].contains(']') => &authority[..index],
+        _ => authority,
+    };
+
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+async fn is_connect_request(stream: &TcpStream) -> bool {
+    let mut prefix = [0; CONNECT_PREFIX.len()];
+
+    match stream.peek(&mut prefix).await {
+        Ok(length) => prefix[..length] == *CONNECT_PREFIX,
+        Err(error) => {
+            trace!(target: "proxy", "Cannot peek request: {}", error);
+            false
+        }
+    }
+}
+
+/// Reads the request head byte by byte, so nothing after it is consumed
+async fn read_request_head(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LENGTH {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Request head is too long",
+            ));
+        }
+
+        head.push(stream.read_u8().await?);
+    }
+
+    Ok(head)
+}
+
+async fn read_connect_authority(stream: &mut TcpStream) -> std::io::Result<String> {
+    let head = read_request_head(stream).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+
+    match request.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) | Err(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Malformed CONNECT request",
+            ))
+        }
+    }
+
+    request.path.map(str::to_owned).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "CONNECT request has no authority",
+        )
+    })
+}
+
+/// Connects to the authority and copies bytes until either side hangs up
+async fn tunnel(mut client: TcpStream, authority: String) {
+    debug!(target: "proxy", "Tunneling to: {}", authority);
+
+    let mut upstream = match TcpStream::connect(&authority).await {
+        Ok(upstream) => upstream,
+        Err(error) => {
+            warn!(target: "proxy", "Cannot connect to {}: {}", authority, error);
+            let _ = client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+                .await;
+            return;
+        }
+    };
+
+    if let Err(error) = client.write_all(CONNECT_ESTABLISHED).await {
+        warn!(target: "proxy", "Cannot confirm tunnel to {}: {}", authority, error);
+        return;
+    }
+
+    if let Err(error) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        trace!(target: "proxy", "Tunnel closed: {}", error);
+    }
+}
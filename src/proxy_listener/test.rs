@@ -0,0 +1,126 @@
+use super::*;
+use std::{
+    io::{BufRead, Read, Write},
+    net::{SocketAddr, TcpListener},
+};
+
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+fn start_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Echo server should start");
+    let address = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.expect("Echo connection should be accepted");
+            let mut stream_clone = stream.try_clone().unwrap();
+            std::io::copy(&mut stream, &mut stream_clone).unwrap();
+        }
+    });
+
+    address
+}
+
+fn start_proxy() -> crate::Parody {
+    crate::start_proxy(crate::storage::Config::default()).expect("Proxy should start")
+}
+
+#[test]
+fn test_proxy_listener_when_connect_requested_should_tunnel_to_authority() {
+    init();
+    let echo_address = start_echo_server();
+    let proxy = start_proxy();
+
+    let mut client = std::net::TcpStream::connect(("127.0.0.1", proxy.port())).unwrap();
+    write!(
+        client,
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+        echo_address
+    )
+    .unwrap();
+
+    let mut reader = std::io::BufReader::new(client.try_clone().unwrap());
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+    assert_eq!(status_line, "HTTP/1.1 200 Connection Established\r\n");
+
+    let mut empty_line = String::new();
+    reader.read_line(&mut empty_line).unwrap();
+    assert_eq!(empty_line, "\r\n");
+
+    client.write_all(b"Lorem ipsum").unwrap();
+    let mut echo = [0; 11];
+    reader.read_exact(&mut echo).unwrap();
+    assert_eq!(&echo, b"Lorem ipsum");
+}
+
+#[test]
+fn test_proxy_listener_when_authority_unreachable_should_respond_bad_gateway() {
+    init();
+    let unreachable_address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = start_proxy();
+
+    let mut client = std::net::TcpStream::connect(("127.0.0.1", proxy.port())).unwrap();
+    write!(client, "CONNECT {} HTTP/1.1\r\n\r\n", unreachable_address).unwrap();
+
+    let mut status_line = String::new();
+    std::io::BufReader::new(client)
+        .read_line(&mut status_line)
+        .unwrap();
+    assert_eq!(status_line, "HTTP/1.1 502 Bad Gateway\r\n");
+}
+
+#[test]
+fn test_proxy_listener_with_certificate_authority_should_intercept_tunnel() {
+    init();
+    let certificate_authority = CertificateAuthority::generate().expect("CA should be generated");
+    let certificate =
+        reqwest::Certificate::from_pem(certificate_authority.certificate_pem().as_bytes())
+            .expect("CA certificate should be valid PEM");
+
+    let storage_config = crate::storage::Config::default().with_root_dir(
+        std::path::Path::new(file!())
+            .parent()
+            .expect("source file always has a parent directory")
+            .join("test_files"),
+    );
+    let parody = crate::start_intercepting_proxy(storage_config, certificate_authority)
+        .expect("Intercepting proxy should start");
+
+    let client = reqwest::blocking::Client::builder()
+        .proxy(
+            reqwest::Proxy::https(format!("http://{}:{}", parody.ip(), parody.port()))
+                .expect("Proxy URL is valid"),
+        )
+        .add_root_certificate(certificate)
+        .build()
+        .expect("Proxy client should be built");
+
+    let response = client
+        .get("https://example.com/")
+        .send()
+        .expect("Intercepted request succeeded");
+
+    assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+    assert_eq!(
+        response.text().expect("Response should have text body"),
+        "{\"lorem\": \"ipsum\"}\n"
+    );
+
+    let requests = parody.requests().expect("Requests should be logged");
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].get_url().as_str(), "https://example.com/");
+}
+
+#[test]
+fn test_get_authority_host_should_strip_port() {
+    assert_eq!(get_authority_host("example.com:443"), "example.com");
+    assert_eq!(get_authority_host("example.com"), "example.com");
+    assert_eq!(get_authority_host("[::1]:8443"), "::1");
+}
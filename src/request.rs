@@ -3,6 +3,11 @@ use ::url::Url;
 pub trait ParodyRequest {
     fn get_url(&self) -> Url;
     fn get_method(&self) -> String;
+
+    /// Comma-separated values of the header
+    fn get_header(&self, _name: &str) -> Option<String> {
+        None
+    }
 }
 
 impl std::fmt::Debug for dyn ParodyRequest + Send + Sync {
@@ -11,55 +16,52 @@ impl std::fmt::Debug for dyn ParodyRequest + Send + Sync {
     }
 }
 
+/// A request logged to the journal
+#[derive(Clone, Debug)]
 pub struct RequestLogItem {
     url: Url,
     method: String,
     // headers: Vec<(String, Vec<u8>)>,
 }
 
-impl ParodyRequest for RequestLogItem {
-    fn get_url(&self) -> Url {
-        self.url.clone()
+impl RequestLogItem {
+    pub fn url(&self) -> &Url {
+        &self.url
     }
 
-    fn get_method(&self) -> String {
-        self.method.clone()
+    pub fn method(&self) -> &str {
+        &self.method
     }
 }
 
-impl From<iron::Request<'_, '_>> for RequestLogItem {
-    fn from(req: iron::Request<'_, '_>) -> Self {
+impl From<&(dyn ParodyRequest + Send + Sync)> for RequestLogItem {
+    fn from(req: &(dyn ParodyRequest + Send + Sync)) -> Self {
         RequestLogItem {
-            url: req.url.into(),
-            method: req.method.as_ref().to_owned(),
-            // headers: req
-            //     .headers
-            //     .iter()
-            //     .map(|header| {
-            //         (
-            //             header.name().to_string(),
-            //             header.value_string().as_bytes().to_vec(),
-            //         )
-            //     })
-            //     .collect(),
+            url: req.get_url(),
+            method: req.get_method(),
         }
     }
 }
 
-impl From<&iron::Request<'_, '_>> for RequestLogItem {
-    fn from(req: &iron::Request<'_, '_>) -> Self {
+impl ParodyRequest for RequestLogItem {
+    fn get_url(&self) -> Url {
+        self.url.clone()
+    }
+
+    fn get_method(&self) -> String {
+        self.method.clone()
+    }
+}
+
+impl From<&crate::server::Request> for RequestLogItem {
+    fn from(req: &crate::server::Request) -> Self {
         RequestLogItem {
-            url: req.url.clone().into(),
-            method: req.method.as_ref().to_owned(),
+            url: req.url.clone(),
+            method: req.method.as_str().to_owned(),
             // headers: req
             //     .headers
             //     .iter()
-            //     .map(|header| {
-            //         (
-            //             header.name().to_string(),
-            //             header.value_string().as_bytes().to_vec(),
-            //         )
-            //     })
+            //     .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_vec()))
             //     .collect(),
         }
     }
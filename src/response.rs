@@ -1,29 +1,52 @@
-use std::{io::Read, string::String};
+use std::{
+    io::{Read, Write},
+    string::String,
+};
 
 pub trait ParodyResponse {
     fn get_status(&self) -> u16;
     fn get_headers(&self) -> Vec<(String, Vec<u8>)>;
     fn get_body_reader(&mut self) -> &mut dyn Read;
-}
 
-impl ParodyResponse for reqwest::Response {
-    fn get_body_reader(&mut self) -> &mut dyn std::io::Read {
-        self
+    fn get_reason(&self) -> Option<String> {
+        None
+    }
+
+    fn get_version(&self) -> Option<String> {
+        None
     }
 
-    fn get_headers(&self) -> Vec<(String, Vec<u8>)> {
-        self.headers()
-            .iter()
-            .map(|(name, value)| {
-                (
-                    name.as_str().to_owned(),
-                    value.as_bytes().to_vec()
-                )
-            })
-            .collect()
+    /// Where the response came from
+    fn get_url(&self) -> Option<url::Url> {
+        None
     }
+}
+
+/// Writes a response body, called on a thread where blocking is allowed
+pub trait WriteBody: Send {
+    fn write_body(&mut self, res: &mut dyn Write) -> std::io::Result<()>;
+}
+
+impl WriteBody for Vec<u8> {
+    fn write_body(&mut self, res: &mut dyn Write) -> std::io::Result<()> {
+        res.write_all(self)
+    }
+}
+
+/// A response to the client
+pub struct Response {
+    pub status: http::StatusCode,
+    pub headers: http::HeaderMap,
+    /// Written to the client as it is produced, `None` for an empty body
+    pub body: Option<Box<dyn WriteBody>>,
+}
 
-    fn get_status(&self) -> u16 {
-        self.status().as_u16()
+impl Response {
+    pub fn with_status(status: http::StatusCode) -> Self {
+        Self {
+            status,
+            headers: http::HeaderMap::new(),
+            body: None,
+        }
     }
 }
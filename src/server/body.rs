@@ -0,0 +1,82 @@
+//! Request and response bodies
+
+use crate::response::WriteBody;
+use bytes::Bytes;
+use http_body::Frame;
+use http_body_util::{BodyExt, Empty};
+use std::{
+    io::Write,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// Written chunks sent ahead of the client, bounds memory used by slow clients
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+pub type Body = http_body_util::combinators::UnsyncBoxBody<Bytes, std::io::Error>;
+
+pub fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
+
+/// Body produced by the writer on a blocking thread
+///
+/// Storage reads and writes files, so bodies are written the way they are
+/// stored and passed to the client chunk by chunk.
+pub fn from_writer(mut writer: Box<dyn WriteBody>) -> Body {
+    let (sender, receiver) = channel(CHUNKS_IN_FLIGHT);
+
+    tokio::task::spawn_blocking(move || {
+        let mut channel = ChannelWriter { sender };
+
+        if let Err(error) = writer.write_body(&mut channel) {
+            debug!(target: "server", "Cannot write body: {}", error);
+            let _ = channel.sender.blocking_send(Err(error));
+        }
+    });
+
+    ChannelBody { receiver }.boxed_unsync()
+}
+
+/// Sends what is written to the body read by the server
+struct ChannelWriter {
+    sender: Sender<std::io::Result<Bytes>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.sender
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client is gone"))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Receives chunks written on another thread, ends once the writer is done
+struct ChannelBody {
+    receiver: Receiver<std::io::Result<Bytes>>,
+}
+
+impl http_body::Body for ChannelBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::io::Result<Frame<Bytes>>>> {
+        self.receiver
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(Frame::data)))
+    }
+}
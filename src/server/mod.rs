@@ -0,0 +1,421 @@
+//! HTTP server running requests through a middleware chain
+
+use crate::{request::ParodyRequest, response::Response, result::Result};
+pub use body::Body;
+use http_body_util::BodyExt;
+use hyper_util::rt::TokioIo;
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    task::JoinSet,
+};
+
+pub mod body;
+#[cfg(test)]
+mod test;
+
+pub type HandlerResult = std::result::Result<Response, HandlerError>;
+/// Accepting a connection, resolves to `None` if the listener took it over
+pub type Accepting<'a> = Pin<Box<dyn Future<Output = Result<Option<Connection>>> + Send + 'a>>;
+type Handling = Pin<Box<dyn Future<Output = HandlerResult> + Send>>;
+
+/// A request as middlewares and handlers see it
+pub struct Request {
+    pub method: http::Method,
+    /// Absolute URL, the origin comes from the connection or the `Host` header
+    pub url: url::Url,
+    pub headers: http::HeaderMap,
+    pub body: Body,
+    /// Values passed from middlewares to the handler
+    pub extensions: http::Extensions,
+    pub local_addr: SocketAddr,
+}
+
+/// A failed request and the status to respond with
+#[derive(Debug)]
+pub struct HandlerError {
+    pub error: Box<dyn std::error::Error + Send + Sync>,
+    pub status: http::StatusCode,
+}
+
+pub trait BeforeMiddleware: Send + Sync {
+    fn before(&self, req: &mut Request) -> std::result::Result<(), HandlerError>;
+}
+
+pub trait Handler: Send + Sync {
+    fn handle(&self, req: Request) -> Handling;
+}
+
+/// Passes connections to the server or takes them over
+pub trait Listener: Send + Sync {
+    fn accept(&self, connection: Connection) -> Accepting<'_>;
+}
+
+/// Middlewares run one after another before the handler
+pub struct Chain {
+    befores: Vec<Box<dyn BeforeMiddleware>>,
+    /// Inserted into each request
+    extensions: http::Extensions,
+    handler: Box<dyn Handler>,
+}
+
+/// An accepted connection
+pub struct Connection {
+    pub stream: Stream,
+    pub local_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+    /// Origin of requests in the connection, e.g. of an intercepted tunnel
+    pub origin: Option<url::Url>,
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+/// Serves connections accepted at a random port at localhost
+pub struct Server {
+    listener: std::net::TcpListener,
+    listeners: Vec<Box<dyn Listener>>,
+    chain: Arc<Chain>,
+}
+
+impl ParodyRequest for Request {
+    fn get_method(&self) -> String {
+        self.method.as_str().to_owned()
+    }
+
+    fn get_url(&self) -> url::Url {
+        self.url.clone()
+    }
+
+    fn get_header(&self, name: &str) -> Option<String> {
+        let values: Vec<String> = self
+            .headers
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .collect();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+}
+
+impl HandlerError {
+    pub fn new<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+        error: E,
+        status: http::StatusCode,
+    ) -> Self {
+        Self {
+            error: error.into(),
+            status,
+        }
+    }
+}
+
+impl From<crate::error::Error> for HandlerError {
+    fn from(error: crate::error::Error) -> Self {
+        Self::new(error, http::StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl std::fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({})", self.error, self.status)
+    }
+}
+
+impl<F, T> Handler for F
+where
+    F: Fn(Request) -> T + Send + Sync,
+    T: Future<Output = HandlerResult> + Send + 'static,
+{
+    fn handle(&self, req: Request) -> Handling {
+        Box::pin(self(req))
+    }
+}
+
+impl Chain {
+    pub fn new<H: Handler + 'static>(handler: H) -> Self {
+        Self {
+            befores: Vec::new(),
+            extensions: http::Extensions::new(),
+            handler: Box::new(handler),
+        }
+    }
+
+    pub fn link_before<M: BeforeMiddleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.befores.push(Box::new(middleware));
+        self
+    }
+
+    /// Makes the value available to middlewares and the handler of each request
+    pub fn link_extension<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.extensions.insert(value);
+        self
+    }
+
+    pub async fn handle(&self, mut req: Request) -> Response {
+        let method = req.method.clone();
+        let url = req.url.clone();
+
+        req.extensions.extend(self.extensions.clone());
+
+        let result = match self
+            .befores
+            .iter()
+            .try_for_each(|middleware| middleware.before(&mut req))
+        {
+            Ok(()) => self.handler.handle(req).await,
+            Err(error) => Err(error),
+        };
+
+        result.unwrap_or_else(|error| {
+            warn!(target: "server", "Request {} {} failed: {}", method, url, error);
+            Response::with_status(error.status)
+        })
+    }
+}
+
+impl Server {
+    pub fn new(listeners: Vec<Box<dyn Listener>>, chain: Chain) -> Result<Self> {
+        let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            listeners,
+            chain: Arc::new(chain),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts connections until the future is dropped, dropping the open ones with it
+    pub async fn run(self) {
+        let listener = match tokio::net::TcpListener::from_std(self.listener) {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!(target: "server", "Cannot listen: {}", error);
+                return;
+            }
+        };
+        let serving = Arc::new(Serving {
+            listeners: self.listeners,
+            chain: self.chain,
+        });
+        let mut connections = JoinSet::new();
+
+        loop {
+            while connections.try_join_next().is_some() {}
+
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    warn!(target: "server", "Cannot accept connection: {}", error);
+                    continue;
+                }
+            };
+            let local_addr = match stream.local_addr() {
+                Ok(local_addr) => local_addr,
+                Err(error) => {
+                    warn!(target: "server", "Cannot get connection address: {}", error);
+                    continue;
+                }
+            };
+
+            connections.spawn(serving.clone().serve(Connection {
+                stream: Stream::Tcp(stream),
+                local_addr,
+                remote_addr,
+                origin: None,
+            }));
+        }
+    }
+}
+
+/// What connections are served with
+struct Serving {
+    listeners: Vec<Box<dyn Listener>>,
+    chain: Arc<Chain>,
+}
+
+impl Serving {
+    async fn serve(self: Arc<Self>, mut connection: Connection) {
+        for listener in &self.listeners {
+            connection = match listener.accept(connection).await {
+                Ok(Some(connection)) => connection,
+                Ok(None) => return,
+                Err(error) => {
+                    warn!(target: "server", "Cannot accept connection: {}", error);
+                    return;
+                }
+            };
+        }
+
+        let Connection {
+            stream,
+            local_addr,
+            remote_addr,
+            origin,
+        } = connection;
+        trace!(target: "server", "Serving connection from: {}", remote_addr);
+
+        let scheme = match &stream {
+            Stream::Tcp(_) => "http",
+            Stream::Tls(_) => "https",
+        };
+        let chain = self.chain.clone();
+
+        let service = hyper::service::service_fn(
+            move |req: http::Request<hyper::body::Incoming>| {
+                let chain = chain.clone();
+                let (parts, body) = req.into_parts();
+                let url = get_request_url(&parts, origin.as_ref(), scheme, local_addr);
+
+                async move {
+                    let response = match url {
+                        Ok(url) => {
+                            chain
+                                .handle(Request {
+                                    method: parts.method,
+                                    url,
+                                    headers: parts.headers,
+                                    body: body.map_err(std::io::Error::other).boxed_unsync(),
+                                    extensions: parts.extensions,
+                                    local_addr,
+                                })
+                                .await
+                        }
+                        Err(error) => {
+                            warn!(target: "server", "Cannot parse request URL {}: {}", parts.uri, error);
+                            Response::with_status(http::StatusCode::BAD_REQUEST)
+                        }
+                    };
+
+                    Ok::<_, Infallible>(into_http_response(response))
+                }
+            },
+        );
+
+        if let Err(error) = hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await
+        {
+            debug!(target: "server", "Connection failed: {}", error);
+        }
+    }
+}
+
+impl Stream {
+    /// The socket, unless it's wrapped into TLS
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Stream::Tcp(stream) => Some(stream),
+            Stream::Tls(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Absolute URL of the request
+///
+/// Absolute-form URIs of proxy requests are taken as is, origin-form ones
+/// are put on the connection origin or the `Host` header.
+fn get_request_url(
+    parts: &http::request::Parts,
+    origin: Option<&url::Url>,
+    scheme: &str,
+    local_addr: SocketAddr,
+) -> std::result::Result<url::Url, url::ParseError> {
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    if let Some(origin) = origin {
+        // Joining would take a path starting with `//` for another host
+        let mut url = origin.clone();
+        url.set_path(parts.uri.path());
+        url.set_query(parts.uri.query());
+        return Ok(url);
+    }
+
+    if parts.uri.scheme().is_some() {
+        return url::Url::parse(&parts.uri.to_string());
+    }
+
+    let host = parts
+        .headers
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| local_addr.to_string());
+
+    url::Url::parse(&format!("{}://{}{}", scheme, host, path))
+}
+
+fn into_http_response(response: Response) -> http::Response<Body> {
+    let mut http_response = http::Response::new(match response.body {
+        Some(writer) => body::from_writer(writer),
+        None => body::empty(),
+    });
+
+    *http_response.status_mut() = response.status;
+    *http_response.headers_mut() = response.headers;
+    http_response
+}
@@ -0,0 +1,134 @@
+use super::*;
+use crate::Parody;
+
+struct Forbid;
+
+impl BeforeMiddleware for Forbid {
+    fn before(&self, _: &mut Request) -> std::result::Result<(), HandlerError> {
+        Err(HandlerError::new("forbidden", http::StatusCode::FORBIDDEN))
+    }
+}
+
+#[derive(Clone)]
+struct Greeting(&'static str);
+
+async fn greet(req: Request) -> HandlerResult {
+    let Greeting(greeting) = req
+        .extensions
+        .get::<Greeting>()
+        .cloned()
+        .expect("greeting is linked to the chain");
+    let mut response = Response::with_status(http::StatusCode::OK);
+    response.body = Some(Box::new(format!("{} {}", greeting, req.url).into_bytes()));
+
+    Ok(response)
+}
+
+fn parts(uri: &str, host: Option<&str>) -> http::request::Parts {
+    let mut request = http::Request::builder().uri(uri);
+    if let Some(host) = host {
+        request = request.header(http::header::HOST, host);
+    }
+
+    request
+        .body(())
+        .expect("test request is valid")
+        .into_parts()
+        .0
+}
+
+fn local_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8080))
+}
+
+#[test]
+fn get_request_url_when_origin_form_should_use_host_header() {
+    let url = get_request_url(
+        &parts("/a/b?c=d", Some("example.com:3000")),
+        None,
+        "http",
+        local_addr(),
+    )
+    .expect("Cannot get request URL");
+
+    assert_eq!(url.as_str(), "http://example.com:3000/a/b?c=d");
+}
+
+#[test]
+fn get_request_url_when_no_host_header_should_use_local_address() {
+    let url = get_request_url(&parts("/a", None), None, "https", local_addr())
+        .expect("Cannot get request URL");
+
+    assert_eq!(url.as_str(), "https://127.0.0.1:8080/a");
+}
+
+#[test]
+fn get_request_url_when_absolute_form_should_take_it_as_is() {
+    let url = get_request_url(
+        &parts("http://example.com/a?b", Some("localhost")),
+        None,
+        "http",
+        local_addr(),
+    )
+    .expect("Cannot get request URL");
+
+    assert_eq!(url.as_str(), "http://example.com/a?b");
+}
+
+#[test]
+fn get_request_url_when_connection_has_origin_should_resolve_against_it() {
+    let origin = url::Url::parse("https://example.com").expect("Cannot parse origin");
+    let url = get_request_url(
+        &parts("/a", Some("localhost")),
+        Some(&origin),
+        "https",
+        local_addr(),
+    )
+    .expect("Cannot get request URL");
+
+    assert_eq!(url.as_str(), "https://example.com/a");
+}
+
+#[test]
+fn get_request_url_when_path_starts_with_two_slashes_should_keep_origin_host() {
+    let origin = url::Url::parse("https://example.com").expect("Cannot parse origin");
+    let url = get_request_url(
+        &parts("//evil.test/steal?a=b", Some("localhost")),
+        Some(&origin),
+        "https",
+        local_addr(),
+    )
+    .expect("Cannot get request URL");
+
+    assert_eq!(url.as_str(), "https://example.com//evil.test/steal?a=b");
+}
+
+#[test]
+fn server_should_pass_extensions_to_handler() {
+    let mut chain = Chain::new(greet);
+    chain.link_extension(Greeting("Hello"));
+    let guard = Parody::run(Server::new(Vec::new(), chain).expect("Cannot start server"))
+        .expect("Cannot run server");
+
+    let response = reqwest::blocking::get(format!("http://localhost:{}/a", guard.port()))
+        .expect("Cannot send request");
+
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(
+        response.text().expect("Cannot read body"),
+        format!("Hello http://localhost:{}/a", guard.port())
+    );
+}
+
+#[test]
+fn server_when_middleware_fails_should_respond_with_its_status() {
+    let mut chain = Chain::new(greet);
+    chain.link_extension(Greeting("Hello")).link_before(Forbid);
+    let guard = Parody::run(Server::new(Vec::new(), chain).expect("Cannot start server"))
+        .expect("Cannot run server");
+
+    let response = reqwest::blocking::get(format!("http://localhost:{}", guard.port()))
+        .expect("Cannot send request");
+
+    assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+}
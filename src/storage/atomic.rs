@@ -0,0 +1,92 @@
+//! Files replaced at once, so readers never see them half-written
+
+use crate::result::Result;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const TEMP_FILE_EXTENSION: &str = ".tmp";
+
+/// Tells temp files of concurrent writers in the process apart
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A file written next to its path and renamed into place on commit
+///
+/// Dropped without a commit, it's removed and the file at the path is left alone.
+pub struct AtomicFile {
+    file: File,
+    path: PathBuf,
+    temp_path: PathBuf,
+    is_committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        let temp_path = get_temp_file_path(path);
+
+        Ok(Self {
+            file: File::create(&temp_path)?,
+            path: path.to_owned(),
+            temp_path,
+            is_committed: false,
+        })
+    }
+
+    /// Replaces the file at the path with what was written
+    pub fn commit(mut self) -> Result<()> {
+        self.file.flush()?;
+        // Otherwise a crash may leave the renamed file empty
+        self.file.sync_all()?;
+        std::fs::rename(&self.temp_path, &self.path)?;
+        self.is_committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.is_committed {
+            return;
+        }
+
+        if let Err(error) = std::fs::remove_file(&self.temp_path) {
+            warn!(target: "storage", "Cannot remove {}: {}", self.temp_path.to_string_lossy(), error);
+        }
+    }
+}
+
+/// Saves the value as YAML, replacing the file at once
+pub fn save_yaml<T: serde::Serialize>(path: &Path, value: &T) -> Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    serde_yaml::to_writer(&mut file, value)?;
+    file.commit()
+}
+
+/// A hidden file in the same directory, so renaming never crosses file systems
+fn get_temp_file_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(
+        ".{}.{}-{}{}",
+        file_name,
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TEMP_FILE_EXTENSION
+    ))
+}
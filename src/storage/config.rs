@@ -1,22 +1,105 @@
-use std::path::{Path, PathBuf};
+use super::redaction::Redaction;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum QueryInPath {
     None,
+    #[default]
     All,
     Selected(Vec<String>),
 }
 
-impl Default for QueryInPath {
+/// Status of responses to unmatched requests in strict replay mode
+pub const STRICT_REPLAY_STATUS: u16 = 599;
+
+impl std::fmt::Display for QueryInPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryInPath::None => write!(f, "none"),
+            QueryInPath::All => write!(f, "all"),
+            QueryInPath::Selected(queries) => write!(f, "selected ({})", queries.join(", ")),
+        }
+    }
+}
+
+/// Headers dropped by default: hop-by-hop ones describe a single connection
+/// and volatile ones change on every request, producing noisy diffs
+pub const DEFAULT_DENIED_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "date",
+    "age",
+    "x-request-id",
+    "x-correlation-id",
+    "x-amzn-requestid",
+    "x-amz-request-id",
+    "x-amz-cf-id",
+    "cf-ray",
+    "x-runtime",
+    "server-timing",
+];
+
+/// Which headers are saved and replayed, allowed ones are kept even if denied
+#[derive(Debug, Clone)]
+pub struct HeaderFilter {
+    /// Lowercase names of headers to drop
+    pub denied: Vec<String>,
+    /// Lowercase names of headers to keep
+    pub allowed: Vec<String>,
+}
+
+impl Default for HeaderFilter {
     fn default() -> Self {
-        QueryInPath::All
+        Self {
+            denied: DEFAULT_DENIED_HEADERS
+                .iter()
+                .map(|name| (*name).to_owned())
+                .collect(),
+            allowed: Vec::new(),
+        }
+    }
+}
+
+impl HeaderFilter {
+    pub fn is_kept(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+
+        self.allowed.contains(&name) || !self.denied.contains(&name)
     }
 }
 
 #[derive(Default, Clone)]
 pub struct Config {
     pub query_in_path: QueryInPath,
+    /// Whether to store responses in a separate directory for each host
+    pub host_in_path: bool,
     pub root_dir: PathBuf,
+    /// Upstream URL whose origin is replaced with Parody's one in replayed responses
+    pub rewrite_origin: Option<url::Url>,
+    /// Secrets to remove from responses before saving them
+    pub redaction: Redaction,
+    pub header_filter: HeaderFilter,
+    /// Store JSON bodies with sorted keys and consistent indentation,
+    /// so re-recording an unchanged response produces no diff
+    pub canonical_json: bool,
+    /// JSON paths of volatile values (timestamps, ids) stored as a placeholder
+    pub masked_json_paths: Vec<String>,
+    /// Multiplies recorded delays between chunks of streamed bodies, the original timing if unset
+    pub timing_scale: Option<f64>,
+    /// Status to respond with to requests without a recording instead of forwarding them
+    pub strict_replay_status: Option<u16>,
+    /// Recordings older than this are recorded again, unless replay is strict
+    pub max_age: Option<Duration>,
 }
 
 impl Config {
@@ -30,6 +113,173 @@ impl Config {
         self
     }
 
+    pub fn use_host_path(&mut self) -> &Self {
+        self.host_in_path = true;
+        self
+    }
+
+    pub fn with_host_path(mut self) -> Self {
+        self.use_host_path();
+        self
+    }
+
+    pub fn use_origin_rewrite(&mut self, upstream_url: url::Url) -> &Self {
+        self.rewrite_origin = Some(upstream_url);
+        self
+    }
+
+    pub fn with_origin_rewrite(mut self, upstream_url: url::Url) -> Self {
+        self.use_origin_rewrite(upstream_url);
+        self
+    }
+
+    pub fn use_canonical_json(&mut self) -> &Self {
+        self.canonical_json = true;
+        self
+    }
+
+    pub fn with_canonical_json(mut self) -> Self {
+        self.use_canonical_json();
+        self
+    }
+
+    pub fn use_masked_json_path(&mut self, path: &str) -> &Self {
+        self.masked_json_paths.push(path.to_owned());
+        self
+    }
+
+    pub fn with_masked_json_path(mut self, path: &str) -> Self {
+        self.use_masked_json_path(path);
+        self
+    }
+
+    /// Replays streamed bodies faster (below 1) or slower (above 1), 0 sends them at once
+    pub fn use_timing_scale(&mut self, timing_scale: f64) -> &Self {
+        self.timing_scale = Some(timing_scale);
+        self
+    }
+
+    pub fn with_timing_scale(mut self, timing_scale: f64) -> Self {
+        self.use_timing_scale(timing_scale);
+        self
+    }
+
+    /// Never forwards requests, those without a recording get a 599 explaining why
+    pub fn use_strict_replay(&mut self) -> &Self {
+        self.strict_replay_status = Some(STRICT_REPLAY_STATUS);
+        self
+    }
+
+    pub fn with_strict_replay(mut self) -> Self {
+        self.use_strict_replay();
+        self
+    }
+
+    /// Like [`use_strict_replay`](#method.use_strict_replay) with another status
+    pub fn use_strict_replay_status(&mut self, status: u16) -> &Self {
+        self.strict_replay_status = Some(status);
+        self
+    }
+
+    pub fn with_strict_replay_status(mut self, status: u16) -> Self {
+        self.use_strict_replay_status(status);
+        self
+    }
+
+    /// Treats older recordings as missing, so they are refreshed from the upstream
+    ///
+    /// Recordings of unknown age, e.g. migrated ones, count as older.
+    /// In strict replay mode they are replayed anyway.
+    pub fn use_max_age(&mut self, max_age: Duration) -> &Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.use_max_age(max_age);
+        self
+    }
+
+    /// Whether a recording of the age should be recorded again
+    pub fn is_stale(&self, age: Option<Duration>) -> bool {
+        match (self.max_age, age) {
+            (Some(max_age), Some(age)) => age > max_age,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    pub fn use_denied_header(&mut self, name: &str) -> &Self {
+        self.header_filter.denied.push(name.to_ascii_lowercase());
+        self
+    }
+
+    pub fn with_denied_header(mut self, name: &str) -> Self {
+        self.use_denied_header(name);
+        self
+    }
+
+    pub fn use_allowed_header(&mut self, name: &str) -> &Self {
+        self.header_filter.allowed.push(name.to_ascii_lowercase());
+        self
+    }
+
+    pub fn with_allowed_header(mut self, name: &str) -> Self {
+        self.use_allowed_header(name);
+        self
+    }
+
+    /// Saves and replays headers as is
+    pub fn use_all_headers(&mut self) -> &Self {
+        self.header_filter.denied.clear();
+        self
+    }
+
+    pub fn with_all_headers(mut self) -> Self {
+        self.use_all_headers();
+        self
+    }
+
+    pub fn use_redacted_header(&mut self, name: &str) -> &Self {
+        self.redaction.headers.push(name.to_ascii_lowercase());
+        self
+    }
+
+    pub fn with_redacted_header(mut self, name: &str) -> Self {
+        self.use_redacted_header(name);
+        self
+    }
+
+    pub fn use_redacted_query_param(&mut self, name: &str) -> &Self {
+        self.redaction.query_params.push(name.to_owned());
+        self
+    }
+
+    pub fn with_redacted_query_param(mut self, name: &str) -> Self {
+        self.use_redacted_query_param(name);
+        self
+    }
+
+    pub fn use_redacted_json_path(&mut self, path: &str) -> &Self {
+        self.redaction.json_paths.push(path.to_owned());
+        self
+    }
+
+    pub fn with_redacted_json_path(mut self, path: &str) -> Self {
+        self.use_redacted_json_path(path);
+        self
+    }
+
+    pub fn use_redaction_pattern(&mut self, pattern: regex::Regex) -> &Self {
+        self.redaction.patterns.push(pattern);
+        self
+    }
+
+    pub fn with_redaction_pattern(mut self, pattern: regex::Regex) -> Self {
+        self.use_redaction_pattern(pattern);
+        self
+    }
+
     pub fn use_no_query_path(&mut self) -> &Self {
         self.query_in_path = QueryInPath::None;
         self
@@ -59,10 +309,11 @@ impl Config {
             }
         };
 
-        match query_in_path.binary_search_by(|probe: &String| probe.as_str().cmp(query)) {
-            Err(index) => query_in_path.insert(index, query.to_string()),
-            _ => {}
-        };
+        if let Err(index) =
+            query_in_path.binary_search_by(|probe: &String| probe.as_str().cmp(query))
+        {
+            query_in_path.insert(index, query.to_string());
+        }
 
         self
     }
@@ -114,21 +365,26 @@ mod test {
 
     #[test]
     fn test_config_with_query_when_has_query_in_path_should_return_true() {
-        assert_eq!(
-            Config::default()
-                .use_query_path("foo")
-                .is_query_in_path("foo"),
-            true
-        )
+        assert!(Config::default()
+            .use_query_path("foo")
+            .is_query_in_path("foo"))
     }
 
     #[test]
     fn test_config_with_query_when_no_query_in_path_should_return_true() {
-        assert_eq!(
-            Config::default()
-                .with_query_path("foo")
-                .is_query_in_path("bar"),
-            false
-        );
+        assert!(!Config::default()
+            .with_query_path("foo")
+            .is_query_in_path("bar"));
+    }
+
+    #[test]
+    fn test_config_is_stale_should_compare_age_with_max_age() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let config = Config::default().with_max_age(day);
+
+        assert!(!config.is_stale(Some(day / 2)));
+        assert!(config.is_stale(Some(day * 2)));
+        assert!(config.is_stale(None));
+        assert!(!Config::default().is_stale(None));
     }
 }
@@ -0,0 +1,115 @@
+//! Decoding of compressed bodies for storage and encoding them back on replay
+
+use std::io::{Read, Write};
+
+/// A content coding Parody can decode and encode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// Parses a `Content-Encoding` value, `None` for identity, unknown or stacked codings
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(ContentEncoding::Gzip),
+            "deflate" => Some(ContentEncoding::Deflate),
+            "br" => Some(ContentEncoding::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+            ContentEncoding::Brotli => "br",
+        }
+    }
+
+    pub fn decode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+
+        match self {
+            ContentEncoding::Gzip => {
+                flate2::read::MultiGzDecoder::new(body).read_to_end(&mut decoded)?;
+            }
+            ContentEncoding::Deflate => {
+                flate2::read::ZlibDecoder::new(body).read_to_end(&mut decoded)?;
+            }
+            ContentEncoding::Brotli => {
+                brotli::Decompressor::new(body, 4096).read_to_end(&mut decoded)?;
+            }
+        }
+
+        Ok(decoded)
+    }
+
+    pub fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            ContentEncoding::Brotli => {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                    encoder.write_all(body)?;
+                }
+                Ok(encoded)
+            }
+        }
+    }
+}
+
+/// Picks the coding to replay a body with
+///
+/// The recorded coding is preferred, otherwise any other acceptable one,
+/// `None` means the body should be sent as is.
+pub fn negotiate(
+    accept_encoding: Option<&str>,
+    recorded: ContentEncoding,
+) -> Option<ContentEncoding> {
+    let accepted: Vec<(String, f32)> = accept_encoding?
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .next()
+                .and_then(|quality| quality.trim().parse().ok())
+                .unwrap_or(1.0);
+
+            Some((coding, quality))
+        })
+        .collect();
+
+    let quality_of = |coding: ContentEncoding| {
+        accepted
+            .iter()
+            .find(|(name, _)| ContentEncoding::parse(name) == Some(coding))
+            .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0)
+    };
+
+    std::iter::once(recorded)
+        .chain(vec![
+            ContentEncoding::Gzip,
+            ContentEncoding::Brotli,
+            ContentEncoding::Deflate,
+        ])
+        .find(|coding| quality_of(*coding) > 0.0)
+}
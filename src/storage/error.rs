@@ -3,6 +3,7 @@ use crate::error::CommonError;
 #[derive(Debug)]
 pub(crate) enum StorageError {
     StatusFileNotFound,
+    UnsupportedVersion(u32),
     Common(CommonError),
 }
 
@@ -0,0 +1,47 @@
+use crate::result::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+/// A header value in the headers file
+///
+/// Values are plain strings when they are valid UTF-8, which is the common case,
+/// other values are base64-encoded under the `base64` key to round-trip exactly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HeaderValue {
+    Text(String),
+    Binary { base64: String },
+}
+
+impl HeaderValue {
+    pub fn from_bytes(value: Vec<u8>) -> Self {
+        match String::from_utf8(value) {
+            Ok(text) => HeaderValue::Text(text),
+            Err(error) => HeaderValue::Binary {
+                base64: STANDARD.encode(error.as_bytes()),
+            },
+        }
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        match self {
+            HeaderValue::Text(text) => Ok(text.into_bytes()),
+            HeaderValue::Binary { base64 } => Ok(STANDARD.decode(base64)?),
+        }
+    }
+
+    /// Applies the function to text values, binary ones are kept as is
+    pub fn map_text<F: FnOnce(String) -> String>(self, map: F) -> Self {
+        match self {
+            HeaderValue::Text(text) => HeaderValue::Text(map(text)),
+            binary => binary,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            HeaderValue::Text(text) => Some(text),
+            HeaderValue::Binary { .. } => None,
+        }
+    }
+}
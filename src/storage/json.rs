@@ -0,0 +1,92 @@
+//! JSON body normalization shared by canonicalization, masking and redaction
+//!
+//! Paths are dot-separated keys (e.g. `user.token`), `*` matches any array
+//! element or object member and an optional `$.` prefix is ignored.
+
+use serde_json::{Map, Value};
+
+/// Replaces masked volatile values, so they don't change between recordings
+pub const MASK_PLACEHOLDER: &str = "PARODY-MASKED";
+
+pub fn split_path(path: &str) -> Vec<&str> {
+    path.trim_start_matches("$.")
+        .split('.')
+        .filter(|key| !key.is_empty())
+        .collect()
+}
+
+/// Replaces values at the path with the placeholder, returns whether any was replaced
+pub fn replace_path(value: &mut Value, path: &[&str], placeholder: &str) -> bool {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            if value.as_str() == Some(placeholder) {
+                return false;
+            }
+
+            *value = Value::String(placeholder.to_owned());
+            return true;
+        }
+    };
+
+    match (value, *key) {
+        (Value::Object(members), "*") => replace_paths(members.values_mut(), rest, placeholder),
+        (Value::Object(members), key) => members
+            .get_mut(key)
+            .map(|member| replace_path(member, rest, placeholder))
+            .unwrap_or(false),
+        (Value::Array(elements), "*") => replace_paths(elements.iter_mut(), rest, placeholder),
+        (Value::Array(elements), key) => key
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| elements.get_mut(index))
+            .map(|element| replace_path(element, rest, placeholder))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Unlike `Iterator::any` visits all the values
+fn replace_paths<'a>(
+    values: impl Iterator<Item = &'a mut Value>,
+    path: &[&str],
+    placeholder: &str,
+) -> bool {
+    let mut is_replaced = false;
+
+    for value in values {
+        is_replaced |= replace_path(value, path, placeholder);
+    }
+
+    is_replaced
+}
+
+/// Sorts object members by key at every level
+///
+/// Done explicitly rather than relying on `serde_json::Map` being ordered,
+/// which stops being true once any dependency enables `preserve_order`.
+pub fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(members) => {
+            let mut members: Vec<(String, Value)> = members.into_iter().collect();
+            members.sort_by(|(left, _), (right, _)| left.cmp(right));
+
+            Value::Object(
+                members
+                    .into_iter()
+                    .map(|(key, member)| (key, sort_keys(member)))
+                    .collect::<Map<String, Value>>(),
+            )
+        }
+        Value::Array(elements) => Value::Array(elements.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+/// Keys sorted, indented by two spaces and ending with a newline
+pub fn to_canonical_vec(value: Value) -> Vec<u8> {
+    let mut canonical =
+        serde_json::to_vec_pretty(&sort_keys(value)).expect("JSON value should always serialize");
+    canonical.push(b'\n');
+    canonical
+}
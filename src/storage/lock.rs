@@ -0,0 +1,70 @@
+//! Locks shared by requests to the same recording
+
+use crate::response::{Response, WriteBody};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, Weak},
+};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+/// Replays share it, recording a response takes it alone
+pub type KeyLock = Arc<RwLock<()>>;
+
+/// Locks of the keys in use, dropped once no request holds them
+static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Weak<RwLock<()>>>>> = OnceLock::new();
+
+/// The lock of the key, the same for all storages of the process
+pub fn get(key: &Path) -> KeyLock {
+    let mut locks = LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .expect("Key locks should never be poisoned");
+
+    if let Some(lock) = locks.get(key).and_then(Weak::upgrade) {
+        return lock;
+    }
+
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    let lock = Arc::new(RwLock::new(()));
+    locks.insert(key.to_owned(), Arc::downgrade(&lock));
+    lock
+}
+
+/// Takes the lock to replay, `true` if it waited for a recording in flight
+pub async fn read(lock: KeyLock) -> (OwnedRwLockReadGuard<()>, bool) {
+    match lock.clone().try_read_owned() {
+        Ok(guard) => (guard, false),
+        Err(_) => (lock.read_owned().await, true),
+    }
+}
+
+/// Takes the lock to record, `true` if it waited for other requests
+pub async fn write(lock: KeyLock) -> (OwnedRwLockWriteGuard<()>, bool) {
+    match lock.clone().try_write_owned() {
+        Ok(guard) => (guard, false),
+        Err(_) => (lock.write_owned().await, true),
+    }
+}
+
+/// Keeps the guard until the body of the response is written
+pub fn hold<G: Send + 'static>(response: &mut Response, guard: G) {
+    if let Some(body) = response.body.take() {
+        response.body = Some(Box::new(GuardedBody {
+            body,
+            _guard: guard,
+        }));
+    }
+}
+
+struct GuardedBody<G> {
+    body: Box<dyn WriteBody>,
+    _guard: G,
+}
+
+impl<G: Send> WriteBody for GuardedBody<G> {
+    fn write_body(&mut self, res: &mut dyn Write) -> std::io::Result<()> {
+        self.body.write_body(res)
+    }
+}
@@ -0,0 +1,151 @@
+//! Versioned description of a recorded response
+
+use super::{header_value::HeaderValue, stream::Chunk};
+use crate::result::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// Version of the metadata format written by this Parody
+pub const CURRENT_VERSION: u32 = 1;
+
+/// Everything about a recorded response except its body
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResponseMetadata {
+    pub version: u32,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_version: Option<String>,
+    /// Headers in the order they were received, duplicates included
+    #[serde(default)]
+    pub headers: Vec<(String, HeaderValue)>,
+    /// RFC 3339 time of recording
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recorded_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parody_version: Option<String>,
+    /// `sha256:` followed by the hex digest of the stored body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// `Content-Encoding` the body was received with, it's stored decoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    /// Pieces a streamed body arrived in, replayed with the same delays
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
+}
+
+impl ResponseMetadata {
+    pub fn new(status: u16, headers: Vec<(String, HeaderValue)>) -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            status,
+            reason: None,
+            http_version: None,
+            headers,
+            recorded_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+            upstream_url: None,
+            parody_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            content_hash: None,
+            content_encoding: None,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_yaml::from_reader(File::open(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        super::save_yaml(path, self)
+    }
+
+    /// Time since recording, `None` if unknown, e.g. for legacy recordings
+    pub fn age(&self) -> Option<Duration> {
+        get_age(self.recorded_at.as_deref())
+    }
+}
+
+/// Time since the RFC 3339 time of recording, `None` if unknown
+pub(crate) fn get_age(recorded_at: Option<&str>) -> Option<Duration> {
+    let recorded_at = humantime::parse_rfc3339_weak(recorded_at?).ok()?;
+
+    // A clock set back makes the recording brand new rather than unknown
+    Some(
+        SystemTime::now()
+            .duration_since(recorded_at)
+            .unwrap_or_default(),
+    )
+}
+
+/// Calculates the content hash of data written through it
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    context: ring::digest::Context,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+        }
+    }
+
+    /// The content hash and the writer it wrote to
+    pub fn finish(self) -> (String, W) {
+        (format_content_hash(self.context.finish()), self.inner)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = self.inner.write(buf)?;
+        self.context.update(&buf[..length]);
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Content hash of a stored body, `None` if there is no body file
+pub fn hash_file(path: &Path) -> Result<Option<String>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let mut writer = HashingWriter::new(std::io::sink());
+
+    std::io::copy(&mut file, &mut writer)?;
+
+    Ok(Some(writer.finish().0))
+}
+
+fn format_content_hash(digest: ring::digest::Digest) -> String {
+    let mut hash = String::from("sha256:");
+
+    for byte in digest.as_ref() {
+        hash.push_str(&format!("{:02x}", byte));
+    }
+
+    hash
+}
+
+/// Reads a legacy status file
+pub fn load_legacy_status(path: &Path) -> Result<u16> {
+    let mut status_raw = String::new();
+    File::open(path)?.read_to_string(&mut status_raw)?;
+
+    Ok(status_raw.trim().parse()?)
+}
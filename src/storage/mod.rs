@@ -3,43 +3,101 @@ extern crate serde_json;
 extern crate serde_yaml;
 extern crate url;
 use crate::{
-    error::Error, request::ParodyRequest, response::ParodyResponse, result::Result,
+    error::Error,
+    request::ParodyRequest,
+    response::{ParodyResponse, Response, WriteBody},
+    result::Result,
     storage::error::StorageError,
 };
-pub use config::Config;
+pub(crate) use atomic::save_yaml;
+use atomic::AtomicFile;
+pub use config::{Config, HeaderFilter, QueryInPath, DEFAULT_DENIED_HEADERS, STRICT_REPLAY_STATUS};
+use encoding::ContentEncoding;
+pub(crate) use header_value::HeaderValue;
+pub use json::MASK_PLACEHOLDER;
+pub(crate) use lock::KeyLock;
+pub(crate) use metadata::get_age;
+use metadata::HashingWriter;
+pub use metadata::ResponseMetadata;
+pub use nearby::{
+    find_recordings, Difference, Miss, MissReport, NearMiss, Recording, RecordingKind,
+};
+pub use redaction::{Finding, Redaction, PLACEHOLDER};
+use rewrite::OriginRewrite;
 use std::{
     borrow::Cow,
     fs::File,
-    io::{Read, Write},
-    path::PathBuf,
+    io::Write,
+    path::{Path, PathBuf},
 };
+use stream::Chunk;
+pub use usage::{append_to_journal, find_unused, read_journal, recording_age, remove_recording};
 
+mod atomic;
 mod config;
+mod encoding;
 mod error;
+mod header_value;
+mod json;
+pub(crate) mod lock;
+mod metadata;
+mod nearby;
+mod redaction;
+mod rewrite;
+mod stream;
 #[cfg(test)]
 pub(crate) mod test;
+mod usage;
 
-
-const QUERY_SEPARATOR: &'static str = ":PARODY-QUERY";
-const HEADERS_FILE_EXTENSION: &'static str = ".headers.yaml";
-const BODY_FILE_EXTENSION: &'static str = ".body";
-const STATUS_FILE_EXTENSION: &'static str = ".status";
+const QUERY_SEPARATOR: &str = ":PARODY-QUERY";
+const HEADERS_FILE_EXTENSION: &str = ".headers.yaml";
+const BODY_FILE_EXTENSION: &str = ".body";
+const STATUS_FILE_EXTENSION: &str = ".status";
+const METADATA_FILE_EXTENSION: &str = ".meta.yaml";
+const WEBSOCKET_FILE_EXTENSION: &str = ".websocket.yaml";
+const GRPC_FILE_EXTENSION: &str = ".grpc.yaml";
 
 /// Stores a request data
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct DirectoryStorage {
     config: config::Config,
     /// A directory relative to root dir from the config where we store request details
     storage_path_relative: PathBuf,
     method: String,
+    /// Applied to loaded responses, stored files are never altered
+    origin_rewrite: Option<OriginRewrite>,
+    /// Codings the client accepts, compressed recordings are encoded back accordingly
+    accept_encoding: Option<String>,
+}
+
+/// What happened to the body on saving
+struct SavedBody {
+    /// Coding the body was decoded from
+    decoded_from: Option<ContentEncoding>,
+    /// Whether the stored body differs from the received one
+    is_changed: bool,
+    content_hash: String,
+    /// Pieces of a streamed body
+    chunks: Vec<Chunk>,
 }
 
 struct CachedBodyWriter {
     body_file_path: PathBuf,
+    /// Body prepared at load time, e.g. rewritten, the file is not read then
+    body: Option<Vec<u8>>,
+    /// Recorded pieces of a streamed body, written with their delays
+    chunks: Vec<Chunk>,
+    timing_scale: f64,
+    /// Applied to a streamed body before it's split into pieces
+    origin_rewrite: Option<OriginRewrite>,
 }
 
-impl iron::response::WriteBody for CachedBodyWriter {
+impl WriteBody for CachedBodyWriter {
     fn write_body(&mut self, res: &mut dyn Write) -> std::io::Result<()> {
+        if let Some(body) = &self.body {
+            return res.write_all(body);
+        }
+
         let mut body_file = match File::open(&self.body_file_path) {
             Ok(body_file) => body_file,
             Err(error) => match error.kind() {
@@ -48,9 +106,117 @@ impl iron::response::WriteBody for CachedBodyWriter {
             },
         };
 
-        std::io::copy(&mut body_file, res)?;
+        if self.chunks.is_empty() {
+            std::io::copy(&mut body_file, res)?;
+            return Ok(());
+        }
 
-        Ok(())
+        stream::write_chunks(
+            &mut body_file,
+            &self.chunks,
+            self.timing_scale,
+            self.origin_rewrite.as_ref(),
+            res,
+        )
+    }
+}
+
+/// Streams an upstream response to the client while saving it
+struct RecordingBodyWriter<T> {
+    storage: DirectoryStorage,
+    response: T,
+    /// Called once the recording is saved, dropped if saving fails
+    on_saved: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl<T: ParodyResponse + Send> WriteBody for RecordingBodyWriter<T> {
+    fn write_body(&mut self, res: &mut dyn Write) -> std::io::Result<()> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let storage = &self.storage;
+        let response = &mut self.response;
+        let on_saved = &mut self.on_saved;
+
+        std::thread::scope(|scope| {
+            // Saving doesn't wait for the client, chunks it hasn't taken yet are buffered
+            let saving = scope.spawn(move || {
+                let mut tee = TeeResponse {
+                    response,
+                    client: sender,
+                };
+
+                if let Err(error) = storage.save(&mut tee) {
+                    warn!(target: "storage", "Cannot save response: {}", error);
+                    return Err(std::io::Error::other(error.to_string()));
+                }
+
+                if let Some(on_saved) = on_saved.take() {
+                    on_saved();
+                }
+                Ok(())
+            });
+
+            let mut client_error = None;
+
+            for chunk in receiver {
+                if let Err(error) = res.write_all(&chunk).and_then(|_| res.flush()) {
+                    debug!(target: "storage", "Client stopped receiving the response: {}", error);
+                    client_error = Some(error);
+                    break;
+                }
+            }
+
+            saving.join().expect("Saving should never panic")?;
+
+            match client_error {
+                Some(error) => Err(error),
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+/// A response whose body is sent to the client as it is read
+struct TeeResponse<'a, T> {
+    response: &'a mut T,
+    /// Disconnected once the client is gone, the body is still read to finish the recording
+    client: std::sync::mpsc::Sender<Vec<u8>>,
+}
+
+impl<T: ParodyResponse> std::io::Read for TeeResponse<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.response.get_body_reader().read(buf)?;
+
+        if read > 0 {
+            let _ = self.client.send(buf[..read].to_vec());
+        }
+
+        Ok(read)
+    }
+}
+
+impl<T: ParodyResponse> ParodyResponse for TeeResponse<'_, T> {
+    fn get_status(&self) -> u16 {
+        self.response.get_status()
+    }
+
+    fn get_headers(&self) -> Vec<(String, Vec<u8>)> {
+        self.response.get_headers()
+    }
+
+    fn get_body_reader(&mut self) -> &mut dyn std::io::Read {
+        self
+    }
+
+    fn get_reason(&self) -> Option<String> {
+        self.response.get_reason()
+    }
+
+    fn get_version(&self) -> Option<String> {
+        self.response.get_version()
+    }
+
+    fn get_url(&self) -> Option<url::Url> {
+        self.response.get_url()
     }
 }
 
@@ -60,10 +226,17 @@ impl DirectoryStorage {
     }
 
     pub fn new_with_config<T: ParodyRequest>(req: &T, config: Config) -> Result<Self> {
+        let origin_rewrite = config
+            .rewrite_origin
+            .as_ref()
+            .and_then(|upstream_url| OriginRewrite::new(upstream_url, &req.get_url()));
+
         Ok(DirectoryStorage {
             storage_path_relative: get_response_storage_dir(req, &config)?,
-            config: config,
+            config,
             method: req.get_method(),
+            origin_rewrite,
+            accept_encoding: req.get_header("accept-encoding"),
         })
     }
 
@@ -80,64 +253,281 @@ impl DirectoryStorage {
         current_directory
     }
 
-    fn get_status_file_path(&self) -> PathBuf {
+    /// Lock of the recording, shared by storages of requests with the same key
+    pub(crate) fn get_lock(&self) -> KeyLock {
+        lock::get(&self.get_metadata_file_path())
+    }
+
+    fn get_metadata_file_path(&self) -> PathBuf {
         self.get_absolute_storage_path()
-            .join(self.method.clone() + STATUS_FILE_EXTENSION)
+            .join(self.method.clone() + METADATA_FILE_EXTENSION)
     }
 
-    fn save_status<T: ParodyResponse>(&self, resp: &T) -> Result<()> {
-        let status = resp.get_status();
-        let status_file_path = self.get_status_file_path();
+    /// Where messages of a WebSocket session opened by the request are stored
+    pub(crate) fn get_websocket_file_path(&self) -> PathBuf {
+        self.get_absolute_storage_path()
+            .join(self.method.clone() + WEBSOCKET_FILE_EXTENSION)
+    }
 
-        writeln!(&mut File::create(&status_file_path)?, "{}", status)?;
-        Ok(())
+    /// Where response messages and trailers of a gRPC call are stored
+    pub(crate) fn get_grpc_file_path(&self) -> PathBuf {
+        self.get_absolute_storage_path()
+            .join(self.method.clone() + GRPC_FILE_EXTENSION)
     }
 
+    /// Legacy layout only, new recordings keep the status in the metadata file
+    fn get_status_file_path(&self) -> PathBuf {
+        self.get_absolute_storage_path()
+            .join(self.method.clone() + STATUS_FILE_EXTENSION)
+    }
+
+    /// Legacy layout only, new recordings keep headers in the metadata file
     fn get_headers_file_path(&self) -> PathBuf {
         self.get_absolute_storage_path()
             .join(self.method.clone() + HEADERS_FILE_EXTENSION)
     }
 
-    fn save_headers<T: ParodyResponse>(&self, resp: &T) -> Result<()> {
-        let headers: Vec<(String, String)> = resp
-            .get_headers()
-            .drain(..)
+    /// Path of the stored body, whatever extension it was saved with
+    fn get_body_file_path(&self) -> PathBuf {
+        let storage_path = self.get_absolute_storage_path();
+
+        find_body_file(&storage_path, &self.method)
+            .unwrap_or_else(|| storage_path.join(self.method.clone() + BODY_FILE_EXTENSION))
+    }
+
+    /// Path to save a body with the content type to
+    fn get_new_body_file_path(&self, content_type: Option<&str>) -> PathBuf {
+        let mut file_name = self.method.clone() + BODY_FILE_EXTENSION;
+
+        if let Some(extension) = content_type.and_then(get_body_file_extension) {
+            file_name.push('.');
+            file_name.push_str(extension);
+        }
+
+        self.get_absolute_storage_path().join(file_name)
+    }
+
+    /// Removes bodies saved before with other extensions
+    fn remove_other_body_files(&self, body_file_path: &Path) -> Result<()> {
+        let storage_path = self.get_absolute_storage_path();
+
+        for entry in std::fs::read_dir(&storage_path)? {
+            let path = entry?.path();
+            let is_other_body = path != body_file_path
+                && path
+                    .file_name()
+                    .map(|name| get_body_file_method(&name.to_string_lossy()) == Some(&self.method))
+                    .unwrap_or(false);
+
+            if is_other_body {
+                debug!(target: "storage", "Removing stale body: {}", path.to_string_lossy());
+                remove_if_exists(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn prepare_headers(
+        &self,
+        headers: Vec<(String, Vec<u8>)>,
+        saved_body: &SavedBody,
+    ) -> Vec<(String, HeaderValue)> {
+        headers
+            .into_iter()
+            .filter(|(name, _)| self.config.header_filter.is_kept(name))
+            // The stored length doesn't match a changed body
+            .filter(|(name, _)| {
+                !(saved_body.is_changed && name.eq_ignore_ascii_case("content-length"))
+            })
+            // The coding is kept in metadata, the body is stored decoded
+            .filter(|(name, _)| {
+                !(saved_body.decoded_from.is_some()
+                    && name.eq_ignore_ascii_case("content-encoding"))
+            })
             .map(|(name, value): (String, Vec<u8>)| {
-                (
-                    name,
-                    std::str::from_utf8(&value)
-                        .expect("FIXME: need to decide what to do if headers are not UTF-8 strings")
-                        .to_owned(),
-                )
+                let value = if self.config.redaction.is_header_redacted(&name) {
+                    HeaderValue::Text(PLACEHOLDER.to_owned())
+                } else {
+                    HeaderValue::from_bytes(value)
+                        .map_text(|text| self.config.redaction.redact_text(text))
+                };
+
+                (name, value)
             })
-            .collect();
+            .collect()
+    }
 
-        let headers_file_path = self.get_headers_file_path();
-        match serde_yaml::to_writer(File::create(&headers_file_path)?, &headers) {
-            Ok(_) => {
-                trace!(target: "storage", "Saved headers to {}", &headers_file_path.as_os_str().to_string_lossy())
+    /// Filters and redacts headers of a response stored outside the metadata file
+    pub(crate) fn prepare_recorded_headers(
+        &self,
+        headers: Vec<(String, Vec<u8>)>,
+    ) -> Vec<(String, HeaderValue)> {
+        self.prepare_headers(
+            headers,
+            &SavedBody {
+                decoded_from: None,
+                is_changed: false,
+                content_hash: String::new(),
+                chunks: Vec::new(),
+            },
+        )
+    }
+
+    /// Decodes, formats and redacts the body as configured
+    fn prepare_body(
+        &self,
+        mut body: Vec<u8>,
+        headers: &[(String, Vec<u8>)],
+    ) -> (Vec<u8>, Option<ContentEncoding>, bool) {
+        let content_encoding = get_header(headers, "content-encoding");
+        let mut decoded_from = None;
+        let mut is_changed = false;
+
+        if let Some(content_encoding) = content_encoding {
+            match ContentEncoding::parse(&content_encoding)
+                .map(|coding| (coding, coding.decode(&body)))
+            {
+                Some((coding, Ok(decoded))) => {
+                    body = decoded;
+                    decoded_from = Some(coding);
+                    is_changed = true;
+                }
+                Some((coding, Err(error))) => {
+                    warn!(target: "storage", "Cannot decode {} body, storing it as is: {}", coding.name(), error);
+                    return (body, None, false);
+                }
+                // Identity or a coding we can't decode, either way the body is left alone
+                None if content_encoding.trim().eq_ignore_ascii_case("identity") => {}
+                None => return (body, None, false),
             }
-            Err(error) => {
-                warn!(target: "storage", "{}", error);
-                return Err(error.into());
+        }
+
+        let is_json = get_header(headers, "content-type")
+            .map(|content_type| is_json_content_type(&content_type))
+            .unwrap_or(false);
+
+        if is_json && (self.config.canonical_json || !self.config.masked_json_paths.is_empty()) {
+            if let Some(normalized) = self.normalize_json(&body) {
+                body = normalized;
+                is_changed = true;
             }
         }
 
-        Ok(())
+        if let Some(redacted_body) = self.config.redaction.redact_body(&body) {
+            debug!(target: "storage", "Redacted body of: {}", self.storage_path_relative.to_string_lossy());
+            body = redacted_body;
+            is_changed = true;
+        }
+
+        (body, decoded_from, is_changed)
     }
 
-    fn get_body_file_path(&self) -> PathBuf {
-        self.get_absolute_storage_path()
-            .join(self.method.clone() + BODY_FILE_EXTENSION)
+    /// Masked and canonicalized JSON body or `None` if it is unchanged or not JSON
+    fn normalize_json(&self, body: &[u8]) -> Option<Vec<u8>> {
+        let mut value = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+        let mut is_masked = false;
+
+        for path in &self.config.masked_json_paths {
+            is_masked |= json::replace_path(&mut value, &json::split_path(path), MASK_PLACEHOLDER);
+        }
+
+        let normalized = if self.config.canonical_json {
+            json::to_canonical_vec(value)
+        } else if is_masked {
+            serde_json::to_vec(&value).expect("JSON value should always serialize")
+        } else {
+            return None;
+        };
+
+        if normalized == body {
+            None
+        } else {
+            Some(normalized)
+        }
     }
 
-    fn save_body<T: ParodyResponse>(&self, resp: &mut T) -> Result<()> {
-        std::io::copy(
-            resp.get_body_reader(),
-            &mut File::create(&self.get_body_file_path())?,
-        )?;
+    fn save_body<T: ParodyResponse>(
+        &self,
+        resp: &mut T,
+        headers: &[(String, Vec<u8>)],
+    ) -> Result<SavedBody> {
+        let content_type = get_header(headers, "content-type");
+        let content_encoding = get_header(headers, "content-encoding");
+        let is_streaming = content_encoding.is_none()
+            && content_type
+                .as_deref()
+                .map(stream::is_streaming_content_type)
+                .unwrap_or(false);
 
-        Ok(())
+        if is_streaming {
+            let body_file_path = self.get_new_body_file_path(content_type.as_deref());
+            let mut body_writer = HashingWriter::new(AtomicFile::create(&body_file_path)?);
+            let (chunks, is_changed) = stream::copy_chunks(
+                resp.get_body_reader(),
+                &mut body_writer,
+                &self.config.redaction,
+            )?;
+            let (content_hash, body_file) = body_writer.finish();
+            body_file.commit()?;
+            self.remove_other_body_files(&body_file_path)?;
+
+            return Ok(SavedBody {
+                decoded_from: None,
+                is_changed,
+                content_hash,
+                chunks,
+            });
+        }
+
+        let is_prepared = self.config.redaction.applies_to_body()
+            || self.config.canonical_json
+            || !self.config.masked_json_paths.is_empty()
+            || content_encoding.is_some();
+
+        if !is_prepared {
+            let body_file_path = self.get_new_body_file_path(content_type.as_deref());
+            let mut body_writer = HashingWriter::new(AtomicFile::create(&body_file_path)?);
+
+            std::io::copy(resp.get_body_reader(), &mut body_writer)?;
+            let (content_hash, body_file) = body_writer.finish();
+            body_file.commit()?;
+            self.remove_other_body_files(&body_file_path)?;
+
+            return Ok(SavedBody {
+                decoded_from: None,
+                is_changed: false,
+                content_hash,
+                chunks: Vec::new(),
+            });
+        }
+
+        let mut body = Vec::new();
+        resp.get_body_reader().read_to_end(&mut body)?;
+
+        let (body, decoded_from, is_changed) = self.prepare_body(body, headers);
+        // A body left encoded isn't of its content type as far as editors are concerned
+        let is_encoded = decoded_from.is_none()
+            && content_encoding
+                .map(|content_encoding| !content_encoding.trim().eq_ignore_ascii_case("identity"))
+                .unwrap_or(false);
+        let body_file_path = self.get_new_body_file_path(match is_encoded {
+            true => None,
+            false => content_type.as_deref(),
+        });
+        let mut body_writer = HashingWriter::new(AtomicFile::create(&body_file_path)?);
+
+        body_writer.write_all(&body)?;
+        let (content_hash, body_file) = body_writer.finish();
+        body_file.commit()?;
+        self.remove_other_body_files(&body_file_path)?;
+
+        Ok(SavedBody {
+            decoded_from,
+            is_changed,
+            content_hash,
+            chunks: Vec::new(),
+        })
     }
 
     pub fn save<T: ParodyResponse>(&self, resp: &mut T) -> Result<()> {
@@ -145,89 +535,528 @@ impl DirectoryStorage {
 
         debug!("Saving response to: {}", &storage_path.to_string_lossy());
         std::fs::create_dir_all(&storage_path)?;
-        self.save_body(resp)?;
-        self.save_headers(resp)?;
-        self.save_status(resp)?;
+        let headers = resp.get_headers();
+        let saved_body = self.save_body(resp, &headers)?;
+
+        let mut metadata = ResponseMetadata::new(
+            resp.get_status(),
+            self.prepare_headers(headers, &saved_body),
+        );
+        metadata.reason = resp.get_reason();
+        metadata.http_version = resp.get_version();
+        metadata.upstream_url = resp
+            .get_url()
+            .map(|url| self.config.redaction.redact_url(&url));
+        metadata.content_encoding = saved_body
+            .decoded_from
+            .map(|coding| coding.name().to_owned());
+        metadata.content_hash = Some(saved_body.content_hash);
+        metadata.chunks = saved_body.chunks;
+
+        let metadata_file_path = self.get_metadata_file_path();
+        if let Err(error) = metadata.save(&metadata_file_path) {
+            warn!(target: "storage", "{}", error);
+            return Err(error);
+        }
+        trace!(target: "storage", "Saved metadata to {}", metadata_file_path.to_string_lossy());
+
+        // Re-recording over the legacy layout shouldn't leave stale files behind
+        remove_if_exists(&self.get_status_file_path())?;
+        remove_if_exists(&self.get_headers_file_path())?;
+
+        nearby::invalidate_index(self.config.get_root_dir());
         info!("Saved response to: {}", &storage_path.to_string_lossy());
         Ok(())
     }
 
-    fn load_headers(&self) -> Result<iron::Headers> {
+    /// Responds with the upstream response, saving it while the body is sent
+    ///
+    /// The body goes to the client and to disk as it arrives, so it is never
+    /// held in memory unless saving has to prepare it.
+    pub fn record<T: ParodyResponse + Send + 'static>(self, response: T) -> Result<Response> {
+        self.record_then(response, || {})
+    }
+
+    /// Like [`record`](#method.record), calling back once the recording is saved
+    ///
+    /// The client may not have the whole body by then, a slow one doesn't delay it.
+    pub(crate) fn record_then<T, F>(self, response: T, on_saved: F) -> Result<Response>
+    where
+        T: ParodyResponse + Send + 'static,
+        F: FnOnce() + Send + 'static,
+    {
+        std::fs::create_dir_all(self.get_absolute_storage_path())?;
+
+        let mut client_response = Response::with_status(to_status(response.get_status())?);
+
+        for (name, value) in response.get_headers() {
+            // The body is framed anew for the client
+            if name.eq_ignore_ascii_case("transfer-encoding") {
+                continue;
+            }
+
+            client_response.headers.append(
+                http::HeaderName::from_bytes(name.as_bytes()).map_err(http::Error::from)?,
+                http::HeaderValue::from_bytes(&value).map_err(http::Error::from)?,
+            );
+        }
+
+        client_response.body = Some(Box::new(RecordingBodyWriter {
+            storage: self,
+            response,
+            on_saved: Some(Box::new(on_saved)),
+        }));
+
+        Ok(client_response)
+    }
+
+    fn to_header_map(&self, headers_raw: Vec<(String, HeaderValue)>) -> Result<http::HeaderMap> {
+        let mut headers = http::HeaderMap::new();
+
+        for (name, value) in headers_raw {
+            // Recordings made before filtering or with another config may have any headers
+            if !self.config.header_filter.is_kept(&name) {
+                trace!(target: "storage", "Skipped header: {}", name);
+                continue;
+            }
+
+            let value = match &self.origin_rewrite {
+                Some(origin_rewrite) => value.map_text(|text| origin_rewrite.apply(&text)),
+                None => value,
+            };
+
+            headers.append(
+                http::HeaderName::from_bytes(name.as_bytes()).map_err(http::Error::from)?,
+                http::HeaderValue::from_bytes(&value.into_bytes()?).map_err(http::Error::from)?,
+            );
+        }
+
+        Ok(headers)
+    }
+
+    fn load_legacy_headers(&self) -> Result<Vec<(String, HeaderValue)>> {
         let headers_file_path = self.get_headers_file_path();
-        debug!("Loading headers from: {}", headers_file_path.to_string_lossy());
-        let mut headers = iron::headers::Headers::new();
-        let headers_file = match File::open(&headers_file_path) {
-            Ok(file) => file,
+        debug!(
+            "Loading headers from: {}",
+            headers_file_path.to_string_lossy()
+        );
+
+        match File::open(&headers_file_path) {
+            Ok(file) => Ok(serde_yaml::from_reader(file)?),
             Err(error) => match error.kind() {
                 std::io::ErrorKind::NotFound => {
                     debug!("Headers file not found. Returning empty headers.");
-                    return Ok(headers);
+                    Ok(Vec::new())
                 }
-                _ => return Err(error.into()),
+                _ => Err(error.into()),
             },
-        };
+        }
+    }
 
-        let headers_raw: Vec<(String, String)> = serde_yaml::from_reader(headers_file)?;
+    fn load_legacy_status(&self) -> std::result::Result<u16, StorageError> {
+        let status_file_path = self.get_status_file_path();
 
-        for (name, value) in headers_raw {
-            headers.append_raw(name, value.as_bytes().to_vec());
+        debug!(
+            "Loading status from: {}",
+            status_file_path.to_string_lossy()
+        );
+
+        if !status_file_path.exists() {
+            return Err(StorageError::StatusFileNotFound);
         }
 
-        Ok(headers)
+        metadata::load_legacy_status(&status_file_path).map_err(|error| match error {
+            Error::Common(error) => StorageError::Common(error),
+            error => {
+                trace!("Cannot load status: {:?}", error);
+                StorageError::StatusFileNotFound
+            }
+        })
     }
 
-    fn load_status(&self) -> std::result::Result<iron::status::Status, StorageError> {
-        let status_file_path = self.get_status_file_path();
+    /// Loads the metadata file or builds metadata from the legacy status and headers files
+    fn load_metadata(&self) -> std::result::Result<ResponseMetadata, StorageError> {
+        let metadata_file_path = self.get_metadata_file_path();
 
-        debug!("Loading status from: {}", status_file_path.to_string_lossy());
+        if metadata_file_path.exists() {
+            debug!(
+                "Loading metadata from: {}",
+                metadata_file_path.to_string_lossy()
+            );
+            let metadata =
+                ResponseMetadata::load(&metadata_file_path).map_err(|error| match error {
+                    Error::Common(error) => StorageError::Common(error),
+                    _ => StorageError::StatusFileNotFound,
+                })?;
 
-        let mut status_raw = String::new();
-        File::open(&status_file_path)
-            .map_err(|error| match error.kind() {
-                std::io::ErrorKind::NotFound => StorageError::StatusFileNotFound,
-                _ => {
-                    trace!("Cannot load status: {:?}", error);
-                    StorageError::Common(error.into())
-                }
-            })?
-            .read_to_string(&mut status_raw)?;
-        let status_raw = status_raw.trim();
+            if metadata.version > metadata::CURRENT_VERSION {
+                return Err(StorageError::UnsupportedVersion(metadata.version));
+            }
+
+            return Ok(metadata);
+        }
+
+        let status = self.load_legacy_status()?;
+        let headers = self.load_legacy_headers().map_err(|error| match error {
+            Error::Common(error) => StorageError::Common(error),
+            _ => StorageError::StatusFileNotFound,
+        })?;
 
-        Ok(iron::status::Status::from_u16(status_raw.parse()?))
+        Ok(ResponseMetadata {
+            version: 0,
+            recorded_at: None,
+            parody_version: None,
+            ..ResponseMetadata::new(status, headers)
+        })
     }
 
-    fn load_body(&self) -> CachedBodyWriter {
-        CachedBodyWriter {
-            body_file_path: self.get_body_file_path(),
+    /// Prepares the body and sets its actual `Content-Length`
+    fn load_body(
+        &self,
+        status: http::StatusCode,
+        headers: &mut http::HeaderMap,
+        recorded_encoding: Option<ContentEncoding>,
+        chunks: Vec<Chunk>,
+    ) -> Result<CachedBodyWriter> {
+        let body_file_path = self.get_body_file_path();
+        let origin_rewrite = self
+            .origin_rewrite
+            .as_ref()
+            .filter(|_| rewrite::is_rewritable_body(headers));
+
+        // Streams are sent chunked as they arrived, rewritten before being split
+        if !chunks.is_empty() {
+            headers.remove(http::header::CONTENT_LENGTH);
+
+            return Ok(CachedBodyWriter {
+                body_file_path,
+                body: None,
+                chunks,
+                timing_scale: self.config.timing_scale.unwrap_or(1.0),
+                origin_rewrite: origin_rewrite.cloned(),
+            });
+        }
+        let content_encoding = recorded_encoding
+            .and_then(|recorded| encoding::negotiate(self.accept_encoding.as_deref(), recorded));
+
+        let mut body: Option<Vec<u8>> = if origin_rewrite.is_some() || content_encoding.is_some() {
+            match std::fs::read(&body_file_path) {
+                Ok(body) => Some(body),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+                Err(error) => return Err(error.into()),
+            }
+        } else {
+            None
+        };
+
+        if let Some(origin_rewrite) = origin_rewrite {
+            body = body.map(|body| origin_rewrite.apply_to_body(body));
         }
+
+        if let Some(content_encoding) = content_encoding {
+            if let Some(raw_body) = &body {
+                body = Some(content_encoding.encode(raw_body)?);
+                headers.append(
+                    http::header::CONTENT_ENCODING,
+                    http::HeaderValue::from_static(content_encoding.name()),
+                );
+            }
+        }
+
+        if recorded_encoding.is_some() {
+            headers.append(
+                http::header::VARY,
+                http::HeaderValue::from_static("Accept-Encoding"),
+            );
+        }
+
+        // Responses to HEAD keep the stored length of the body they don't have
+        if self.method != "HEAD" && has_body(status) {
+            let content_length = match &body {
+                Some(body) => Some(body.len() as u64),
+                None => match std::fs::metadata(&body_file_path) {
+                    Ok(metadata) => Some(metadata.len()),
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => Some(0),
+                    Err(error) => return Err(error.into()),
+                },
+            };
+
+            if let Some(content_length) = content_length {
+                headers.insert(http::header::CONTENT_LENGTH, content_length.into());
+            }
+        }
+
+        Ok(CachedBodyWriter {
+            body_file_path,
+            body,
+            chunks: Vec::new(),
+            timing_scale: 1.0,
+            origin_rewrite: None,
+        })
     }
 
-    pub fn load(&self) -> Result<iron::Response> {
+    pub fn load(&self) -> Result<Response> {
         let storage_path = self.get_absolute_storage_path();
 
         if !storage_path.exists() {
-            trace!("Storage dir doesn't exist: {:?}", storage_path.to_string_lossy());
+            trace!(
+                "Storage dir doesn't exist: {:?}",
+                storage_path.to_string_lossy()
+            );
             return Err(Error::CacheMiss);
         } else {
             trace!("Storage dir exists: {:?}", storage_path.to_string_lossy());
         };
 
-        let mut response = iron::Response::with(iron::status::Ok);
-
-        response.status = match self.load_status() {
-            Ok(status) => Some(status),
+        let metadata = match self.load_metadata() {
+            Ok(metadata) => metadata,
             Err(StorageError::StatusFileNotFound) => {
-                trace!("Status file not found in cache");
+                trace!("Neither metadata nor status file found in cache");
                 return Err(Error::CacheMiss);
             }
+            Err(StorageError::UnsupportedVersion(version)) => {
+                return Err(Error::UnsupportedMetadataVersion(version))
+            }
             Err(StorageError::Common(common_error)) => return Err(common_error.into()),
         };
 
-        response.headers = self.load_headers()?;
-        response.body = Some(Box::new(self.load_body()));
+        if self.is_stale(metadata.age()) {
+            info!(
+                target: "storage",
+                "Recording is stale, recording again: {}",
+                self.recording()
+            );
+            return Err(Error::CacheMiss);
+        }
+
+        let status = to_status(metadata.status)?;
+        let mut response = Response::with_status(status);
+
+        response.headers = self.to_header_map(metadata.headers)?;
+        let recorded_encoding = metadata
+            .content_encoding
+            .as_deref()
+            .and_then(ContentEncoding::parse);
+        response.body = Some(Box::new(self.load_body(
+            status,
+            &mut response.headers,
+            recorded_encoding,
+            metadata.chunks,
+        )?));
 
         Ok(response)
     }
+
+    /// The recording of the request, found under the root dir
+    pub fn recording(&self) -> Recording {
+        self.recording_of(RecordingKind::Http)
+    }
+
+    /// Like [`recording`](#method.recording) for WebSocket sessions and gRPC calls
+    pub(crate) fn recording_of(&self, kind: RecordingKind) -> Recording {
+        Recording {
+            path: self
+                .storage_path_relative
+                .strip_prefix(self.config.get_root_dir())
+                .unwrap_or(&self.storage_path_relative)
+                .to_owned(),
+            method: self.method.clone(),
+            kind,
+        }
+    }
+
+    /// Makes miss reports see a recording saved other than by [`save`](#method.save)
+    pub(crate) fn invalidate_recordings_index(&self) {
+        nearby::invalidate_index(self.config.get_root_dir());
+    }
+
+    /// Explains a cache miss with the recordings closest to the request
+    pub fn miss_report(&self) -> Result<MissReport> {
+        self.miss_report_of(RecordingKind::Http)
+    }
+
+    /// Like [`miss_report`](#method.miss_report) with the recordings of the kind only
+    pub(crate) fn miss_report_of(&self, kind: RecordingKind) -> Result<MissReport> {
+        let recordings = nearby::find_indexed_recordings(self.config.get_root_dir())?;
+
+        Ok(MissReport {
+            storage_path: self.get_absolute_storage_path(),
+            method: self.method.clone(),
+            kind,
+            closest: nearby::find_closest(&recordings, &self.recording_of(kind)),
+            query_in_path: self.config.query_in_path.clone(),
+        })
+    }
+
+    /// Status of responses to requests without a recording, `None` if they are forwarded
+    pub fn strict_replay_status(&self) -> Option<u16> {
+        self.config.strict_replay_status
+    }
+
+    /// Whether a recording of the age should be recorded again, never in strict replay
+    pub(crate) fn is_stale(&self, age: Option<std::time::Duration>) -> bool {
+        self.config.strict_replay_status.is_none() && self.config.is_stale(age)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Converts recordings under the directory from the legacy status and headers files
+/// to metadata files, returns how many were converted
+pub fn migrate(dir: &Path) -> Result<usize> {
+    let mut migrated = 0;
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            migrated += migrate(&path)?;
+            continue;
+        }
+
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let method = match file_name.strip_suffix(STATUS_FILE_EXTENSION) {
+            Some(method) => method,
+            None => continue,
+        };
+
+        let metadata_file_path = dir.join(method.to_owned() + METADATA_FILE_EXTENSION);
+        if metadata_file_path.exists() {
+            warn!(target: "storage", "Both legacy and metadata files exist in: {}", dir.to_string_lossy());
+            continue;
+        }
+
+        let headers_file_path = dir.join(method.to_owned() + HEADERS_FILE_EXTENSION);
+        let headers = match File::open(&headers_file_path) {
+            Ok(file) => serde_yaml::from_reader(file)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        let metadata = ResponseMetadata {
+            recorded_at: None,
+            parody_version: None,
+            content_hash: match find_body_file(dir, method) {
+                Some(body_file_path) => metadata::hash_file(&body_file_path)?,
+                None => None,
+            },
+            ..ResponseMetadata::new(metadata::load_legacy_status(&path)?, headers)
+        };
+
+        metadata.save(&metadata_file_path)?;
+        remove_if_exists(&path)?;
+        remove_if_exists(&headers_file_path)?;
+
+        info!(target: "storage", "Migrated: {}", metadata_file_path.to_string_lossy());
+        migrated += 1;
+    }
+
+    Ok(migrated)
+}
+
+/// Extension of body files for the content type, so editors recognize them
+fn get_body_file_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    let extension = match mime.as_str() {
+        "application/json" => "json",
+        "text/html" | "application/xhtml+xml" => "html",
+        "application/xml" | "text/xml" => "xml",
+        "text/plain" => "txt",
+        "text/css" => "css",
+        "text/csv" => "csv",
+        "text/event-stream" => "sse",
+        "application/x-ndjson" | "application/ndjson" => "ndjson",
+        "application/jsonl" | "application/x-jsonlines" => "jsonl",
+        "text/javascript" | "application/javascript" => "js",
+        "application/yaml" | "application/x-yaml" | "text/yaml" => "yaml",
+        "application/pdf" => "pdf",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        mime if mime.ends_with("+json") => "json",
+        mime if mime.ends_with("+xml") => "xml",
+        _ => return None,
+    };
+
+    Some(extension)
+}
+
+/// Method of a body file name, e.g. `GET` for `GET.body` and `GET.body.json`
+fn get_body_file_method(file_name: &str) -> Option<&str> {
+    let (method, extension) = file_name.split_once(BODY_FILE_EXTENSION)?;
+
+    if method.is_empty() || method.contains('.') {
+        return None;
+    }
+
+    match extension.strip_prefix('.') {
+        Some(extension) if !extension.is_empty() && !extension.contains('.') => Some(method),
+        None if extension.is_empty() => Some(method),
+        _ => None,
+    }
+}
+
+/// Finds the body of the method in the directory, whatever its extension
+fn find_body_file(dir: &Path, method: &str) -> Option<PathBuf> {
+    let mut body_files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .map(|name| get_body_file_method(&name.to_string_lossy()) == Some(method))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    body_files.sort();
+    body_files.into_iter().next()
+}
+
+/// Comma-separated values of the header
+fn get_header(headers: &[(String, Vec<u8>)], name: &str) -> Option<String> {
+    let values: Vec<String> = headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+        .collect();
+
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+fn is_json_content_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    mime.eq_ignore_ascii_case("application/json") || mime.to_ascii_lowercase().ends_with("+json")
+}
+
+/// Whether responses with the status may have a body
+fn has_body(status: http::StatusCode) -> bool {
+    let code = status.as_u16();
+
+    !(100..200).contains(&code) && code != 204 && code != 304
+}
+
+fn to_status(code: u16) -> Result<http::StatusCode> {
+    Ok(http::StatusCode::from_u16(code).map_err(http::Error::from)?)
 }
 
 fn percent_encode_slash(input: &str) -> String {
@@ -240,6 +1069,16 @@ fn get_response_storage_dir<T: ParodyRequest>(req: &T, config: &Config) -> Resul
 
     let mut target_path = config.get_root_dir().to_path_buf();
 
+    if config.host_in_path {
+        // With the default port too, so plain and TLS requests to a host don't share recordings
+        if let Some(host) = url.host_str() {
+            target_path.push(match url.port_or_known_default() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_owned(),
+            });
+        }
+    }
+
     if let Some(segments) = url.path_segments() {
         for dir in segments {
             let decoded_str = percent_encoding::percent_decode_str(dir).decode_utf8()?;
@@ -256,6 +1095,11 @@ fn get_response_storage_dir<T: ParodyRequest>(req: &T, config: &Config) -> Resul
         target_path.push(QUERY_SEPARATOR);
         query.sort();
         for (argument, value) in query {
+            let value = if config.redaction.is_query_param_redacted(&argument) {
+                Cow::Borrowed(PLACEHOLDER)
+            } else {
+                value
+            };
             let dir_name = if !value.is_empty() {
                 format!("{}={}", argument.as_ref(), value.as_ref())
             } else {
@@ -0,0 +1,371 @@
+//! Recordings close to a request that has none
+
+use super::config::QueryInPath;
+use crate::result::Result;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+/// How many of the closest recordings are reported
+const MAX_CLOSEST: usize = 5;
+
+/// Recordings under each root dir, walked on the first miss and again after a recording is saved
+static INDEX: OnceLock<Mutex<HashMap<PathBuf, Arc<Vec<Recording>>>>> = OnceLock::new();
+/// Counts invalidations, so a walk overtaken by a save isn't indexed
+static INDEX_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// A recorded response found under the root dir
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Recording {
+    /// Directory of the recording relative to the root dir
+    pub path: PathBuf,
+    pub method: String,
+    pub kind: RecordingKind,
+}
+
+/// What a recording holds, each kind is kept in files of its own
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RecordingKind {
+    /// A response, in a metadata file or the legacy status and headers files, plus body files
+    #[default]
+    Http,
+    /// A WebSocket session transcript
+    WebSocket,
+    /// A unary gRPC call
+    Grpc,
+}
+
+/// How a recording close to the request differs from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Difference {
+    /// Same path, other query parameters
+    Query,
+    /// Same path and query, other method
+    Method,
+    /// One path segment added, removed or changed
+    Segment,
+}
+
+/// A recording close to the request
+#[derive(Debug, Clone, PartialEq)]
+pub struct NearMiss {
+    pub recording: Recording,
+    /// `None` if it differs in more than one way
+    pub difference: Option<Difference>,
+}
+
+/// Why a request has no recording and which ones come close
+#[derive(Debug, Clone)]
+pub struct MissReport {
+    /// Where the response would be stored
+    pub storage_path: PathBuf,
+    pub method: String,
+    pub kind: RecordingKind,
+    /// Near misses with a single difference first, then by path similarity
+    pub closest: Vec<NearMiss>,
+    pub query_in_path: QueryInPath,
+}
+
+/// A request which had no recording
+#[derive(Debug, Clone)]
+pub struct Miss {
+    /// Method and URL of the request
+    pub request: String,
+    pub report: MissReport,
+}
+
+impl MissReport {
+    /// Recordings differing from the request in a single way
+    pub fn near_misses(&self) -> impl Iterator<Item = &NearMiss> {
+        self.closest
+            .iter()
+            .filter(|near_miss| near_miss.difference.is_some())
+    }
+}
+
+impl RecordingKind {
+    const ALL: [RecordingKind; 3] = [
+        RecordingKind::Http,
+        RecordingKind::WebSocket,
+        RecordingKind::Grpc,
+    ];
+
+    /// Extension of the file naming the recording after its method
+    pub fn file_extension(self) -> &'static str {
+        match self {
+            RecordingKind::Http => super::METADATA_FILE_EXTENSION,
+            RecordingKind::WebSocket => super::WEBSOCKET_FILE_EXTENSION,
+            RecordingKind::Grpc => super::GRPC_FILE_EXTENSION,
+        }
+    }
+
+    /// Prefix of journal lines, HTTP recordings have none
+    fn name(self) -> Option<&'static str> {
+        match self {
+            RecordingKind::Http => None,
+            RecordingKind::WebSocket => Some("websocket"),
+            RecordingKind::Grpc => Some("grpc"),
+        }
+    }
+
+    /// The kind and method of a recording file, `None` for other files
+    fn of_file(name: &str) -> Option<(Self, &str)> {
+        let (kind, method) = [
+            (RecordingKind::Http, super::METADATA_FILE_EXTENSION),
+            (RecordingKind::Http, super::STATUS_FILE_EXTENSION),
+            (RecordingKind::WebSocket, super::WEBSOCKET_FILE_EXTENSION),
+            (RecordingKind::Grpc, super::GRPC_FILE_EXTENSION),
+        ]
+        .iter()
+        .find_map(|(kind, extension)| Some((*kind, name.strip_suffix(extension)?)))?;
+
+        Some((kind, method)).filter(|(_, method)| !method.is_empty() && !method.contains('.'))
+    }
+}
+
+impl std::fmt::Display for Recording {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(kind) = self.kind.name() {
+            write!(f, "{} ", kind)?;
+        }
+
+        write!(f, "{} {}", self.method, self.path.to_string_lossy())
+    }
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Difference::Query => write!(f, "different query"),
+            Difference::Method => write!(f, "different method"),
+            Difference::Segment => write!(f, "one segment off"),
+        }
+    }
+}
+
+impl std::fmt::Display for NearMiss {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.difference {
+            Some(difference) => write!(f, "{} ({})", self.recording, difference),
+            None => self.recording.fmt(f),
+        }
+    }
+}
+
+impl std::str::FromStr for Recording {
+    type Err = std::convert::Infallible;
+
+    /// Parses a journal line, e.g. `GET users/42` or `websocket GET chat`
+    fn from_str(line: &str) -> std::result::Result<Self, Self::Err> {
+        let (kind, line) = RecordingKind::ALL
+            .iter()
+            .find_map(|kind| {
+                let rest = line.strip_prefix(kind.name()?)?.strip_prefix(' ')?;
+                Some((*kind, rest))
+            })
+            .unwrap_or((RecordingKind::Http, line));
+        let (method, path) = line.split_once(' ').unwrap_or((line, ""));
+
+        Ok(Recording {
+            path: path.into(),
+            method: method.to_owned(),
+            kind,
+        })
+    }
+}
+
+impl std::fmt::Display for MissReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Storage path: {}",
+            self.storage_path
+                .join(self.method.clone() + self.kind.file_extension())
+                .to_string_lossy()
+        )?;
+        writeln!(f, "Query in path: {}", self.query_in_path)?;
+
+        if self.closest.is_empty() {
+            return writeln!(f, "No recordings found");
+        }
+
+        writeln!(f, "Closest recordings:")?;
+        for near_miss in &self.closest {
+            writeln!(f, "  {}", near_miss)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Recordings under the directory, sorted by path
+pub fn find_recordings(root_dir: &Path) -> Result<Vec<Recording>> {
+    let mut recordings = Vec::new();
+
+    if root_dir.is_dir() {
+        find_recordings_in(root_dir, Path::new(""), &mut recordings)?;
+    }
+
+    Ok(recordings)
+}
+
+/// Like [`find_recordings`](fn.find_recordings.html), walking the directory only when a recording was saved since
+///
+/// Recordings added by other processes aren't seen until this one saves one.
+pub fn find_indexed_recordings(root_dir: &Path) -> Result<Arc<Vec<Recording>>> {
+    let index = INDEX.get_or_init(Default::default);
+
+    if let Some(recordings) = index.lock().unwrap().get(root_dir) {
+        return Ok(recordings.clone());
+    }
+
+    // Walking happens unlocked, so saving recordings doesn't wait for it
+    let generation = INDEX_GENERATION.load(Ordering::SeqCst);
+    let recordings = Arc::new(find_recordings(root_dir)?);
+    let mut index = index.lock().unwrap();
+
+    if INDEX_GENERATION.load(Ordering::SeqCst) == generation {
+        index.insert(root_dir.to_owned(), recordings.clone());
+    }
+
+    Ok(recordings)
+}
+
+/// Makes the next miss walk the root dir again
+pub fn invalidate_index(root_dir: &Path) {
+    let mut index = INDEX.get_or_init(Default::default).lock().unwrap();
+
+    INDEX_GENERATION.fetch_add(1, Ordering::SeqCst);
+    index.remove(root_dir);
+}
+
+fn find_recordings_in(dir: &Path, relative: &Path, recordings: &mut Vec<Recording>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if entry.file_type()?.is_dir() {
+            find_recordings_in(&entry.path(), &relative.join(&name), recordings)?;
+            continue;
+        }
+
+        if let Some((kind, method)) = RecordingKind::of_file(&name) {
+            let recording = Recording {
+                path: relative.to_owned(),
+                method: method.to_owned(),
+                kind,
+            };
+
+            // Recordings being migrated have both files
+            if !recordings.contains(&recording) {
+                recordings.push(recording);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The recordings of the same kind closest to the target, closest first
+///
+/// Recordings differing in a single way come first: the same path with
+/// another query, another method, then one path segment off. The rest
+/// are compared segment by segment, so related ones come before unrelated.
+pub fn find_closest(recordings: &[Recording], target: &Recording) -> Vec<NearMiss> {
+    let target_segments = get_segments(&target.path, &target.method);
+    let mut ranked: Vec<(Option<Difference>, usize, usize, &Recording)> = recordings
+        .iter()
+        // The request's own recording shows up once it's saved
+        .filter(|recording| recording.kind == target.kind && *recording != target)
+        .map(|recording| {
+            let segments = get_segments(&recording.path, &recording.method);
+            let difference = get_difference(&target_segments, &segments);
+            let distance = edit_distance(&target_segments, &segments);
+            let text_distance = edit_distance(
+                &target_segments.join("/").chars().collect::<Vec<_>>(),
+                &segments.join("/").chars().collect::<Vec<_>>(),
+            );
+
+            (difference, distance, text_distance, recording)
+        })
+        .collect();
+
+    // `None` sorts first, while near misses should
+    ranked.sort_by_key(|(difference, distance, text_distance, _)| {
+        (difference.is_none(), *difference, *distance, *text_distance)
+    });
+    ranked
+        .into_iter()
+        .take(MAX_CLOSEST)
+        .map(|(difference, _, _, recording)| NearMiss {
+            recording: recording.clone(),
+            difference,
+        })
+        .collect()
+}
+
+/// The single way the recording differs from the request, if it's the only one
+fn get_difference(target: &[String], recording: &[String]) -> Option<Difference> {
+    let (target_path, target_query, target_method) = split_segments(target);
+    let (path, query, method) = split_segments(recording);
+
+    if target_path == path && target_method == method && target_query != query {
+        return Some(Difference::Query);
+    }
+
+    if target_path == path && target_query == query && target_method != method {
+        return Some(Difference::Method);
+    }
+
+    if target_query == query && target_method == method && edit_distance(target_path, path) == 1 {
+        return Some(Difference::Segment);
+    }
+
+    None
+}
+
+/// Path segments, query segments and the method
+fn split_segments(segments: &[String]) -> (&[String], &[String], &String) {
+    let (method, segments) = segments
+        .split_last()
+        .expect("Segments should always end with a method");
+    let query_start = segments
+        .iter()
+        .position(|segment| segment == super::QUERY_SEPARATOR)
+        .unwrap_or(segments.len());
+
+    (&segments[..query_start], &segments[query_start..], method)
+}
+
+/// Path segments followed by the method, which counts as one more segment
+fn get_segments(path: &Path, method: &str) -> Vec<String> {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .chain(std::iter::once(method.to_owned()))
+        .collect()
+}
+
+/// Levenshtein distance between the sequences
+fn edit_distance<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_item) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+
+        for (j, b_item) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_item != b_item);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
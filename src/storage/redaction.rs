@@ -0,0 +1,272 @@
+//! Removal of secrets from recordings before they are written
+
+use super::{header_value::HeaderValue, json, metadata::ResponseMetadata};
+use crate::result::Result;
+use std::path::{Path, PathBuf};
+
+/// Replaces every redacted value, stable so recordings don't change between runs
+pub const PLACEHOLDER: &str = "PARODY-REDACTED";
+/// A regex group to redact instead of the whole match
+const SECRET_GROUP: &str = "secret";
+
+/// Rules of what to redact
+///
+/// JSON paths are dot-separated keys (e.g. `user.token`), `*` matches
+/// any array element or object member and an optional `$.` prefix is ignored.
+/// Patterns replace the whole match or only the `secret` named group if present.
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    /// Lowercase names of headers with secret values
+    pub headers: Vec<String>,
+    pub query_params: Vec<String>,
+    pub json_paths: Vec<String>,
+    pub patterns: Vec<regex::Regex>,
+}
+
+/// An unredacted secret found in recordings
+#[derive(Debug)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub rule: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.to_string_lossy(), self.rule)
+    }
+}
+
+impl Redaction {
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+            && self.query_params.is_empty()
+            && self.json_paths.is_empty()
+            && self.patterns.is_empty()
+    }
+
+    pub fn is_header_redacted(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name))
+    }
+
+    pub fn is_query_param_redacted(&self, name: &str) -> bool {
+        self.query_params.iter().any(|param| param == name)
+    }
+
+    /// Whether bodies have to be inspected at all
+    pub fn applies_to_body(&self) -> bool {
+        !self.json_paths.is_empty() || !self.patterns.is_empty()
+    }
+
+    pub fn redact_text(&self, mut text: String) -> String {
+        for pattern in &self.patterns {
+            text = redact_pattern(pattern, &text);
+        }
+
+        text
+    }
+
+    /// The URL with the password, redacted query parameters and patterns replaced
+    pub fn redact_url(&self, url: &url::Url) -> String {
+        let mut url = url.clone();
+
+        if url.password().is_some() {
+            let _ = url.set_password(Some(PLACEHOLDER));
+        }
+
+        if url
+            .query_pairs()
+            .any(|(name, _)| self.is_query_param_redacted(&name))
+        {
+            let query: Vec<(String, String)> = url
+                .query_pairs()
+                .map(|(name, value)| match self.is_query_param_redacted(&name) {
+                    true => (name.into_owned(), PLACEHOLDER.to_owned()),
+                    false => (name.into_owned(), value.into_owned()),
+                })
+                .collect();
+            url.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        self.redact_text(url.into())
+    }
+
+    /// Redacted body or `None` if nothing was redacted
+    pub fn redact_body(&self, body: &[u8]) -> Option<Vec<u8>> {
+        let text = std::str::from_utf8(body).ok()?;
+        let mut redacted = text.to_owned();
+
+        if !self.json_paths.is_empty() {
+            if let Ok(mut value) = serde_json::from_str::<serde_json::Value>(text) {
+                let mut is_redacted = false;
+
+                for path in &self.json_paths {
+                    is_redacted |=
+                        json::replace_path(&mut value, &json::split_path(path), PLACEHOLDER);
+                }
+
+                if is_redacted {
+                    redacted = value.to_string();
+                }
+            }
+        }
+
+        redacted = self.redact_text(redacted);
+
+        if redacted == text {
+            None
+        } else {
+            Some(redacted.into_bytes())
+        }
+    }
+
+    /// Rules the header value violates
+    fn check_header(&self, name: &str, value: &str) -> Vec<String> {
+        let mut rules = Vec::new();
+
+        if self.is_header_redacted(name) && value != PLACEHOLDER {
+            rules.push(format!("header {}", name));
+        }
+
+        rules.extend(self.check_text(value));
+        rules
+    }
+
+    fn check_url(&self, url: &str) -> Vec<String> {
+        let mut rules: Vec<String> = url::Url::parse(url)
+            .map(|url| {
+                url.query_pairs()
+                    .filter(|(name, value)| {
+                        self.is_query_param_redacted(name) && value != PLACEHOLDER
+                    })
+                    .map(|(name, _)| format!("query parameter {}", name))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        rules.extend(self.check_text(url));
+        rules
+    }
+
+    fn check_text(&self, text: &str) -> Vec<String> {
+        self.patterns
+            .iter()
+            .filter(|pattern| redact_pattern(pattern, text) != text)
+            .map(|pattern| format!("pattern {}", pattern.as_str()))
+            .collect()
+    }
+
+    fn check_body(&self, body: &[u8]) -> Vec<String> {
+        let text = match std::str::from_utf8(body) {
+            Ok(text) => text,
+            Err(_) => return Vec::new(),
+        };
+        let mut rules = Vec::new();
+
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(text) {
+            for path in &self.json_paths {
+                if has_unredacted_json_path(&value, &json::split_path(path)) {
+                    rules.push(format!("JSON path {}", path));
+                }
+            }
+        }
+
+        rules.extend(self.check_text(text));
+        rules
+    }
+
+    /// Walks recordings under the directory looking for values the rules would redact
+    pub fn find_unredacted(&self, dir: &Path) -> Result<Vec<Finding>> {
+        let mut findings = Vec::new();
+        self.find_unredacted_in(dir, &mut findings)?;
+        Ok(findings)
+    }
+
+    fn find_unredacted_in(&self, dir: &Path, findings: &mut Vec<Finding>) -> Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let mut rules: Vec<String> = Vec::new();
+
+            if entry.file_type()?.is_dir() {
+                if let Some((param, value)) = name.split_once('=') {
+                    if self.is_query_param_redacted(param) && value != PLACEHOLDER {
+                        findings.push(Finding {
+                            path: path.clone(),
+                            rule: format!("query parameter {}", param),
+                        });
+                    }
+                }
+
+                self.find_unredacted_in(&path, findings)?;
+            } else if name.ends_with(super::HEADERS_FILE_EXTENSION)
+                || name.ends_with(super::METADATA_FILE_EXTENSION)
+            {
+                let headers: Vec<(String, HeaderValue)> =
+                    if name.ends_with(super::METADATA_FILE_EXTENSION) {
+                        let metadata = ResponseMetadata::load(&path)?;
+
+                        if let Some(upstream_url) = &metadata.upstream_url {
+                            rules.extend(self.check_url(upstream_url));
+                        }
+                        metadata.headers
+                    } else {
+                        serde_yaml::from_reader(std::fs::File::open(&path)?)?
+                    };
+
+                for (name, value) in headers {
+                    // Binary values can't match text rules, but a redacted header must be a placeholder
+                    rules.extend(self.check_header(&name, value.as_text().unwrap_or_default()));
+                }
+            } else if name.ends_with(super::WEBSOCKET_FILE_EXTENSION) {
+                let transcript = crate::websocket::transcript::Transcript::load(&path)?;
+
+                for message in &transcript.messages {
+                    rules.extend(self.check_body(&message.payload.content().unwrap_or_default()));
+                }
+                rules.sort();
+                rules.dedup();
+            } else if super::get_body_file_method(&name).is_some() {
+                rules.extend(self.check_body(&std::fs::read(&path)?));
+            }
+
+            findings.extend(rules.into_iter().map(|rule| Finding {
+                path: path.clone(),
+                rule,
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+fn redact_pattern(pattern: &regex::Regex, text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut last_end = 0;
+
+    for secret in find_secrets(pattern, text) {
+        redacted.push_str(&text[last_end..secret.start]);
+        redacted.push_str(PLACEHOLDER);
+        last_end = secret.end;
+    }
+
+    redacted.push_str(&text[last_end..]);
+    redacted
+}
+
+/// Ranges of the text the pattern redacts, sorted
+pub fn find_secrets(pattern: &regex::Regex, text: &str) -> Vec<std::ops::Range<usize>> {
+    pattern
+        .captures_iter(text)
+        .filter_map(|captures| captures.name(SECRET_GROUP).or_else(|| captures.get(0)))
+        .map(|secret| secret.range())
+        .collect()
+}
+
+fn has_unredacted_json_path(value: &serde_json::Value, path: &[&str]) -> bool {
+    json::replace_path(&mut value.clone(), path, PLACEHOLDER)
+}
@@ -0,0 +1,71 @@
+//! Replay-time replacement of the upstream origin with Parody's origin
+
+/// Replaces one origin with another in headers and textual bodies
+#[derive(Debug, Clone)]
+pub struct OriginRewrite {
+    from: String,
+    to: String,
+}
+
+impl OriginRewrite {
+    pub fn new(from: &url::Url, to: &url::Url) -> Option<Self> {
+        let from = from.origin().ascii_serialization();
+        let to = to.origin().ascii_serialization();
+
+        if from == to || from == "null" || to == "null" {
+            return None;
+        }
+
+        Some(Self { from, to })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        self.replacements()
+            .iter()
+            .fold(text.to_owned(), |text, (from, to)| text.replace(from, to))
+    }
+
+    /// What to replace with what, in order
+    pub fn replacements(&self) -> [(String, String); 2] {
+        // JSON encoders may escape slashes, links should be rewritten in both forms
+        [
+            (self.from.clone(), self.to.clone()),
+            (self.from.replace('/', "\\/"), self.to.replace('/', "\\/")),
+        ]
+    }
+
+    /// Rewrites the body if it's an uncompressed UTF-8 text, otherwise returns it as is
+    pub fn apply_to_body(&self, body: Vec<u8>) -> Vec<u8> {
+        match String::from_utf8(body) {
+            Ok(text) => self.apply(&text).into_bytes(),
+            Err(error) => error.into_bytes(),
+        }
+    }
+}
+
+/// Whether a body with such headers is text we can rewrite
+pub fn is_rewritable_body(headers: &http::HeaderMap) -> bool {
+    let is_encoded = headers
+        .get(http::header::CONTENT_ENCODING)
+        .map(|value| !value.as_bytes().eq_ignore_ascii_case(b"identity"))
+        .unwrap_or(false);
+
+    if is_encoded {
+        return false;
+    }
+
+    let content_type = match headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) => content_type.to_ascii_lowercase(),
+        None => return false,
+    };
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    mime.starts_with("text/")
+        || mime.ends_with("json")
+        || mime.ends_with("xml")
+        || mime.ends_with("javascript")
+        || mime == "application/x-www-form-urlencoded"
+}
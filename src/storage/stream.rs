@@ -0,0 +1,228 @@
+//! Recording and replay of streamed bodies, e.g. Server-Sent Events
+
+use super::{
+    redaction::{self, Redaction},
+    rewrite::OriginRewrite,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    io::{Cursor, Read, Write},
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+/// Size of reads from the upstream, a chunk larger than this is recorded as several
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Content types of bodies consumed incrementally
+const STREAMING_CONTENT_TYPES: &[&str] = &[
+    "text/event-stream",
+    "application/x-ndjson",
+    "application/ndjson",
+    "application/jsonl",
+    "application/x-jsonlines",
+    "application/stream+json",
+];
+
+/// A piece of a streamed body as it arrived from the upstream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    /// Size of the stored chunk, it may differ from the received one if redacted
+    pub size: usize,
+    /// Time since the previous chunk or since the body started
+    pub delay_ms: u64,
+}
+
+pub fn is_streaming_content_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    STREAMING_CONTENT_TYPES
+        .iter()
+        .any(|streaming| mime.eq_ignore_ascii_case(streaming))
+}
+
+/// Copies the body as it arrives, returns its chunks and whether redaction changed it
+///
+/// With redaction patterns the body is held until it ends and redacted whole,
+/// so secrets split between chunks are found. JSON paths don't apply,
+/// a stream is rarely a single document.
+pub fn copy_chunks(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    redaction: &Redaction,
+) -> std::io::Result<(Vec<Chunk>, bool)> {
+    let is_redacted = !redaction.patterns.is_empty();
+    let mut chunks = Vec::new();
+    let mut held = Vec::new();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    let mut last_chunk_at = Instant::now();
+
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        let received_at = Instant::now();
+
+        match is_redacted {
+            true => held.extend_from_slice(&buffer[..read]),
+            false => writer.write_all(&buffer[..read])?,
+        }
+        chunks.push(Chunk {
+            size: read,
+            delay_ms: (received_at - last_chunk_at).as_millis() as u64,
+        });
+        last_chunk_at = received_at;
+    }
+
+    if !is_redacted {
+        return Ok((chunks, false));
+    }
+
+    let mut is_changed = false;
+    let body = match ChunkedText::new(held, &chunks) {
+        Ok(mut text) => {
+            for pattern in &redaction.patterns {
+                let secrets = redaction::find_secrets(pattern, &text.text);
+                is_changed |= !secrets.is_empty();
+                text.replace(&secrets, redaction::PLACEHOLDER);
+            }
+            text.resize(&mut chunks);
+            text.text.into_bytes()
+        }
+        // Patterns only match text
+        Err(body) => body,
+    };
+
+    writer.write_all(&body)?;
+    Ok((chunks, is_changed))
+}
+
+/// Writes the body split and delayed as recorded, delays are multiplied by the scale
+///
+/// Bytes beyond the recorded chunks, e.g. added by editing the body, are written at the end.
+/// The origin is rewritten in the whole body, so one split between chunks is found.
+pub fn write_chunks(
+    body: &mut dyn Read,
+    chunks: &[Chunk],
+    timing_scale: f64,
+    origin_rewrite: Option<&OriginRewrite>,
+    res: &mut dyn Write,
+) -> std::io::Result<()> {
+    let mut chunks = Cow::Borrowed(chunks);
+    let mut rewritten: Option<Cursor<Vec<u8>>> = None;
+
+    if let Some(origin_rewrite) = origin_rewrite {
+        let mut data = Vec::new();
+        body.read_to_end(&mut data)?;
+
+        rewritten = Some(Cursor::new(match ChunkedText::new(data, &chunks) {
+            Ok(mut text) => {
+                for (from, to) in origin_rewrite.replacements() {
+                    let ranges: Vec<_> = text
+                        .text
+                        .match_indices(&from)
+                        .map(|(start, _)| start..start + from.len())
+                        .collect();
+                    text.replace(&ranges, &to);
+                }
+                text.resize(chunks.to_mut());
+                text.text.into_bytes()
+            }
+            Err(data) => data,
+        }));
+    }
+
+    let body: &mut dyn Read = match &mut rewritten {
+        Some(rewritten) => rewritten,
+        None => body,
+    };
+
+    for chunk in chunks.iter() {
+        std::thread::sleep(Duration::from_millis(chunk.delay_ms).mul_f64(timing_scale.max(0.0)));
+
+        let mut data = Vec::with_capacity(chunk.size);
+        body.take(chunk.size as u64).read_to_end(&mut data)?;
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        res.write_all(&data)?;
+        // Clients should receive each chunk when it's due, not when the buffer fills up
+        res.flush()?;
+    }
+
+    std::io::copy(body, res)?;
+    Ok(())
+}
+
+/// A streamed body as text, with the ends of its chunks
+///
+/// Replacing parts of it moves the ends, so each chunk keeps its part of the text.
+struct ChunkedText {
+    text: String,
+    ends: Vec<usize>,
+}
+
+impl ChunkedText {
+    /// The body back if it's not UTF-8
+    fn new(body: Vec<u8>, chunks: &[Chunk]) -> std::result::Result<Self, Vec<u8>> {
+        let text = String::from_utf8(body).map_err(|error| error.into_bytes())?;
+        let mut end = 0;
+        let ends = chunks
+            .iter()
+            .map(|chunk| {
+                end += chunk.size;
+                end.min(text.len())
+            })
+            .collect();
+
+        Ok(Self { text, ends })
+    }
+
+    /// Replaces sorted, non-overlapping ranges of the text
+    ///
+    /// A chunk ending inside a range ends after its replacement instead.
+    fn replace(&mut self, ranges: &[Range<usize>], replacement: &str) {
+        if ranges.is_empty() {
+            return;
+        }
+
+        let mut replaced = String::with_capacity(self.text.len());
+        let mut last_end = 0;
+
+        for range in ranges {
+            replaced.push_str(&self.text[last_end..range.start]);
+            replaced.push_str(replacement);
+            last_end = range.end;
+        }
+        replaced.push_str(&self.text[last_end..]);
+
+        for end in self.ends.iter_mut() {
+            let old_end = ranges
+                .iter()
+                .find(|range| range.start < *end && *end < range.end)
+                .map_or(*end, |range| range.end);
+            let replaced_before = ranges.iter().filter(|range| range.end <= old_end);
+
+            *end =
+                replaced_before.fold(old_end, |end, range| end - range.len() + replacement.len());
+        }
+
+        self.text = replaced;
+    }
+
+    /// Sets sizes of the chunks to their parts of the text
+    fn resize(&self, chunks: &mut [Chunk]) {
+        let mut start = 0;
+
+        for (chunk, end) in chunks.iter_mut().zip(&self.ends) {
+            chunk.size = end - start;
+            start = *end;
+        }
+    }
+}
@@ -12,12 +12,14 @@ use config::Config;
 use regex::Regex;
 use std::{io::Read, path::Path};
 
-const REQUEST_REGEX: &'static str = r"^(?:(?P<method>[A-Z]+) )?(?P<url>.*)$";
-const DEFAULT_METHOD: &'static str = "GET";
+const REQUEST_REGEX: &str = r"^(?:(?P<method>[A-Z]+) )?(?P<url>.*)$";
+const DEFAULT_METHOD: &str = "GET";
 
 impl ParodyRequest for &str {
     fn get_url(&self) -> url::Url {
-        url::Url::from_str(*self).unwrap()
+        let captures = Regex::new(REQUEST_REGEX).unwrap().captures(self).unwrap();
+
+        url::Url::from_str(&captures["url"]).unwrap()
     }
 
     fn get_method(&self) -> String {
@@ -54,7 +56,45 @@ where
     }
 }
 
-header! { (XTestData, "X-Test-Data") => [String] }
+/// Raw values of the header, like the server sends them
+fn get_raw(headers: &http::HeaderMap, name: &str) -> Option<Vec<Vec<u8>>> {
+    let values: Vec<Vec<u8>> = headers
+        .get_all(name)
+        .iter()
+        .map(|value| value.as_bytes().to_vec())
+        .collect();
+
+    if values.is_empty() {
+        None
+    } else {
+        Some(values)
+    }
+}
+
+fn get_content_length(headers: &http::HeaderMap) -> Option<u64> {
+    headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok())
+}
+
+/// Text headers from the metadata file of a saved response
+fn get_saved_headers(storage: &DirectoryStorage) -> Vec<(String, String)> {
+    ResponseMetadata::load(&storage.get_metadata_file_path())
+        .expect("Cannot load metadata")
+        .headers
+        .into_iter()
+        .map(|(name, value)| {
+            (
+                name,
+                value
+                    .as_text()
+                    .expect("Saved header should be a text")
+                    .to_owned(),
+            )
+        })
+        .collect()
+}
 
 #[test]
 fn test_load_should_return_exactly_same_result_as_was_saved() {
@@ -68,7 +108,7 @@ fn test_load_should_return_exactly_same_result_as_was_saved() {
 
     storage
         .save(&mut (
-            500 as u16,
+            500,
             &[
                 ("Content-Type", "application/json"),
                 ("X-Test-Data", "1234567890"),
@@ -78,10 +118,14 @@ fn test_load_should_return_exactly_same_result_as_was_saved() {
         .expect("Cannot save request to storage");
 
     let response = storage.load().expect("Cannot load response");
-    let mut expected_headers = iron::Headers::new();
+    let mut expected_headers = http::HeaderMap::new();
 
-    expected_headers.set(iron::headers::ContentType::json());
-    expected_headers.set(XTestData("1234567890".to_owned()));
+    expected_headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    expected_headers.insert("x-test-data", http::HeaderValue::from_static("1234567890"));
+    expected_headers.insert(http::header::CONTENT_LENGTH, 18.into());
     assert_eq!(response.headers, expected_headers);
 
     let mut write_body = response.body.expect("Response should have a write body");
@@ -94,10 +138,7 @@ fn test_load_should_return_exactly_same_result_as_was_saved() {
 
     assert_eq!(*body_cursor.get_ref(), b"{\"lorem\": \"ipsum\"}".to_vec());
 
-    assert_eq!(
-        response.status.expect("Response does not have status"),
-        iron::status::InternalServerError
-    );
+    assert_eq!(response.status, http::StatusCode::INTERNAL_SERVER_ERROR);
 }
 
 #[test]
@@ -112,8 +153,12 @@ fn test_load_when_response_cached_should_return_response() {
 
     let response = storage.load().unwrap();
 
-    let mut expected_headers = iron::Headers::new();
-    expected_headers.set(iron::headers::ContentType::json());
+    let mut expected_headers = http::HeaderMap::new();
+    expected_headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    expected_headers.insert(http::header::CONTENT_LENGTH, 18.into());
     assert_eq!(response.headers, expected_headers);
 
     let mut write_body = response.body.expect("Response should have a write body");
@@ -137,7 +182,8 @@ fn test_load_when_response_not_cached_should_return_cache_miss() {
     )
     .unwrap()
     .load()
-    .expect_err("Load didn't exit with an error");
+    .err()
+    .expect("Load didn't exit with an error");
 
     match error {
         Error::CacheMiss => {}
@@ -193,11 +239,11 @@ fn test_storage_new_should_create_storage_for_request() {
 //         .expect("Canot save status file");
 
 //     let response = storage.load().expect("Cannot load a response");
-//     assert_eq!(response.status.unwrap(), iron::status::MethodNotAllowed);
+//     assert_eq!(response.status, http::StatusCode::METHOD_NOT_ALLOWED);
 // }
 
 #[test]
-fn test_save_should_save_response_status_in_status_file() {
+fn test_save_should_save_response_status_in_metadata_file() {
     let storage_root = tempfile::tempdir().unwrap();
 
     let storage = DirectoryStorage::new_with_config(
@@ -208,22 +254,31 @@ fn test_save_should_save_response_status_in_status_file() {
 
     storage
         .save(&mut (
-            403 as u16,
+            403,
             &[],
             Cursor::new("Lorem ipsum dolor sit amet".as_bytes()),
         ))
         .expect("Cannot save into storage");
 
-    let status_path = storage_root.path().join("example.com/some-path/:PARODY-QUERY/query=value/GET.status");
-
-    let mut status_file = File::open(&status_path).expect("Cannot open status file");
+    let metadata_path = storage_root
+        .path()
+        .join("example.com/some-path/:PARODY-QUERY/query=value/GET.meta.yaml");
 
-    let mut status: Vec<u8> = Vec::new();
-    status_file
-        .read_to_end(&mut status)
-        .expect("Cannot read status");
+    let metadata = ResponseMetadata::load(&metadata_path).expect("Cannot load metadata");
 
-    assert_eq!(status, b"403\n".to_vec());
+    assert_eq!(metadata.version, metadata::CURRENT_VERSION);
+    assert_eq!(metadata.status, 403);
+    assert_eq!(
+        metadata.content_hash,
+        Some("sha256:16aba5393ad72c0041f5600ad3c2c52ec437a2f0c7fc08fadfc3c0fe9641d7a3".to_owned())
+    );
+    assert_eq!(
+        metadata.parody_version,
+        Some(env!("CARGO_PKG_VERSION").to_owned())
+    );
+    assert!(metadata.recorded_at.is_some());
+    assert!(!storage.get_status_file_path().exists());
+    assert!(!storage.get_headers_file_path().exists());
 }
 
 #[test]
@@ -240,7 +295,7 @@ fn test_save_should_save_response_body_in_body_file() {
 
     storage
         .save(&mut (
-            200 as u16,
+            200,
             &[],
             Cursor::new("Lorem ipsum dolor sit amet".as_bytes()),
         ))
@@ -250,10 +305,8 @@ fn test_save_should_save_response_body_in_body_file() {
         .path()
         .join("example.com/some-path/:PARODY-QUERY/query=value/GET.body");
 
-    let mut body_file = std::fs::File::open(&headers_path).expect(&format!(
-        "Cannot open file at: {}",
-        headers_path.to_str().unwrap()
-    ));
+    let mut body_file = std::fs::File::open(&headers_path)
+        .unwrap_or_else(|_| panic!("Cannot open file at: {}", headers_path.to_str().unwrap()));
 
     let mut body: Vec<u8> = Vec::new();
     body_file
@@ -264,7 +317,7 @@ fn test_save_should_save_response_body_in_body_file() {
 }
 
 #[test]
-fn test_save_should_save_response_headers_in_headers_file() {
+fn test_save_should_save_response_headers_in_metadata_file() {
     let storage_root = tempfile::tempdir().unwrap();
 
     let storage = DirectoryStorage::new_with_config(
@@ -275,22 +328,10 @@ fn test_save_should_save_response_headers_in_headers_file() {
     )
     .expect("Cannot create new storage with config");
     storage
-        .save(&mut (200 as u16, &[("Authorization", "Bearer")], Cursor::new(&[])))
+        .save(&mut (200, &[("Authorization", "Bearer")], Cursor::new(&[])))
         .expect("Cannot save request to storage");
 
-    let headers_path = storage_root
-        .path()
-        .join("example.com/some-path/:PARODY-QUERY/query=value/GET.headers.yaml");
-
-    let headers_file = std::fs::File::open(&headers_path).expect(&format!(
-        "Cannot open file at: {}",
-        headers_path.to_str().unwrap()
-    ));
-
-    let headers: Vec<(String, String)> = serde_yaml::from_reader(headers_file).expect(&format!(
-        "Cannot read yaml file at: {}",
-        headers_path.to_str().unwrap()
-    ));
+    let headers = get_saved_headers(&storage);
 
     assert_eq!(
         headers,
@@ -330,7 +371,7 @@ fn test_get_response_storage_dir_when_request_has_query_should_include_query_fro
     assert_eq!(
         get_response_storage_dir(
             &"https://example.com/test string/unicode-α?query&query=&query-arg=value",
-            &Config::default().use_query_path("query")
+            Config::default().use_query_path("query")
         )
         .unwrap(),
         PathBuf::from_str("test string/unicode-α/:PARODY-QUERY/query/query").unwrap()
@@ -357,3 +398,1390 @@ fn test_get_response_storage_dir_all_features() {
             .unwrap()
     );
 }
+
+#[test]
+fn test_get_response_storage_dir_when_host_in_path_should_start_with_host() {
+    assert_eq!(
+        get_response_storage_dir(
+            &"http://example.com:8080/some-path?query=value",
+            &Config::default().with_host_path().with_no_query_path()
+        )
+        .unwrap(),
+        PathBuf::from_str("example.com:8080/some-path").unwrap()
+    );
+}
+
+#[test]
+fn test_get_response_storage_dir_when_host_in_path_should_include_default_port() {
+    let config = Config::default().with_host_path().with_no_query_path();
+
+    assert_eq!(
+        get_response_storage_dir(&"http://example.com/some-path", &config).unwrap(),
+        PathBuf::from_str("example.com:80/some-path").unwrap()
+    );
+    assert_eq!(
+        get_response_storage_dir(&"https://example.com/some-path", &config).unwrap(),
+        PathBuf::from_str("example.com:443/some-path").unwrap()
+    );
+}
+
+#[test]
+fn test_load_with_origin_rewrite_should_replace_upstream_origin_in_headers_and_body() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let body = "{\"next\": \"https://example.com/items?page=2\", \"self\": \"https:\\/\\/example.com\\/items\"}";
+
+    let storage = DirectoryStorage::new_with_config(
+        &"http://localhost:1234/items",
+        Config::default()
+            .with_root_dir(storage_path.path().to_owned())
+            .with_origin_rewrite(url::Url::from_str("https://example.com/").unwrap()),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[
+                ("Content-Type", "application/json"),
+                ("Content-Length", "82"),
+                ("Link", "<https://example.com/items?page=2>; rel=\"next\""),
+            ],
+            Cursor::new(body.as_bytes()),
+        ))
+        .expect("Cannot save request to storage");
+
+    let response = storage.load().expect("Cannot load response");
+
+    assert_eq!(
+        get_raw(&response.headers, "link"),
+        Some(vec![
+            b"<http://localhost:1234/items?page=2>; rel=\"next\"".to_vec()
+        ])
+    );
+
+    let mut body_cursor = Cursor::new(Vec::<u8>::new());
+    response
+        .body
+        .expect("Response should have a write body")
+        .write_body(&mut body_cursor)
+        .expect("Cannot write body to a cursor");
+
+    assert_eq!(
+        get_content_length(&response.headers),
+        Some(body_cursor.get_ref().len() as u64)
+    );
+    assert_eq!(
+        String::from_utf8(body_cursor.into_inner()).unwrap(),
+        "{\"next\": \"http://localhost:1234/items?page=2\", \"self\": \"http:\\/\\/localhost:1234\\/items\"}"
+    );
+    assert_eq!(
+        std::fs::read_to_string(storage.get_body_file_path()).unwrap(),
+        body
+    );
+}
+
+#[test]
+fn test_load_with_origin_rewrite_should_keep_binary_body() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+
+    let storage = DirectoryStorage::new_with_config(
+        &"http://localhost:1234/image",
+        Config::default()
+            .with_root_dir(storage_path.path().to_owned())
+            .with_origin_rewrite(url::Url::from_str("https://example.com/").unwrap()),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[("Content-Type", "image/png"), ("Content-Length", "19")],
+            Cursor::new("https://example.com".as_bytes()),
+        ))
+        .expect("Cannot save request to storage");
+
+    let response = storage.load().expect("Cannot load response");
+
+    assert_eq!(
+        get_raw(&response.headers, "content-length"),
+        Some(vec![b"19".to_vec()])
+    );
+
+    let mut body_cursor = Cursor::new(Vec::<u8>::new());
+    response
+        .body
+        .expect("Response should have a write body")
+        .write_body(&mut body_cursor)
+        .expect("Cannot write body to a cursor");
+
+    assert_eq!(body_cursor.into_inner(), b"https://example.com".to_vec());
+}
+
+fn get_redacting_config(root_dir: &Path) -> Config {
+    Config::default()
+        .with_root_dir(root_dir.to_owned())
+        .with_redacted_header("Set-Cookie")
+        .with_redacted_query_param("api_key")
+        .with_redacted_json_path("$.user.token")
+        .with_redacted_json_path("sessions.*.id")
+        .with_redaction_pattern(Regex::new("Bearer (?P<secret>[a-z0-9]+)").unwrap())
+}
+
+#[test]
+fn test_save_with_redaction_should_replace_secrets_with_placeholder() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = get_redacting_config(storage_path.path());
+
+    let storage =
+        DirectoryStorage::new_with_config(&"https://example.com/me?api_key=123&page=1", config)
+            .unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[
+                ("Content-Type", "application/json"),
+                ("Content-Length", "94"),
+                ("Set-Cookie", "session=abc"),
+                ("X-Echo", "Bearer abc123"),
+            ],
+            Cursor::new(
+                "{\"user\": {\"name\": \"lorem\", \"token\": \"abc\"}, \"sessions\": [{\"id\": 1}, {\"id\": 2}]}"
+                    .as_bytes(),
+            ),
+        ))
+        .expect("Cannot save request to storage");
+
+    assert!(storage
+        .get_absolute_storage_path()
+        .ends_with("me/:PARODY-QUERY/api_key=PARODY-REDACTED/page=1"));
+
+    let headers = get_saved_headers(&storage);
+    assert_eq!(
+        headers,
+        vec![
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+            ("Set-Cookie".to_owned(), PLACEHOLDER.to_owned()),
+            ("X-Echo".to_owned(), "Bearer PARODY-REDACTED".to_owned()),
+        ]
+    );
+
+    let body: serde_json::Value =
+        serde_json::from_reader(File::open(storage.get_body_file_path()).unwrap()).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "user": {"name": "lorem", "token": PLACEHOLDER},
+            "sessions": [{"id": PLACEHOLDER}, {"id": PLACEHOLDER}],
+        })
+    );
+
+    assert!(config_findings(storage_path.path()).is_empty());
+}
+
+#[test]
+fn test_find_unredacted_when_secrets_saved_should_report_them() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/me?api_key=123",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[("Set-Cookie", "session=abc")],
+            Cursor::new("{\"user\": {\"token\": \"Bearer abc123\"}}".as_bytes()),
+        ))
+        .expect("Cannot save request to storage");
+
+    let rules: Vec<String> = config_findings(storage_path.path())
+        .into_iter()
+        .map(|finding| finding.rule)
+        .collect();
+
+    assert_eq!(
+        rules,
+        vec![
+            "query parameter api_key",
+            "JSON path $.user.token",
+            "pattern Bearer (?P<secret>[a-z0-9]+)",
+            "header Set-Cookie",
+        ]
+    );
+}
+
+fn config_findings(root_dir: &Path) -> Vec<Finding> {
+    get_redacting_config(root_dir)
+        .redaction
+        .find_unredacted(root_dir)
+        .expect("Recordings should be checked")
+}
+
+#[test]
+fn test_save_should_skip_denied_headers_unless_allowed() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/",
+        Config::default()
+            .with_root_dir(storage_path.path().to_owned())
+            .with_allowed_header("Date")
+            .with_denied_header("Set-Cookie"),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[
+                ("Content-Type", "text/plain"),
+                ("Transfer-Encoding", "chunked"),
+                ("Connection", "keep-alive"),
+                ("X-Request-Id", "42"),
+                (
+                    "Set-Cookie",
+                    "session=abc; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+                ),
+                ("Date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ],
+            Cursor::new("lorem".as_bytes()),
+        ))
+        .expect("Cannot save request to storage");
+
+    let headers = get_saved_headers(&storage);
+    assert_eq!(
+        headers,
+        vec![
+            ("Content-Type".to_owned(), "text/plain".to_owned()),
+            (
+                "Date".to_owned(),
+                "Wed, 21 Oct 2015 07:28:00 GMT".to_owned()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_load_should_skip_denied_headers_and_recompute_content_length() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/",
+        Config::default()
+            .with_root_dir(storage_path.path().to_owned())
+            .with_all_headers(),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[("Transfer-Encoding", "chunked"), ("Content-Length", "1000")],
+            Cursor::new("lorem".as_bytes()),
+        ))
+        .expect("Cannot save request to storage");
+
+    let response = DirectoryStorage::new_with_config(
+        &"https://example.com/",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap()
+    .load()
+    .expect("Cannot load response");
+
+    let mut expected_headers = http::HeaderMap::new();
+    expected_headers.insert(http::header::CONTENT_LENGTH, 5.into());
+    assert_eq!(response.headers, expected_headers);
+}
+
+struct BinaryHeaderResponse {
+    headers: Vec<(String, Vec<u8>)>,
+    body: Cursor<Vec<u8>>,
+}
+
+impl ParodyResponse for BinaryHeaderResponse {
+    fn get_headers(&self) -> Vec<(String, Vec<u8>)> {
+        self.headers.clone()
+    }
+
+    fn get_body_reader(&mut self) -> &mut dyn Read {
+        &mut self.body
+    }
+
+    fn get_status(&self) -> u16 {
+        200
+    }
+}
+
+#[test]
+fn test_save_when_header_is_not_utf8_should_round_trip_it() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut BinaryHeaderResponse {
+            headers: vec![
+                ("Content-Type".to_owned(), b"text/plain".to_vec()),
+                // "Café" in Latin-1
+                ("X-Name".to_owned(), b"Caf\xe9".to_vec()),
+            ],
+            body: Cursor::new(Vec::new()),
+        })
+        .expect("Cannot save request to storage");
+
+    assert_eq!(
+        ResponseMetadata::load(&storage.get_metadata_file_path())
+            .unwrap()
+            .headers,
+        vec![
+            (
+                "Content-Type".to_owned(),
+                HeaderValue::Text("text/plain".to_owned())
+            ),
+            (
+                "X-Name".to_owned(),
+                HeaderValue::Binary {
+                    base64: "Q2Fm6Q==".to_owned()
+                }
+            ),
+        ]
+    );
+    assert!(std::fs::read_to_string(storage.get_metadata_file_path())
+        .unwrap()
+        .contains("- - X-Name\n    - base64: Q2Fm6Q==\n"));
+
+    let response = storage.load().expect("Cannot load response");
+
+    assert_eq!(
+        get_raw(&response.headers, "x-name"),
+        Some(vec![b"Caf\xe9".to_vec()])
+    );
+    assert_eq!(
+        get_raw(&response.headers, "content-type"),
+        Some(vec![b"text/plain".to_vec()])
+    );
+}
+
+#[test]
+fn test_load_when_header_has_invalid_base64_should_fail() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+
+    std::fs::create_dir_all(storage.get_absolute_storage_path()).unwrap();
+    std::fs::write(storage.get_status_file_path(), "200\n").unwrap();
+    std::fs::write(
+        storage.get_headers_file_path(),
+        "- [\"X-Name\", {base64: \"not base64!\"}]\n",
+    )
+    .unwrap();
+
+    match storage.load() {
+        Err(Error::Common(crate::error::CommonError::Base64Error(_))) => {}
+        Err(error) => panic!("Unexpected error: {:?}", error),
+        Ok(_) => panic!("Invalid header should not load"),
+    }
+}
+
+#[test]
+fn test_migrate_should_convert_legacy_files_to_metadata() {
+    let storage_root = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/legacy",
+        Config::default().with_root_dir(storage_root.path().to_owned()),
+    )
+    .unwrap();
+
+    std::fs::create_dir_all(storage.get_absolute_storage_path()).unwrap();
+    std::fs::write(storage.get_status_file_path(), "201\n").unwrap();
+    std::fs::write(
+        storage.get_headers_file_path(),
+        "- [\"Content-Type\", \"application/json\"]\n- [\"X-Test-Data\", \"1\"]\n- [\"X-Test-Data\", \"2\"]\n",
+    )
+    .unwrap();
+    std::fs::write(storage.get_body_file_path(), "Lorem ipsum dolor sit amet").unwrap();
+
+    assert_eq!(migrate(storage_root.path()).expect("Cannot migrate"), 1);
+    assert_eq!(migrate(storage_root.path()).expect("Cannot migrate"), 0);
+
+    assert!(!storage.get_status_file_path().exists());
+    assert!(!storage.get_headers_file_path().exists());
+
+    let metadata = ResponseMetadata::load(&storage.get_metadata_file_path()).unwrap();
+    assert_eq!(metadata.status, 201);
+    assert_eq!(
+        get_saved_headers(&storage),
+        vec![
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+            ("X-Test-Data".to_owned(), "1".to_owned()),
+            ("X-Test-Data".to_owned(), "2".to_owned()),
+        ]
+    );
+    assert_eq!(
+        metadata.content_hash,
+        Some("sha256:16aba5393ad72c0041f5600ad3c2c52ec437a2f0c7fc08fadfc3c0fe9641d7a3".to_owned())
+    );
+
+    let response = storage.load().expect("Cannot load migrated response");
+    assert_eq!(response.status, http::StatusCode::CREATED);
+    assert_eq!(
+        get_raw(&response.headers, "x-test-data"),
+        Some(vec![b"1".to_vec(), b"2".to_vec()])
+    );
+}
+
+#[test]
+fn test_load_when_metadata_version_is_newer_should_fail() {
+    let storage_root = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/",
+        Config::default().with_root_dir(storage_root.path().to_owned()),
+    )
+    .unwrap();
+
+    std::fs::create_dir_all(storage.get_absolute_storage_path()).unwrap();
+    std::fs::write(
+        storage.get_metadata_file_path(),
+        "version: 1000\nstatus: 200\n",
+    )
+    .unwrap();
+
+    match storage.load() {
+        Err(Error::UnsupportedMetadataVersion(1000)) => {}
+        Err(error) => panic!("Unexpected error: {:?}", error),
+        Ok(_) => panic!("Newer metadata should not load"),
+    }
+}
+
+struct EncodingRequest {
+    url: &'static str,
+    accept_encoding: Option<&'static str>,
+}
+
+impl ParodyRequest for EncodingRequest {
+    fn get_url(&self) -> url::Url {
+        url::Url::from_str(self.url).unwrap()
+    }
+
+    fn get_method(&self) -> String {
+        DEFAULT_METHOD.to_owned()
+    }
+
+    fn get_header(&self, name: &str) -> Option<String> {
+        match name {
+            "accept-encoding" => self.accept_encoding.map(str::to_owned),
+            _ => None,
+        }
+    }
+}
+
+fn load_with_accept_encoding(
+    root_dir: &Path,
+    accept_encoding: Option<&'static str>,
+) -> (http::HeaderMap, Vec<u8>) {
+    let response = DirectoryStorage::new_with_config(
+        &EncodingRequest {
+            url: "https://example.com/compressed",
+            accept_encoding,
+        },
+        Config::default().with_root_dir(root_dir.to_owned()),
+    )
+    .unwrap()
+    .load()
+    .expect("Cannot load response");
+
+    let mut body_cursor = Cursor::new(Vec::<u8>::new());
+    response
+        .body
+        .expect("Response should have a write body")
+        .write_body(&mut body_cursor)
+        .expect("Cannot write body to a cursor");
+
+    (response.headers, body_cursor.into_inner())
+}
+
+#[test]
+fn test_save_when_body_is_compressed_should_store_it_decoded_and_canonical() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let body = "{\"lorem\":\"ipsum\",\"dolor\":[1,2]}";
+    let compressed = encoding::ContentEncoding::Gzip
+        .encode(body.as_bytes())
+        .unwrap();
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/compressed",
+        Config::default()
+            .with_root_dir(storage_path.path().to_owned())
+            .with_canonical_json(),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut BinaryHeaderResponse {
+            headers: vec![
+                ("Content-Type".to_owned(), b"application/json".to_vec()),
+                ("Content-Encoding".to_owned(), b"gzip".to_vec()),
+                (
+                    "Content-Length".to_owned(),
+                    compressed.len().to_string().into_bytes(),
+                ),
+            ],
+            body: Cursor::new(compressed),
+        })
+        .expect("Cannot save request to storage");
+
+    assert_eq!(
+        std::fs::read_to_string(storage.get_body_file_path()).unwrap(),
+        "{\n  \"dolor\": [\n    1,\n    2\n  ],\n  \"lorem\": \"ipsum\"\n}\n"
+    );
+    assert_eq!(
+        get_saved_headers(&storage),
+        vec![("Content-Type".to_owned(), "application/json".to_owned())]
+    );
+    assert_eq!(
+        ResponseMetadata::load(&storage.get_metadata_file_path())
+            .unwrap()
+            .content_encoding,
+        Some("gzip".to_owned())
+    );
+
+    let (headers, replayed) = load_with_accept_encoding(storage_path.path(), Some("gzip, br"));
+    assert_eq!(
+        get_raw(&headers, "content-encoding"),
+        Some(vec![b"gzip".to_vec()])
+    );
+    assert_eq!(get_content_length(&headers), Some(replayed.len() as u64));
+    assert_eq!(
+        encoding::ContentEncoding::Gzip.decode(&replayed).unwrap(),
+        std::fs::read(storage.get_body_file_path()).unwrap()
+    );
+
+    let (headers, replayed) =
+        load_with_accept_encoding(storage_path.path(), Some("br;q=1, gzip;q=0"));
+    assert_eq!(
+        get_raw(&headers, "content-encoding"),
+        Some(vec![b"br".to_vec()])
+    );
+    assert_eq!(
+        encoding::ContentEncoding::Brotli.decode(&replayed).unwrap(),
+        std::fs::read(storage.get_body_file_path()).unwrap()
+    );
+
+    let (headers, replayed) = load_with_accept_encoding(storage_path.path(), None);
+    assert_eq!(get_raw(&headers, "content-encoding"), None);
+    assert_eq!(
+        replayed,
+        std::fs::read(storage.get_body_file_path()).unwrap()
+    );
+}
+
+#[test]
+fn test_save_when_body_cannot_be_decoded_should_store_it_as_is() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/compressed",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut BinaryHeaderResponse {
+            headers: vec![("Content-Encoding".to_owned(), b"br".to_vec())],
+            body: Cursor::new(b"not brotli".to_vec()),
+        })
+        .expect("Cannot save request to storage");
+
+    assert_eq!(
+        std::fs::read(storage.get_body_file_path()).unwrap(),
+        b"not brotli".to_vec()
+    );
+    assert_eq!(
+        get_saved_headers(&storage),
+        vec![("Content-Encoding".to_owned(), "br".to_owned())]
+    );
+}
+
+#[test]
+fn test_negotiate_should_prefer_recorded_encoding() {
+    use encoding::{negotiate, ContentEncoding::*};
+
+    assert_eq!(negotiate(Some("gzip, deflate, br"), Brotli), Some(Brotli));
+    assert_eq!(negotiate(Some("gzip, deflate"), Brotli), Some(Gzip));
+    assert_eq!(negotiate(Some("*"), Deflate), Some(Deflate));
+    assert_eq!(negotiate(Some("identity"), Gzip), None);
+    assert_eq!(negotiate(Some("gzip;q=0"), Gzip), None);
+    assert_eq!(negotiate(None, Gzip), None);
+}
+
+#[test]
+fn test_save_should_name_body_file_by_content_type() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/page",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[("Content-Type", "application/json; charset=utf-8")],
+            Cursor::new("{}".as_bytes()),
+        ))
+        .expect("Cannot save request to storage");
+
+    let json_path = storage.get_absolute_storage_path().join("GET.body.json");
+    assert!(json_path.exists());
+    assert_eq!(storage.get_body_file_path(), json_path);
+
+    storage
+        .save(&mut (
+            200,
+            &[("Content-Type", "text/html")],
+            Cursor::new("<html></html>".as_bytes()),
+        ))
+        .expect("Cannot save request to storage");
+
+    assert!(!json_path.exists());
+
+    let response = storage.load().expect("Cannot load response");
+    let mut body_cursor = Cursor::new(Vec::<u8>::new());
+    response
+        .body
+        .expect("Response should have a write body")
+        .write_body(&mut body_cursor)
+        .expect("Cannot write body to a cursor");
+
+    assert_eq!(
+        storage.get_body_file_path(),
+        storage.get_absolute_storage_path().join("GET.body.html")
+    );
+    assert_eq!(body_cursor.into_inner(), b"<html></html>".to_vec());
+}
+
+#[test]
+fn test_get_body_file_method_should_accept_body_files_only() {
+    assert_eq!(get_body_file_method("GET.body"), Some("GET"));
+    assert_eq!(get_body_file_method("POST.body.json"), Some("POST"));
+    assert_eq!(get_body_file_method("GET.meta.yaml"), None);
+    assert_eq!(get_body_file_method("GET.body."), None);
+    assert_eq!(get_body_file_method("GET.body.tar.gz"), None);
+    assert_eq!(get_body_file_method("GET.bodyjson"), None);
+    assert_eq!(get_body_file_method(".body"), None);
+}
+
+fn save_json_body(config: Config, body: &str) -> String {
+    let storage = DirectoryStorage::new_with_config(&"https://example.com/items", config).unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[("Content-Type", "application/json; charset=utf-8")],
+            Cursor::new(body.as_bytes().to_vec()),
+        ))
+        .expect("Cannot save request to storage");
+
+    std::fs::read_to_string(storage.get_body_file_path()).unwrap()
+}
+
+#[test]
+fn test_save_with_canonical_json_should_store_same_body_for_reordered_members() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_canonical_json();
+
+    let first = save_json_body(
+        config.clone(),
+        "{\"b\":{\"y\":1,\"x\":[{\"d\":2,\"c\":3}]},\"a\":null}",
+    );
+    let second = save_json_body(
+        config,
+        "{\"a\": null, \"b\": {\"x\": [{\"c\": 3, \"d\": 2}], \"y\": 1}}",
+    );
+
+    assert_eq!(first, second);
+    assert_eq!(
+        first,
+        "{\n  \"a\": null,\n  \"b\": {\n    \"x\": [\n      {\n        \"c\": 3,\n        \"d\": 2\n      }\n    ],\n    \"y\": 1\n  }\n}\n"
+    );
+}
+
+#[test]
+fn test_save_with_masked_json_path_should_replace_volatile_values_with_placeholder() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_masked_json_path("$.generated_at")
+        .with_masked_json_path("items.*.id");
+
+    let body = save_json_body(
+        config,
+        "{\"generated_at\":\"2020-01-01T00:00:00Z\",\"items\":[{\"id\":\"a1\",\"name\":\"lorem\"},{\"id\":\"b2\",\"name\":\"ipsum\"}]}",
+    );
+
+    assert_eq!(
+        body,
+        format!(
+            "{{\"generated_at\":\"{0}\",\"items\":[{{\"id\":\"{0}\",\"name\":\"lorem\"}},{{\"id\":\"{0}\",\"name\":\"ipsum\"}}]}}",
+            MASK_PLACEHOLDER
+        )
+    );
+}
+
+#[test]
+fn test_save_with_masked_json_path_when_body_is_not_json_should_store_it_as_is() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_canonical_json()
+        .with_masked_json_path("id");
+
+    assert_eq!(save_json_body(config, "{\"id\": 1"), "{\"id\": 1");
+}
+
+#[test]
+fn test_record_should_send_body_to_client_and_save_it() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/stream",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+    let loading_storage = DirectoryStorage::new_with_config(
+        &"https://example.com/stream",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+    let body_file_path = storage.get_new_body_file_path(Some("text/plain"));
+
+    let response = storage
+        .record((
+            201,
+            &[
+                ("Content-Type", "text/plain"),
+                ("Transfer-Encoding", "chunked"),
+                ("Date", "Tue, 01 Jan 2030 00:00:00 GMT"),
+            ],
+            Cursor::new("lorem ipsum".as_bytes()),
+        ))
+        .expect("Cannot record response");
+
+    assert_eq!(response.status, http::StatusCode::CREATED);
+    // Denied headers are kept out of the recording only
+    assert_eq!(response.headers.len(), 2);
+    assert_eq!(
+        response.headers.get("date").unwrap(),
+        "Tue, 01 Jan 2030 00:00:00 GMT"
+    );
+    assert!(!body_file_path.exists());
+
+    let mut client = Cursor::new(Vec::<u8>::new());
+    response
+        .body
+        .expect("Response should have a write body")
+        .write_body(&mut client)
+        .expect("Cannot write body to a cursor");
+
+    assert_eq!(client.into_inner(), b"lorem ipsum");
+    assert_eq!(std::fs::read(&body_file_path).unwrap(), b"lorem ipsum");
+    assert_eq!(
+        get_saved_headers(&loading_storage),
+        vec![("Content-Type".to_owned(), "text/plain".to_owned())]
+    );
+}
+
+#[test]
+fn test_record_when_client_is_gone_should_still_save_response() {
+    struct GoneClient;
+
+    impl Write for GoneClient {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "client is gone",
+            ))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/stream",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+    let loading_storage = DirectoryStorage::new_with_config(
+        &"https://example.com/stream",
+        Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .unwrap();
+
+    let error = storage
+        .record((200, &[], Cursor::new("lorem ipsum".as_bytes())))
+        .expect("Cannot record response")
+        .body
+        .expect("Response should have a write body")
+        .write_body(&mut GoneClient)
+        .expect_err("Client error should be reported");
+
+    assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+    assert_eq!(
+        std::fs::read(loading_storage.get_body_file_path()).unwrap(),
+        b"lorem ipsum"
+    );
+}
+
+/// A body arriving in pieces after the given delays
+struct DelayedChunks(std::collections::VecDeque<(u64, &'static str)>);
+
+impl Read for DelayedChunks {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.pop_front() {
+            Some((delay_ms, chunk)) => {
+                std::thread::sleep(std::time::Duration::from_millis(delay_ms));
+                buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+                Ok(chunk.len())
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Pieces of the body flushed by the writer
+#[derive(Default)]
+struct FlushedChunks {
+    chunks: Vec<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Write for FlushedChunks {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.chunks.push(std::mem::take(&mut self.pending));
+        Ok(())
+    }
+}
+
+fn save_event_stream(config: Config) {
+    DirectoryStorage::new_with_config(&"https://example.com/events", config)
+        .unwrap()
+        .save(&mut (
+            200,
+            &[("Content-Type", "text/event-stream")],
+            DelayedChunks(
+                vec![
+                    (0, "data: lorem\n\n"),
+                    (60, "data: ipsum token=abc\n\n"),
+                    (30, "data: dolor\n\n"),
+                ]
+                .into(),
+            ),
+        ))
+        .expect("Cannot save request to storage");
+}
+
+#[test]
+fn test_save_when_body_is_event_stream_should_record_chunks_with_delays() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_redaction_pattern(Regex::new("token=(?P<secret>[a-z]+)").unwrap());
+    save_event_stream(config.clone());
+
+    let storage = DirectoryStorage::new_with_config(&"https://example.com/events", config).unwrap();
+    let metadata = ResponseMetadata::load(&storage.get_metadata_file_path()).unwrap();
+
+    assert!(storage.get_body_file_path().ends_with("GET.body.sse"));
+    assert_eq!(
+        std::fs::read_to_string(storage.get_body_file_path()).unwrap(),
+        "data: lorem\n\ndata: ipsum token=PARODY-REDACTED\n\ndata: dolor\n\n"
+    );
+    assert_eq!(
+        metadata
+            .chunks
+            .iter()
+            .map(|chunk| chunk.size)
+            .collect::<Vec<_>>(),
+        vec![13, 35, 13]
+    );
+    assert!(metadata.chunks[1].delay_ms >= 60);
+    assert!(metadata.chunks[2].delay_ms >= 30);
+}
+
+#[test]
+fn test_load_when_body_was_streamed_should_replay_chunks_with_scaled_timing() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = Config::default().with_root_dir(storage_path.path().to_owned());
+    save_event_stream(config.clone());
+
+    let response = DirectoryStorage::new_with_config(
+        &"https://example.com/events",
+        config.with_timing_scale(2.0),
+    )
+    .unwrap()
+    .load()
+    .expect("Cannot load response");
+
+    assert!(!response.headers.contains_key(http::header::CONTENT_LENGTH));
+
+    let mut client = FlushedChunks::default();
+    let started_at = std::time::Instant::now();
+    response
+        .body
+        .expect("Response should have a write body")
+        .write_body(&mut client)
+        .expect("Cannot write body");
+
+    assert!(started_at.elapsed() >= std::time::Duration::from_millis(180));
+    assert_eq!(
+        client.chunks,
+        vec![
+            b"data: lorem\n\n".to_vec(),
+            b"data: ipsum token=abc\n\n".to_vec(),
+            b"data: dolor\n\n".to_vec(),
+        ]
+    );
+}
+
+#[test]
+fn test_save_when_body_is_not_streamed_should_not_record_chunks() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = Config::default().with_root_dir(storage_path.path().to_owned());
+    let body = save_json_body(config.clone(), "{\"lorem\": \"ipsum\"}");
+    let storage = DirectoryStorage::new_with_config(&"https://example.com/items", config).unwrap();
+
+    assert_eq!(body, "{\"lorem\": \"ipsum\"}");
+    assert!(ResponseMetadata::load(&storage.get_metadata_file_path())
+        .unwrap()
+        .chunks
+        .is_empty());
+}
+
+#[test]
+fn test_save_when_body_cannot_be_read_should_keep_previous_recording() {
+    struct FailingBody(Cursor<&'static [u8]>);
+
+    impl Read for FailingBody {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "upstream is gone",
+                )),
+                read => Ok(read),
+            }
+        }
+    }
+
+    let storage_root = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/atomic",
+        Config::default().with_root_dir(storage_root.path().to_owned()),
+    )
+    .unwrap();
+
+    storage
+        .save(&mut (200, &[], Cursor::new("lorem ipsum".as_bytes())))
+        .expect("Cannot save response");
+    storage
+        .save(&mut (500, &[], FailingBody(Cursor::new("dolor".as_bytes()))))
+        .expect_err("Save should fail when the body cannot be read");
+
+    let response = storage.load().expect("Cannot load response");
+    let mut body = Vec::new();
+    response
+        .body
+        .expect("Response should have a body")
+        .write_body(&mut body)
+        .unwrap();
+
+    assert_eq!(response.status, http::StatusCode::OK);
+    assert_eq!(body, b"lorem ipsum");
+
+    let file_names: Vec<String> = std::fs::read_dir(storage.get_absolute_storage_path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert!(
+        file_names.iter().all(|name| !name.ends_with(".tmp")),
+        "Temp files should be removed: {:?}",
+        file_names
+    );
+}
+
+#[test]
+fn test_miss_report_should_see_recordings_saved_since_the_last_one() {
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = Config::default().with_root_dir(storage_root.path().to_owned());
+    let storage =
+        DirectoryStorage::new_with_config(&"https://example.com/users/43", config.clone()).unwrap();
+    let save = |request: &str| {
+        DirectoryStorage::new_with_config(&request, config.clone())
+            .unwrap()
+            .save(&mut (200, &[], Cursor::new("lorem ipsum".as_bytes())))
+            .expect("Cannot save response")
+    };
+
+    assert!(storage.miss_report().unwrap().closest.is_empty());
+
+    save("https://example.com/users/42");
+    assert_eq!(
+        storage
+            .miss_report()
+            .unwrap()
+            .near_misses()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        vec!["GET users/42 (one segment off)"]
+    );
+
+    save("https://example.com/users/43");
+    assert_eq!(
+        storage.miss_report().unwrap().closest.len(),
+        1,
+        "The request's own recording should not be reported"
+    );
+}
+
+#[test]
+fn test_miss_report_should_rank_near_misses_first() {
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = Config::default().with_root_dir(storage_root.path().to_owned());
+
+    for request in &[
+        "https://example.com/orders/1/items",
+        "https://example.com/users/42",
+        "https://example.com/users",
+        "POST https://example.com/users/43",
+        "https://example.com/users/43?page=2",
+    ] {
+        DirectoryStorage::new_with_config(request, config.clone())
+            .unwrap()
+            .save(&mut (200, &[], Cursor::new("lorem ipsum".as_bytes())))
+            .expect("Cannot save response");
+    }
+
+    let report = DirectoryStorage::new_with_config(&"https://example.com/users/43", config)
+        .unwrap()
+        .miss_report()
+        .expect("Cannot make miss report");
+    let closest: Vec<(String, &str, Option<Difference>)> = report
+        .closest
+        .iter()
+        .map(|near_miss| {
+            (
+                near_miss.recording.path.to_string_lossy().into_owned(),
+                near_miss.recording.method.as_str(),
+                near_miss.difference,
+            )
+        })
+        .collect();
+
+    assert_eq!(
+        closest,
+        vec![
+            (
+                "users/43/:PARODY-QUERY/page=2".to_owned(),
+                "GET",
+                Some(Difference::Query)
+            ),
+            ("users/43".to_owned(), "POST", Some(Difference::Method)),
+            ("users/42".to_owned(), "GET", Some(Difference::Segment)),
+            ("users".to_owned(), "GET", Some(Difference::Segment)),
+            ("orders/1/items".to_owned(), "GET", None),
+        ]
+    );
+    assert_eq!(report.near_misses().count(), 4);
+    assert!(report.storage_path.ends_with("users/43"));
+
+    let text = report.to_string();
+    assert!(text.contains("Query in path: all"), "{}", text);
+    assert!(
+        text.contains("  GET users/42 (one segment off)\n"),
+        "{}",
+        text
+    );
+    assert!(text.contains("  GET orders/1/items\n"), "{}", text);
+}
+
+#[test]
+fn test_prune_should_remove_recordings_missing_from_journal() {
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = Config::default().with_root_dir(storage_root.path().to_owned());
+    let journal_path = storage_root.path().join("journal.txt");
+
+    for request in &[
+        "https://example.com/users/42",
+        "POST https://example.com/users/42",
+        "https://example.com/orders/1/items",
+    ] {
+        let storage = DirectoryStorage::new_with_config(request, config.clone()).unwrap();
+        storage
+            .save(&mut (200, &[], Cursor::new("lorem ipsum".as_bytes())))
+            .expect("Cannot save response");
+
+        if !request.contains("orders") {
+            append_to_journal(&journal_path, &storage.recording()).unwrap();
+        }
+    }
+
+    let used = read_journal(&journal_path).expect("Cannot read journal");
+    let unused = find_unused(&storage_root.path().join("example.com"), &used).unwrap();
+    assert!(unused.is_empty(), "{:?}", unused);
+
+    let unused = find_unused(storage_root.path(), &used).unwrap();
+    assert_eq!(
+        unused
+            .iter()
+            .map(|recording| recording.to_string())
+            .collect::<Vec<_>>(),
+        vec!["GET orders/1/items"]
+    );
+
+    remove_recording(storage_root.path(), &unused[0]).expect("Cannot remove recording");
+    assert!(!storage_root.path().join("orders").exists());
+    assert!(storage_root.path().join("users/42/GET.meta.yaml").exists());
+    assert!(storage_root.path().join("users/42/POST.meta.yaml").exists());
+    assert!(find_unused(storage_root.path(), &used).unwrap().is_empty());
+}
+
+#[test]
+fn test_prune_should_remove_only_files_of_the_recording_kind() {
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = Config::default().with_root_dir(storage_root.path().to_owned());
+    let storage =
+        DirectoryStorage::new_with_config(&"https://example.com/chat", config.clone()).unwrap();
+    storage
+        .save(&mut (200, &[], Cursor::new("lorem ipsum".as_bytes())))
+        .expect("Cannot save response");
+    std::fs::write(storage.get_websocket_file_path(), "version: 1\n").unwrap();
+
+    let websocket = storage.recording_of(RecordingKind::WebSocket);
+    assert_eq!(websocket.to_string(), "websocket GET chat");
+    assert_eq!(
+        Recording::from_str("websocket GET chat").unwrap(),
+        websocket
+    );
+    assert_eq!(
+        Recording::from_str("GET chat").unwrap(),
+        storage.recording()
+    );
+    assert_eq!(
+        find_recordings(storage_root.path()).unwrap(),
+        vec![storage.recording(), websocket.clone()]
+    );
+
+    let used = std::iter::once(websocket.clone()).collect();
+    let unused = find_unused(storage_root.path(), &used).unwrap();
+    assert_eq!(unused, vec![storage.recording()]);
+
+    remove_recording(storage_root.path(), &unused[0]).expect("Cannot remove recording");
+    assert!(!storage.get_metadata_file_path().exists());
+    assert!(storage.get_websocket_file_path().exists());
+    assert_eq!(
+        find_recordings(storage_root.path()).unwrap(),
+        vec![websocket.clone()]
+    );
+
+    remove_recording(storage_root.path(), &websocket).expect("Cannot remove recording");
+    assert!(!storage_root.path().join("chat").exists());
+}
+
+#[test]
+fn test_load_when_recording_is_older_than_max_age_should_miss_unless_strict() {
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = Config::default()
+        .with_root_dir(storage_root.path().to_owned())
+        .with_max_age(std::time::Duration::from_secs(24 * 60 * 60));
+    let storage =
+        DirectoryStorage::new_with_config(&"https://example.com/users/42", config.clone()).unwrap();
+    storage
+        .save(&mut (200, &[], Cursor::new("lorem ipsum".as_bytes())))
+        .expect("Cannot save response");
+    assert!(storage.load().is_ok());
+
+    let metadata_file_path = storage_root.path().join("users/42/GET.meta.yaml");
+    let mut metadata = metadata::ResponseMetadata::load(&metadata_file_path).unwrap();
+    metadata.recorded_at = Some("2020-01-01T00:00:00Z".to_owned());
+    metadata.save(&metadata_file_path).unwrap();
+
+    let age = recording_age(storage_root.path(), &storage.recording())
+        .unwrap()
+        .expect("Recording should have an age");
+    assert!(age > std::time::Duration::from_secs(365 * 24 * 60 * 60));
+    assert!(matches!(storage.load(), Err(Error::CacheMiss)));
+
+    let strict_storage = DirectoryStorage::new_with_config(
+        &"https://example.com/users/42",
+        config.with_strict_replay(),
+    )
+    .unwrap();
+    assert!(strict_storage.load().is_ok());
+
+    storage
+        .save(&mut (200, &[], Cursor::new("dolor sit amet".as_bytes())))
+        .expect("Cannot save response");
+    assert!(storage.load().is_ok());
+}
+
+#[test]
+fn test_load_when_recording_age_is_unknown_should_miss_with_max_age() {
+    let storage_root = tempfile::tempdir().unwrap();
+    let config = Config::default().with_root_dir(storage_root.path().to_owned());
+    let recording_path = storage_root.path().join("users/42");
+    std::fs::create_dir_all(&recording_path).unwrap();
+    std::fs::write(recording_path.join("GET.status"), "200").unwrap();
+
+    let storage =
+        DirectoryStorage::new_with_config(&"https://example.com/users/42", config.clone()).unwrap();
+    assert!(storage.load().is_ok());
+    assert_eq!(
+        recording_age(storage_root.path(), &storage.recording()).unwrap(),
+        None
+    );
+
+    let storage = DirectoryStorage::new_with_config(
+        &"https://example.com/users/42",
+        config.with_max_age(std::time::Duration::from_secs(24 * 60 * 60)),
+    )
+    .unwrap();
+    assert!(matches!(storage.load(), Err(Error::CacheMiss)));
+}
+
+#[test]
+fn test_find_unredacted_when_upstream_url_has_secrets_should_report_them() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let metadata_path = storage_path.path().join("me");
+    std::fs::create_dir_all(&metadata_path).unwrap();
+
+    let mut metadata = metadata::ResponseMetadata::new(200, Vec::new());
+    metadata.upstream_url = Some("https://example.com/me?api_key=123&auth=Bearer%20abc".to_owned());
+    metadata.save(&metadata_path.join("GET.meta.yaml")).unwrap();
+
+    let rules: Vec<String> = config_findings(storage_path.path())
+        .into_iter()
+        .map(|finding| finding.rule)
+        .collect();
+
+    assert_eq!(rules, vec!["query parameter api_key"]);
+
+    metadata.upstream_url = Some("https://example.com/me?auth=Bearer abc".to_owned());
+    metadata.save(&metadata_path.join("GET.meta.yaml")).unwrap();
+
+    let rules: Vec<String> = config_findings(storage_path.path())
+        .into_iter()
+        .map(|finding| finding.rule)
+        .collect();
+
+    assert_eq!(rules, vec!["pattern Bearer (?P<secret>[a-z0-9]+)"]);
+}
+
+#[test]
+fn test_save_when_secret_is_split_between_chunks_should_redact_it() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_redaction_pattern(Regex::new("token=(?P<secret>[a-z]+)").unwrap());
+    let storage =
+        DirectoryStorage::new_with_config(&"https://example.com/events", config.clone()).unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[("Content-Type", "text/event-stream")],
+            DelayedChunks(
+                vec![
+                    (0, "data: lorem tok"),
+                    (0, "en=ab"),
+                    (0, "c ipsum\n\n"),
+                    (0, "data: dolor\n\n"),
+                ]
+                .into(),
+            ),
+        ))
+        .expect("Cannot save request to storage");
+
+    let metadata = ResponseMetadata::load(&storage.get_metadata_file_path()).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(storage.get_body_file_path()).unwrap(),
+        "data: lorem token=PARODY-REDACTED ipsum\n\ndata: dolor\n\n"
+    );
+    assert_eq!(
+        metadata
+            .chunks
+            .iter()
+            .map(|chunk| chunk.size)
+            .collect::<Vec<_>>(),
+        vec![15, 18, 8, 13]
+    );
+    assert!(config
+        .redaction
+        .find_unredacted(storage_path.path())
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_load_when_origin_is_split_between_chunks_should_rewrite_it() {
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_origin_rewrite(url::Url::from_str("https://example.com/").unwrap());
+    let storage =
+        DirectoryStorage::new_with_config(&"http://localhost:1234/events", config).unwrap();
+
+    storage
+        .save(&mut (
+            200,
+            &[("Content-Type", "text/event-stream")],
+            DelayedChunks(
+                vec![
+                    (0, "data: https://exa"),
+                    (0, "mple.com/items\n\n"),
+                    (0, "data: https://example.com/users\n\n"),
+                ]
+                .into(),
+            ),
+        ))
+        .expect("Cannot save request to storage");
+
+    let mut client = FlushedChunks::default();
+    storage
+        .load()
+        .expect("Cannot load response")
+        .body
+        .expect("Response should have a write body")
+        .write_body(&mut client)
+        .expect("Cannot write body");
+
+    assert_eq!(
+        client.chunks,
+        vec![
+            b"data: http://localhost:1234".to_vec(),
+            b"/items\n\n".to_vec(),
+            b"data: http://localhost:1234/users\n\n".to_vec(),
+        ]
+    );
+}
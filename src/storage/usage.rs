@@ -0,0 +1,110 @@
+//! Which recordings are served and how old they are, so stale ones can be pruned
+
+use super::{
+    metadata::ResponseMetadata,
+    nearby::{self, Recording, RecordingKind},
+};
+use crate::{result::Result, websocket::transcript::Transcript};
+use std::{
+    collections::BTreeSet,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::Duration,
+};
+
+/// Appends the served recording to the journal file, one per line
+pub fn append_to_journal(journal_path: &Path, recording: &Recording) -> Result<()> {
+    let mut journal = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal_path)?;
+
+    // A single write, so lines of concurrent sessions don't interleave
+    journal.write_all(format!("{}\n", recording).as_bytes())?;
+    Ok(())
+}
+
+/// Recordings listed in the journal file
+pub fn read_journal(journal_path: &Path) -> Result<BTreeSet<Recording>> {
+    let mut recordings = BTreeSet::new();
+
+    for line in BufReader::new(std::fs::File::open(journal_path)?).lines() {
+        let line = line?;
+
+        if !line.is_empty() {
+            recordings.insert(line.parse().unwrap_or_else(|never| match never {}));
+        }
+    }
+
+    Ok(recordings)
+}
+
+/// Recordings under the directory which weren't served, sorted by path
+pub fn find_unused(root_dir: &Path, used: &BTreeSet<Recording>) -> Result<Vec<Recording>> {
+    Ok(nearby::find_recordings(root_dir)?
+        .into_iter()
+        .filter(|recording| !used.contains(recording))
+        .collect())
+}
+
+/// Time since the recording was made, `None` if unknown, e.g. for legacy recordings
+pub fn recording_age(root_dir: &Path, recording: &Recording) -> Result<Option<Duration>> {
+    let file_path = root_dir
+        .join(&recording.path)
+        .join(recording.method.clone() + recording.kind.file_extension());
+
+    if !file_path.exists() {
+        return Ok(None);
+    }
+
+    Ok(match recording.kind {
+        RecordingKind::Http => ResponseMetadata::load(&file_path)?.age(),
+        RecordingKind::WebSocket => Transcript::load(&file_path)?.age(),
+        RecordingKind::Grpc => crate::grpc::recording::Recording::load(&file_path)?.age(),
+    })
+}
+
+/// Removes files of the recording and directories left empty
+///
+/// Recordings of other kinds in the same directory, e.g. a WebSocket session
+/// next to an HTTP response with the same method, are kept.
+pub fn remove_recording(root_dir: &Path, recording: &Recording) -> Result<()> {
+    let dir = root_dir.join(&recording.path);
+    let extensions: &[&str] = match recording.kind {
+        RecordingKind::Http => &[
+            super::METADATA_FILE_EXTENSION,
+            super::STATUS_FILE_EXTENSION,
+            super::HEADERS_FILE_EXTENSION,
+        ],
+        RecordingKind::WebSocket => &[super::WEBSOCKET_FILE_EXTENSION],
+        RecordingKind::Grpc => &[super::GRPC_FILE_EXTENSION],
+    };
+    let file_names = extensions
+        .iter()
+        .map(|extension| recording.method.clone() + extension)
+        .collect::<Vec<_>>();
+
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_body = recording.kind == RecordingKind::Http
+            && super::get_body_file_method(&name) == Some(&recording.method);
+
+        if file_names.contains(&name) || is_body {
+            debug!(target: "storage", "Removing: {}", entry.path().to_string_lossy());
+            super::remove_if_exists(&entry.path())?;
+        }
+    }
+
+    let mut dir = dir.as_path();
+    while dir != root_dir && std::fs::read_dir(dir)?.next().is_none() {
+        std::fs::remove_dir(dir)?;
+        dir = match dir.parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use crate::{
+    result::Result,
+    server::{Accepting, Connection, Listener, Stream},
+};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::sync::Arc;
+
+#[cfg(test)]
+mod test;
+
+/// A certificate Parody presents to its clients
+pub enum ServerCertificate {
+    /// A certificate for `localhost` issued by a freshly generated authority
+    SelfSigned,
+    /// PEM-encoded certificate chain and private key
+    Pem {
+        certificate_pem: String,
+        key_pem: String,
+    },
+}
+
+/// TLS configuration presenting the certificate chain
+pub(crate) fn server_config(
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<rustls::ServerConfig>> {
+    let mut server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certificates, key)?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Arc::new(server_config))
+}
+
+/// TLS configuration from PEM-encoded certificate chain and private key
+pub(crate) fn server_config_from_pem(
+    certificate_pem: &str,
+    key_pem: &str,
+) -> Result<Arc<rustls::ServerConfig>> {
+    let certificates = CertificateDer::pem_slice_iter(certificate_pem.as_bytes())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    server_config(
+        certificates,
+        PrivateKeyDer::from_pem_slice(key_pem.as_bytes())?,
+    )
+}
+
+/// Wraps connections into TLS sessions
+#[derive(Clone)]
+pub struct TlsListener {
+    acceptor: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsListener {
+    pub fn new(server_config: Arc<rustls::ServerConfig>) -> Self {
+        Self {
+            acceptor: tokio_rustls::TlsAcceptor::from(server_config),
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    fn accept(&self, connection: Connection) -> Accepting<'_> {
+        Box::pin(async move {
+            let stream = match connection.stream {
+                Stream::Tcp(stream) => stream,
+                Stream::Tls(_) => return Ok(Some(connection)),
+            };
+
+            Ok(Some(Connection {
+                stream: Stream::Tls(Box::new(self.acceptor.accept(stream).await?)),
+                ..connection
+            }))
+        })
+    }
+}
@@ -0,0 +1,85 @@
+use super::*;
+use std::{path::Path, str::FromStr};
+
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+fn get_storage_config() -> crate::storage::Config {
+    crate::storage::Config::default().with_root_dir(
+        Path::new(file!())
+            .parent()
+            .expect("source file always has a parent directory")
+            .join("test_files")
+            .join("localhost"),
+    )
+}
+
+fn get_cached_response(parody: &crate::Parody) -> reqwest::blocking::Response {
+    let certificate = reqwest::Certificate::from_pem(
+        parody
+            .certificate_pem()
+            .expect("TLS server should have a certificate")
+            .as_bytes(),
+    )
+    .expect("Server certificate should be valid PEM");
+
+    reqwest::blocking::Client::builder()
+        .add_root_certificate(certificate)
+        .build()
+        .expect("TLS client should be built")
+        .get(format!("https://localhost:{}/", parody.port()))
+        .send()
+        .expect("HTTPS request succeeded")
+}
+
+#[test]
+fn test_start_tls_with_self_signed_certificate_should_serve_https() {
+    init();
+    let parody = crate::start_tls(
+        url::Url::from_str("http://example.com").unwrap(),
+        get_storage_config(),
+        ServerCertificate::SelfSigned,
+    )
+    .expect("TLS server should start");
+
+    let response = get_cached_response(&parody);
+
+    assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+    assert_eq!(
+        response.text().expect("Response should have text body"),
+        "{\"lorem\": \"ipsum\"}\n"
+    );
+}
+
+#[test]
+fn test_start_tls_with_pem_certificate_should_present_it() {
+    init();
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+        .expect("Certificate should be generated");
+    let certificate_pem = certificate.cert.pem();
+
+    let parody = crate::start_tls(
+        url::Url::from_str("http://example.com").unwrap(),
+        get_storage_config(),
+        ServerCertificate::Pem {
+            certificate_pem: certificate_pem.clone(),
+            key_pem: certificate.key_pair.serialize_pem(),
+        },
+    )
+    .expect("TLS server should start");
+
+    assert_eq!(parody.certificate_pem(), Some(certificate_pem.as_str()));
+    assert_eq!(
+        get_cached_response(&parody).status(),
+        reqwest::StatusCode::CREATED
+    );
+}
+
+#[test]
+fn test_server_config_from_pem_when_key_is_missing_should_fail() {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+        .expect("Certificate should be generated");
+
+    assert!(server_config_from_pem(&certificate.cert.pem(), "").is_err());
+}
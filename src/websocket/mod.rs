@@ -0,0 +1,661 @@
+//! Recording and replay of WebSocket sessions
+
+use crate::{
+    forward_middleware::{self, connect},
+    request::ParodyRequest,
+    result::Result,
+    server::{Accepting, Connection, Listener, Stream},
+    storage::{self, DirectoryStorage, RecordingKind, Redaction},
+    ServedRecordings,
+};
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, sync::OwnedRwLockWriteGuard};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use transcript::{Payload, Peer, Transcript, TranscriptMessage};
+use tungstenite::{
+    client::IntoClientRequest,
+    handshake::{
+        client,
+        server::{ErrorResponse, Request, Response},
+    },
+};
+
+#[cfg(test)]
+mod test;
+pub(crate) mod transcript;
+
+const MAX_HEAD_LENGTH: usize = 8192;
+/// How long to wait for the rest of a request head before leaving it to the server
+const HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for more of the request head to arrive before peeking again
+const HEAD_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Handshake headers of the client connection, the upstream one gets its own
+const HANDSHAKE_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+];
+
+type ClientSocket = WebSocketStream<TcpStream>;
+type UpstreamSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Takes over connections starting with WebSocket upgrades
+///
+/// Upgrade requests are detected before the server sees them: without
+/// a transcript the session is proxied to the upstream and recorded,
+/// otherwise recorded server messages are replayed in response
+/// to matching client ones.
+#[derive(Clone)]
+pub struct WebSocketListener {
+    sessions: Arc<Sessions>,
+}
+
+/// What sessions need to find, record and replay transcripts
+struct Sessions {
+    upstream_url: url::Url,
+    storage_config: storage::Config,
+    /// Connects to `wss` upstreams with the TLS settings of the upstream config
+    tls_connector: native_tls::TlsConnector,
+    /// Connect and read timeouts and the proxy of the upstream connections
+    upstream_config: forward_middleware::Config,
+    served: ServedRecordings,
+}
+
+enum Session {
+    Record {
+        upstream: Box<UpstreamSocket>,
+        transcript: Transcript,
+        storage: Box<DirectoryStorage>,
+        /// Sessions with the same key wait until the transcript is saved
+        recording_lock: OwnedRwLockWriteGuard<()>,
+    },
+    Replay(Transcript),
+    /// No transcript in strict replay mode, the handshake is answered with the status and report
+    Reject {
+        status: u16,
+        report: String,
+    },
+}
+
+/// The handshake request as the storage sees it
+struct UpgradeRequest {
+    url: url::Url,
+    headers: Vec<(String, String)>,
+}
+
+impl ParodyRequest for UpgradeRequest {
+    fn get_url(&self) -> url::Url {
+        self.url.clone()
+    }
+
+    fn get_method(&self) -> String {
+        "GET".to_owned()
+    }
+
+    fn get_header(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self
+            .headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+}
+
+impl UpgradeRequest {
+    fn new(request: &Request) -> Result<Self> {
+        let headers: Vec<(String, String)> = request
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str().to_owned(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+        let host = headers
+            .iter()
+            .find(|(name, _)| name == "host")
+            .map(|(_, host)| host.as_str())
+            .unwrap_or("localhost");
+        let path = request
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+
+        Ok(Self {
+            url: url::Url::parse(&format!("http://{}{}", host, path))?,
+            headers,
+        })
+    }
+}
+
+impl WebSocketListener {
+    pub fn new(
+        upstream_url: url::Url,
+        storage_config: storage::Config,
+        upstream_config: &forward_middleware::Config,
+        served: ServedRecordings,
+    ) -> Result<Self> {
+        Ok(Self {
+            sessions: Arc::new(Sessions {
+                upstream_url,
+                storage_config,
+                tls_connector: upstream_config.build_tls_connector()?,
+                upstream_config: upstream_config.clone(),
+                served,
+            }),
+        })
+    }
+}
+
+impl Listener for WebSocketListener {
+    fn accept(&self, connection: Connection) -> Accepting<'_> {
+        Box::pin(async move {
+            let request = match connection.stream.tcp() {
+                Some(stream) => peek_upgrade_request(stream).await,
+                None => None,
+            };
+            let (stream, request) = match (connection.stream, request) {
+                (Stream::Tcp(stream), Some(request)) => (stream, request),
+                (stream, _) => {
+                    return Ok(Some(Connection {
+                        stream,
+                        ..connection
+                    }))
+                }
+            };
+            let sessions = self.sessions.clone();
+
+            tokio::spawn(async move {
+                if let Err(error) = sessions.handle(stream, request).await {
+                    warn!(target: "websocket", "WebSocket session failed: {}", error);
+                }
+            });
+
+            Ok(None)
+        })
+    }
+}
+
+impl Sessions {
+    /// Opens the session before answering the handshake, so failures get a proper response
+    // The handshake callback has to return tungstenite's own large error response
+    #[allow(clippy::result_large_err)]
+    async fn handle(&self, stream: TcpStream, request: Request) -> Result<()> {
+        let opened = self.open(&request).await;
+        let client = tokio_tungstenite::accept_hdr_async(stream, |_: &Request, response| {
+            respond(&request, &opened, response)
+        })
+        .await;
+        // The client was told why the session could not be opened
+        let (session, _) = opened?;
+
+        match session {
+            Session::Record {
+                upstream,
+                transcript,
+                storage,
+                recording_lock: _recording_lock,
+            } => {
+                record(
+                    client?,
+                    *upstream,
+                    transcript,
+                    &storage,
+                    &self.storage_config.redaction,
+                    self.upstream_config.read_timeout,
+                    &self.served,
+                )
+                .await
+            }
+            Session::Replay(transcript) => replay(client?, &transcript, &self.storage_config).await,
+            // The handshake response was the whole session
+            Session::Reject { .. } => Ok(()),
+        }
+    }
+
+    /// Replays the recorded session or connects to the upstream to record one
+    ///
+    /// Sessions take the lock of their key like HTTP requests, so the same
+    /// session opened concurrently is recorded once and replayed to the others.
+    async fn open(&self, request: &Request) -> Result<(Session, Option<String>)> {
+        let storage = DirectoryStorage::new_with_config(
+            &UpgradeRequest::new(request)?,
+            self.storage_config.clone(),
+        )?;
+        let lock = storage.get_lock();
+
+        let (replaying, _) = storage::lock::read(lock.clone()).await;
+        if let Some(transcript) = load(&storage).await? {
+            return Ok(self.replay(transcript, &storage));
+        }
+        drop(replaying);
+
+        if let Some(status) = storage.strict_replay_status() {
+            // Looking for close recordings may walk the storage directory
+            let report = tokio::task::spawn_blocking(move || {
+                storage.miss_report_of(RecordingKind::WebSocket)
+            })
+            .await
+            .map_err(std::io::Error::from)??;
+            let request = format!("GET {}", request.uri());
+            let message = format!("No recording for: {}\n{}", request, report);
+            warn!(target: "websocket", "{}", message.trim_end());
+            self.served.insert_miss(storage::Miss { request, report });
+
+            return Ok((
+                Session::Reject {
+                    status,
+                    report: message,
+                },
+                None,
+            ));
+        }
+
+        let (recording_lock, _) = storage::lock::write(lock).await;
+
+        // Another session with the same key may have been recorded meanwhile
+        if let Some(transcript) = load(&storage).await? {
+            return Ok(self.replay(transcript, &storage));
+        }
+
+        let upstream_request = self.get_upstream_request(request)?;
+        debug!(target: "websocket", "Recording session with: {}", upstream_request.uri());
+
+        let (upstream, response) = self.connect(upstream_request).await?;
+        let protocol = response
+            .headers()
+            .get("sec-websocket-protocol")
+            .and_then(|protocol| protocol.to_str().ok())
+            .map(str::to_owned);
+
+        tokio::fs::create_dir_all(storage.get_absolute_storage_path()).await?;
+
+        Ok((
+            Session::Record {
+                upstream: Box::new(upstream),
+                transcript: Transcript::new(protocol.clone()),
+                storage: Box::new(storage),
+                recording_lock,
+            },
+            protocol,
+        ))
+    }
+
+    /// Marks the transcript served and replays it
+    fn replay(
+        &self,
+        transcript: Transcript,
+        storage: &DirectoryStorage,
+    ) -> (Session, Option<String>) {
+        debug!(
+            target: "websocket",
+            "Replaying session from: {}",
+            storage.get_websocket_file_path().to_string_lossy()
+        );
+        let protocol = transcript.protocol.clone();
+        self.served
+            .insert(storage.recording_of(RecordingKind::WebSocket));
+
+        (Session::Replay(transcript), protocol)
+    }
+
+    /// Connects to the upstream like the HTTP client does, with its TLS settings, timeouts and proxy
+    async fn connect(&self, request: Request) -> Result<(UpstreamSocket, client::Response)> {
+        let uri = request.uri();
+        let is_tls = uri.scheme_str() == Some("wss");
+        let host = uri
+            .host()
+            .unwrap_or("localhost")
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = uri.port_u16().unwrap_or(if is_tls { 443 } else { 80 });
+
+        let stream = connect::connect(&self.upstream_config, &host, port).await?;
+        let connector = match is_tls {
+            true => Connector::NativeTls(self.tls_connector.clone()),
+            false => Connector::Plain,
+        };
+
+        let handshake =
+            tokio_tungstenite::client_async_tls_with_config(request, stream, None, Some(connector));
+
+        Ok(connect::read(self.upstream_config.read_timeout, handshake).await??)
+    }
+
+    /// The handshake to the upstream with the client's own headers
+    fn get_upstream_request(&self, request: &Request) -> Result<Request> {
+        let mut url = self.upstream_url.clone();
+        let scheme = match url.scheme() {
+            "https" => "wss",
+            _ => "ws",
+        };
+
+        url.set_scheme(scheme)
+            .expect("HTTP schemes should always convert to WebSocket ones");
+        url.set_path(&format!(
+            "{}/{}",
+            url.path().trim_end_matches('/'),
+            request.uri().path().trim_start_matches('/')
+        ));
+        url.set_query(request.uri().query());
+
+        let mut upstream_request = url.as_str().into_client_request()?;
+
+        for (name, value) in request.headers() {
+            if !HANDSHAKE_HEADERS.contains(&name.as_str()) {
+                upstream_request
+                    .headers_mut()
+                    .append(name.clone(), value.clone());
+            }
+        }
+
+        Ok(upstream_request)
+    }
+}
+
+/// Completes the handshake with the protocol of the session or explains why it could not be opened
+#[allow(clippy::result_large_err)]
+fn respond(
+    request: &Request,
+    opened: &Result<(Session, Option<String>)>,
+    mut response: Response,
+) -> std::result::Result<Response, ErrorResponse> {
+    match opened {
+        Ok((Session::Reject { status, report }, _)) => {
+            let mut response = ErrorResponse::new(Some(report.clone()));
+            *response.status_mut() = tungstenite::http::StatusCode::from_u16(*status)
+                .unwrap_or(tungstenite::http::StatusCode::NOT_FOUND);
+            response.headers_mut().insert(
+                "content-type",
+                tungstenite::http::HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+            Err(response)
+        }
+        Ok((_, protocol)) => {
+            if let Some(protocol) = protocol.as_ref().and_then(|protocol| protocol.parse().ok()) {
+                response
+                    .headers_mut()
+                    .insert("sec-websocket-protocol", protocol);
+            }
+
+            Ok(response)
+        }
+        Err(error) => {
+            warn!(target: "websocket", "Cannot open session for {}: {}", request.uri(), error);
+
+            let mut response = ErrorResponse::new(Some(error.to_string()));
+            *response.status_mut() = tungstenite::http::StatusCode::BAD_GATEWAY;
+            Err(response)
+        }
+    }
+}
+
+/// Passes messages between the client and the upstream, saving them redacted once either side closes
+///
+/// Sessions ending without a closing handshake aren't saved, replaying them would cut clients short.
+async fn record(
+    mut client: ClientSocket,
+    mut upstream: UpstreamSocket,
+    mut transcript: Transcript,
+    storage: &DirectoryStorage,
+    redaction: &Redaction,
+    read_timeout: Option<Duration>,
+    served: &ServedRecordings,
+) -> Result<()> {
+    let transcript_path = storage.get_websocket_file_path();
+    let mut last_message_at = Instant::now();
+
+    let result = loop {
+        let (from, message) = tokio::select! {
+            message = client.next() => (Peer::Client, message),
+            message = next_upstream_message(&mut upstream, read_timeout) => (Peer::Server, message),
+        };
+        let message = match message {
+            Some(Ok(message)) => message,
+            Some(Err(error)) => break Err(error),
+            None => break Err(tungstenite::Error::ConnectionClosed),
+        };
+
+        // Pings are answered by each side's socket on its own
+        let payload = match Payload::from_message(&message) {
+            Some(payload) => payload,
+            None => continue,
+        };
+        let received_at = Instant::now();
+        let is_close = message.is_close();
+
+        trace!(target: "websocket", "{:?} sent: {:?}", from, payload);
+        transcript.messages.push(TranscriptMessage {
+            from,
+            delay_ms: (received_at - last_message_at).as_millis() as u64,
+            payload: payload.redacted(redaction),
+        });
+        last_message_at = received_at;
+
+        let sent = match from {
+            Peer::Client => upstream.send(message).await,
+            Peer::Server => client.send(message).await,
+        };
+
+        if is_close {
+            // Completes the closing handshake with the side which started it
+            let _ = client.flush().await;
+            let _ = upstream.flush().await;
+            break Ok(());
+        }
+
+        if let Err(error) = sent {
+            break Err(error);
+        }
+    };
+
+    if let Err(error) = result {
+        debug!(target: "websocket", "Not saving incomplete session: {}", transcript_path.to_string_lossy());
+        return match is_closed(&error) {
+            true => Ok(()),
+            false => Err(error.into()),
+        };
+    }
+
+    let saved_transcript = transcript_path.clone();
+    tokio::task::spawn_blocking(move || transcript.save(&saved_transcript))
+        .await
+        .map_err(std::io::Error::from)??;
+    storage.invalidate_recordings_index();
+    served.insert(storage.recording_of(RecordingKind::WebSocket));
+    info!(target: "websocket", "Saved session to: {}", transcript_path.to_string_lossy());
+
+    Ok(())
+}
+
+/// Answers client messages with server messages recorded after the matching ones
+///
+/// Client messages are looked up after the last matched one first,
+/// so repeated messages get their replies in the recorded order.
+/// Client messages are redacted like recorded ones before they are compared.
+async fn replay(
+    mut client: ClientSocket,
+    transcript: &Transcript,
+    config: &storage::Config,
+) -> Result<()> {
+    let timing_scale = config.timing_scale.unwrap_or(1.0);
+    let mut position = send_replies(&mut client, transcript, 0, timing_scale).await?;
+
+    while let Some(message) = client.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(error) if is_closed(&error) => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+
+        let payload = match Payload::from_message(&message) {
+            Some(Payload::Close(_)) => {
+                let _ = client.flush().await;
+                return Ok(());
+            }
+            Some(payload) => payload.redacted(&config.redaction),
+            None => continue,
+        };
+
+        match transcript
+            .find_client_message(position, &payload)
+            .or_else(|| transcript.find_client_message(0, &payload))
+        {
+            Some(index) => {
+                position = send_replies(&mut client, transcript, index + 1, timing_scale).await?
+            }
+            None => warn!(target: "websocket", "No recorded client message matches: {:?}", payload),
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends server messages starting at the position, returns the position after them
+async fn send_replies(
+    client: &mut ClientSocket,
+    transcript: &Transcript,
+    position: usize,
+    timing_scale: f64,
+) -> Result<usize> {
+    let replies = transcript.server_replies(position);
+
+    for reply in replies {
+        tokio::time::sleep(Duration::from_millis(reply.delay_ms).mul_f64(timing_scale.max(0.0)))
+            .await;
+        client.send(reply.payload.to_message()?).await?;
+    }
+
+    Ok(position + replies.len())
+}
+
+/// The transcript of the session, unless there is none or it is stale
+async fn load(storage: &DirectoryStorage) -> Result<Option<Transcript>> {
+    let transcript_path = storage.get_websocket_file_path();
+    let loading_path = transcript_path.clone();
+
+    // Loading reads a file, which would block the runtime
+    let transcript = tokio::task::spawn_blocking(move || match loading_path.exists() {
+        true => Transcript::load(&loading_path).map(Some),
+        false => Ok(None),
+    })
+    .await
+    .map_err(std::io::Error::from)??;
+
+    Ok(match transcript {
+        Some(transcript) if storage.is_stale(transcript.age()) => {
+            info!(
+                target: "websocket",
+                "Recording is stale, recording again: {}",
+                transcript_path.to_string_lossy()
+            );
+            None
+        }
+        transcript => transcript,
+    })
+}
+
+/// The next upstream message, failing the session once the upstream is silent for longer than the read timeout
+async fn next_upstream_message(
+    upstream: &mut UpstreamSocket,
+    read_timeout: Option<Duration>,
+) -> Option<tungstenite::Result<tungstenite::Message>> {
+    match connect::read(read_timeout, upstream.next()).await {
+        Ok(message) => message,
+        Err(error) => Some(Err(tungstenite::Error::Io(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            error.to_string(),
+        )))),
+    }
+}
+
+/// Whether the error just means the session is over
+fn is_closed(error: &tungstenite::Error) -> bool {
+    matches!(
+        error,
+        tungstenite::Error::ConnectionClosed
+            | tungstenite::Error::AlreadyClosed
+            | tungstenite::Error::Protocol(
+                tungstenite::error::ProtocolError::ResetWithoutClosingHandshake
+            )
+    )
+}
+
+/// The request head if the connection starts with a WebSocket upgrade
+async fn peek_upgrade_request(stream: &TcpStream) -> Option<Request> {
+    let mut head = [0; MAX_HEAD_LENGTH];
+    let started_at = Instant::now();
+
+    // Peeking returns what has arrived so far, the head may come in several segments
+    loop {
+        let length = match stream.peek(&mut head).await {
+            Ok(length) => length,
+            Err(error) => {
+                trace!(target: "websocket", "Cannot peek request: {}", error);
+                return None;
+            }
+        };
+        let head = &head[..length];
+        let is_complete = head.windows(4).any(|window| window == b"\r\n\r\n");
+
+        // Only GET requests upgrade, anything else goes to the server right away
+        if is_complete || length == MAX_HEAD_LENGTH || !b"GET ".starts_with(&head[..length.min(4)])
+        {
+            return parse_upgrade_request(head);
+        }
+
+        if length == 0 || started_at.elapsed() >= HEAD_TIMEOUT {
+            trace!(target: "websocket", "Request head incomplete after {} bytes", length);
+            return None;
+        }
+
+        tokio::time::sleep(HEAD_POLL_INTERVAL).await;
+    }
+}
+
+fn parse_upgrade_request(head: &[u8]) -> Option<Request> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Request::new(&mut headers);
+
+    match parsed.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        _ => return None,
+    }
+
+    let is_upgrade = parsed.headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("upgrade")
+            && String::from_utf8_lossy(header.value)
+                .split(',')
+                .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+    });
+
+    if !is_upgrade {
+        return None;
+    }
+
+    let mut request = Request::builder().method(parsed.method?).uri(parsed.path?);
+
+    for header in parsed.headers.iter() {
+        request = request.header(header.name, header.value);
+    }
+
+    request.body(()).ok()
+}
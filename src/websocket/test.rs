@@ -0,0 +1,602 @@
+use super::*;
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::Path,
+};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+/// How often to look for the saved transcript
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+/// Greets a single client and answers its messages in upper case
+fn start_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Upstream should listen");
+    let addr = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("Upstream should accept");
+        let mut socket = tungstenite::accept(stream).expect("Upstream handshake should succeed");
+
+        socket.send(Message::text("hello")).unwrap();
+
+        loop {
+            match socket.read() {
+                Ok(Message::Text(text)) => socket
+                    .send(Message::text(text.as_str().to_uppercase()))
+                    .unwrap(),
+                Ok(Message::Close(_)) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    });
+
+    addr
+}
+
+fn read_text(socket: &mut WebSocket<MaybeTlsStream<std::net::TcpStream>>) -> String {
+    match socket.read().expect("Message should be received") {
+        Message::Text(text) => text.as_str().to_owned(),
+        message => panic!("Unexpected message: {:?}", message),
+    }
+}
+
+fn exchange(port: u16, texts: &[&str]) -> Vec<String> {
+    let (mut socket, _) = tungstenite::connect(format!("ws://127.0.0.1:{}/chat?room=1", port))
+        .expect("Parody handshake should succeed");
+    let mut received = vec![read_text(&mut socket)];
+
+    for text in texts {
+        socket.send(Message::text(*text)).unwrap();
+        received.push(read_text(&mut socket));
+    }
+
+    socket.close(None).unwrap();
+    while socket.read().is_ok() {}
+
+    received
+}
+
+fn wait_for(path: &Path) {
+    let started_at = Instant::now();
+
+    while !path.exists() {
+        assert!(
+            started_at.elapsed() < Duration::from_secs(5),
+            "Transcript should be saved"
+        );
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[test]
+fn websocket_session_should_be_recorded_and_replayed() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default().with_root_dir(storage_path.path().to_owned());
+    let transcript_path =
+        DirectoryStorage::new_with_config(&"http://localhost/chat?room=1", config.clone())
+            .unwrap()
+            .get_websocket_file_path();
+
+    let recording = crate::start(
+        url::Url::parse(&format!("http://{}", start_upstream())).unwrap(),
+        config.clone(),
+    )
+    .expect("Parody should start");
+
+    assert_eq!(
+        exchange(recording.port(), &["lorem", "ipsum"]),
+        vec!["hello", "LOREM", "IPSUM"]
+    );
+    wait_for(&transcript_path);
+    drop(recording);
+
+    let transcript = Transcript::load(&transcript_path).unwrap();
+    assert_eq!(
+        transcript
+            .messages
+            .iter()
+            .map(|message| (message.from, message.payload.clone()))
+            .collect::<Vec<_>>(),
+        vec![
+            (Peer::Server, Payload::Text("hello".to_owned())),
+            (Peer::Client, Payload::Text("lorem".to_owned())),
+            (Peer::Server, Payload::Text("LOREM".to_owned())),
+            (Peer::Client, Payload::Text("ipsum".to_owned())),
+            (Peer::Server, Payload::Text("IPSUM".to_owned())),
+            (Peer::Client, Payload::Close(transcript::Close::default())),
+        ]
+    );
+
+    // Nothing listens there, replay must not reach the upstream
+    let replaying = crate::start(
+        url::Url::parse("http://127.0.0.1:9").unwrap(),
+        config.clone(),
+    )
+    .expect("Parody should start");
+
+    assert_eq!(
+        exchange(replaying.port(), &["ipsum", "lorem"]),
+        vec!["hello", "IPSUM", "LOREM"]
+    );
+    assert_eq!(
+        replaying.served_recordings(),
+        vec![
+            DirectoryStorage::new_with_config(&"http://localhost/chat?room=1", config)
+                .unwrap()
+                .recording_of(RecordingKind::WebSocket)
+        ]
+    );
+}
+
+#[test]
+fn stale_websocket_session_should_be_recorded_again() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_max_age(Duration::from_secs(24 * 60 * 60));
+    let storage =
+        DirectoryStorage::new_with_config(&"http://localhost/chat?room=1", config.clone()).unwrap();
+    let transcript_path = storage.get_websocket_file_path();
+    std::fs::create_dir_all(storage.get_absolute_storage_path()).unwrap();
+    Transcript {
+        messages: vec![TranscriptMessage {
+            from: Peer::Server,
+            delay_ms: 0,
+            payload: Payload::Text("stale".to_owned()),
+        }],
+        recorded_at: Some("2020-01-01T00:00:00Z".to_owned()),
+        ..Transcript::new(None)
+    }
+    .save(&transcript_path)
+    .unwrap();
+    assert!(
+        storage::recording_age(
+            storage_path.path(),
+            &storage.recording_of(RecordingKind::WebSocket)
+        )
+        .unwrap()
+        .expect("Transcript should have an age")
+            > Duration::from_secs(365 * 24 * 60 * 60)
+    );
+
+    let parody = crate::start(
+        url::Url::parse(&format!("http://{}", start_upstream())).unwrap(),
+        config,
+    )
+    .expect("Parody should start");
+
+    assert_eq!(exchange(parody.port(), &["lorem"]), vec!["hello", "LOREM"]);
+
+    let started_at = Instant::now();
+    while Transcript::load(&transcript_path)
+        .unwrap()
+        .recorded_at
+        .as_deref()
+        == Some("2020-01-01T00:00:00Z")
+    {
+        assert!(
+            started_at.elapsed() < Duration::from_secs(5),
+            "Transcript should be saved again"
+        );
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    assert!(Transcript::load(&transcript_path).unwrap().age() < Some(Duration::from_secs(60)));
+}
+
+#[test]
+fn upgrade_request_should_be_detected_when_its_head_arrives_in_pieces() {
+    use std::io::{Read, Write};
+
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default().with_root_dir(storage_path.path().to_owned());
+    let transcript_path =
+        DirectoryStorage::new_with_config(&"http://localhost/chat?room=1", config.clone())
+            .unwrap()
+            .get_websocket_file_path();
+    std::fs::create_dir_all(transcript_path.parent().unwrap()).unwrap();
+    Transcript::new(None).save(&transcript_path).unwrap();
+
+    // Nothing listens there, only a detected upgrade gets a reply
+    let parody = crate::start(url::Url::parse("http://127.0.0.1:9").unwrap(), config)
+        .expect("Parody should start");
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", parody.port())).unwrap();
+
+    stream
+        .write_all(b"GET /chat?room=1 HTTP/1.1\r\nHost: localhost\r\n")
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    stream
+        .write_all(
+            b"Upgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+
+    let mut response = [0; 12];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"HTTP/1.1 101");
+}
+
+#[test]
+fn websocket_messages_should_be_redacted() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_redaction_pattern(regex::Regex::new("(?i)token-[a-z0-9]+").unwrap());
+    let transcript_path =
+        DirectoryStorage::new_with_config(&"http://localhost/chat?room=1", config.clone())
+            .unwrap()
+            .get_websocket_file_path();
+
+    let recording = crate::start(
+        url::Url::parse(&format!("http://{}", start_upstream())).unwrap(),
+        config.clone(),
+    )
+    .expect("Parody should start");
+
+    assert_eq!(
+        exchange(recording.port(), &["token-abc"]),
+        vec!["hello", "TOKEN-ABC"]
+    );
+    wait_for(&transcript_path);
+    drop(recording);
+
+    let transcript = Transcript::load(&transcript_path).unwrap();
+    assert_eq!(
+        transcript.messages[1..3]
+            .iter()
+            .map(|message| message.payload.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            Payload::Text(storage::PLACEHOLDER.to_owned()),
+            Payload::Text(storage::PLACEHOLDER.to_owned()),
+        ]
+    );
+    assert!(config
+        .redaction
+        .find_unredacted(storage_path.path())
+        .unwrap()
+        .is_empty());
+
+    // Other secrets are redacted the same way and match the recorded message
+    let replaying = crate::start(
+        url::Url::parse("http://127.0.0.1:9").unwrap(),
+        config.clone(),
+    )
+    .expect("Parody should start");
+
+    assert_eq!(
+        exchange(replaying.port(), &["token-xyz"]),
+        vec!["hello", storage::PLACEHOLDER]
+    );
+
+    let mut unredacted = transcript;
+    unredacted.messages[1].payload = Payload::Text("token-abc".to_owned());
+    unredacted.save(&transcript_path).unwrap();
+
+    let findings = config
+        .redaction
+        .find_unredacted(storage_path.path())
+        .unwrap();
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].path, transcript_path);
+}
+
+#[test]
+fn websocket_session_ending_without_closing_handshake_should_not_be_saved() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default().with_root_dir(storage_path.path().to_owned());
+    let transcript_path =
+        DirectoryStorage::new_with_config(&"http://localhost/chat?room=1", config.clone())
+            .unwrap()
+            .get_websocket_file_path();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Upstream should listen");
+    let upstream_addr = listener.local_addr().unwrap();
+
+    // Greets the client and goes away without closing the session
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("Upstream should accept");
+        let mut socket = tungstenite::accept(stream).expect("Upstream handshake should succeed");
+
+        socket.send(Message::text("hello")).unwrap();
+    });
+
+    let parody = crate::start(
+        url::Url::parse(&format!("http://{}", upstream_addr)).unwrap(),
+        config,
+    )
+    .expect("Parody should start");
+    let (mut socket, _) =
+        tungstenite::connect(format!("ws://127.0.0.1:{}/chat?room=1", parody.port()))
+            .expect("Parody handshake should succeed");
+
+    assert_eq!(read_text(&mut socket), "hello");
+    while socket.read().is_ok() {}
+
+    assert!(!transcript_path.exists());
+}
+
+#[test]
+fn concurrent_websocket_sessions_should_be_recorded_once() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Upstream should listen");
+    let upstream_addr = listener.local_addr().unwrap();
+    let sessions = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let accepted = sessions.clone();
+
+    // Answers every session like the upstream of a single one
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let mut socket =
+                tungstenite::accept(stream.unwrap()).expect("Upstream handshake should succeed");
+
+            socket.send(Message::text("hello")).unwrap();
+            loop {
+                match socket.read() {
+                    Ok(Message::Text(text)) => socket
+                        .send(Message::text(text.as_str().to_uppercase()))
+                        .unwrap(),
+                    Ok(Message::Close(_)) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        }
+    });
+
+    let parody = crate::start(
+        url::Url::parse(&format!("http://{}", upstream_addr)).unwrap(),
+        storage::Config::default().with_root_dir(storage_path.path().to_owned()),
+    )
+    .expect("Parody should start");
+    let port = parody.port();
+    let (mut socket, _) = tungstenite::connect(format!("ws://127.0.0.1:{}/chat?room=1", port))
+        .expect("Parody handshake should succeed");
+    assert_eq!(read_text(&mut socket), "hello");
+
+    // Opened while the first session is being recorded
+    let concurrent = std::thread::spawn(move || exchange(port, &["lorem"]));
+    std::thread::sleep(Duration::from_millis(200));
+
+    socket.send(Message::text("lorem")).unwrap();
+    assert_eq!(read_text(&mut socket), "LOREM");
+    socket.close(None).unwrap();
+    while socket.read().is_ok() {}
+
+    assert_eq!(concurrent.join().unwrap(), vec!["hello", "LOREM"]);
+    assert_eq!(sessions.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn websocket_session_should_be_recorded_through_upstream_proxy() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let upstream_addr = start_upstream();
+    let (proxy_addr, tunnelled) = forward_middleware::test::start_tunnel_proxy();
+
+    let parody = crate::ParodyBuilder::new()
+        .with_storage_config(
+            storage::Config::default().with_root_dir(storage_path.path().to_owned()),
+        )
+        .with_upstream_config(
+            forward_middleware::Config::default()
+                .with_proxy(url::Url::parse(&format!("http://{}", proxy_addr)).unwrap()),
+        )
+        .start(url::Url::parse(&format!("http://{}", upstream_addr)).unwrap())
+        .expect("Parody should start");
+
+    assert_eq!(exchange(parody.port(), &["lorem"]), vec!["hello", "LOREM"]);
+    assert_eq!(*tunnelled.lock().unwrap(), vec![upstream_addr.to_string()]);
+}
+
+#[test]
+fn websocket_session_with_silent_upstream_should_end_after_read_timeout() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default().with_root_dir(storage_path.path().to_owned());
+    let transcript_path =
+        DirectoryStorage::new_with_config(&"http://localhost/chat?room=1", config.clone())
+            .unwrap()
+            .get_websocket_file_path();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Upstream should listen");
+    let upstream_addr = listener.local_addr().unwrap();
+
+    // Greets the client and keeps the session open without a word more
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("Upstream should accept");
+        let mut socket = tungstenite::accept(stream).expect("Upstream handshake should succeed");
+
+        socket.send(Message::text("hello")).unwrap();
+        while socket.read().is_ok() {}
+    });
+
+    let parody = crate::ParodyBuilder::new()
+        .with_storage_config(config)
+        .with_upstream_config(
+            forward_middleware::Config::default().with_read_timeout(Duration::from_millis(200)),
+        )
+        .start(url::Url::parse(&format!("http://{}", upstream_addr)).unwrap())
+        .expect("Parody should start");
+    let (mut socket, _) =
+        tungstenite::connect(format!("ws://127.0.0.1:{}/chat?room=1", parody.port()))
+            .expect("Parody handshake should succeed");
+    if let MaybeTlsStream::Plain(stream) = socket.get_mut() {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+    }
+    let started_at = Instant::now();
+
+    assert_eq!(read_text(&mut socket), "hello");
+    while socket.read().is_ok() {}
+
+    assert!(started_at.elapsed() < Duration::from_secs(5));
+    assert!(!transcript_path.exists());
+}
+
+/// Reads until the session is closed, returns the close code and reason
+fn read_close(socket: &mut WebSocket<MaybeTlsStream<std::net::TcpStream>>) -> (u16, String) {
+    loop {
+        match socket.read().expect("Close message should be received") {
+            Message::Close(Some(frame)) => return (frame.code.into(), frame.reason.to_string()),
+            Message::Close(None) => panic!("Close message should have a frame"),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn websocket_close_code_and_reason_should_be_recorded_and_replayed() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default().with_root_dir(storage_path.path().to_owned());
+    let transcript_path =
+        DirectoryStorage::new_with_config(&"http://localhost/chat?room=1", config.clone())
+            .unwrap()
+            .get_websocket_file_path();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Upstream should listen");
+    let upstream_addr = listener.local_addr().unwrap();
+
+    // Greets the client and closes the session with its own code
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("Upstream should accept");
+        let mut socket = tungstenite::accept(stream).expect("Upstream handshake should succeed");
+
+        socket.send(Message::text("hello")).unwrap();
+        socket
+            .close(Some(tungstenite::protocol::CloseFrame {
+                code: 4001.into(),
+                reason: "room closed".into(),
+            }))
+            .unwrap();
+        while socket.read().is_ok() {}
+    });
+
+    let recording = crate::start(
+        url::Url::parse(&format!("http://{}", upstream_addr)).unwrap(),
+        config.clone(),
+    )
+    .expect("Parody should start");
+    let (mut socket, _) =
+        tungstenite::connect(format!("ws://127.0.0.1:{}/chat?room=1", recording.port()))
+            .expect("Parody handshake should succeed");
+
+    assert_eq!(read_text(&mut socket), "hello");
+    assert_eq!(read_close(&mut socket), (4001, "room closed".to_owned()));
+    while socket.read().is_ok() {}
+    wait_for(&transcript_path);
+    drop(recording);
+
+    assert_eq!(
+        Transcript::load(&transcript_path).unwrap().messages[1].payload,
+        Payload::Close(transcript::Close {
+            code: Some(4001),
+            reason: "room closed".to_owned(),
+        })
+    );
+
+    let replaying = crate::start(url::Url::parse("http://127.0.0.1:9").unwrap(), config)
+        .expect("Parody should start");
+    let (mut socket, _) =
+        tungstenite::connect(format!("ws://127.0.0.1:{}/chat?room=1", replaying.port()))
+            .expect("Parody handshake should succeed");
+
+    assert_eq!(read_text(&mut socket), "hello");
+    assert_eq!(read_close(&mut socket), (4001, "room closed".to_owned()));
+}
+
+#[test]
+fn transcript_should_load_close_reasons_recorded_without_code() {
+    let transcript: Transcript = serde_yaml::from_str(
+        "version: 1\nmessages:\n- from: server\n  close: room closed\n- from: client\n  close: ''\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        transcript
+            .messages
+            .iter()
+            .map(|message| message.payload.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            Payload::Close(transcript::Close {
+                code: None,
+                reason: "room closed".to_owned(),
+            }),
+            Payload::Close(transcript::Close::default()),
+        ]
+    );
+    assert_eq!(
+        transcript.messages[1].payload.to_message().unwrap(),
+        Message::Close(None)
+    );
+}
+
+#[test]
+fn websocket_session_without_transcript_should_be_rejected_in_strict_replay() {
+    init();
+    let storage_path = tempfile::tempdir().expect("Cannot create storage path");
+    let config = storage::Config::default()
+        .with_root_dir(storage_path.path().to_owned())
+        .with_strict_replay_status(410);
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Upstream should listen");
+    listener.set_nonblocking(true).unwrap();
+
+    let parody = crate::start(
+        url::Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap(),
+        config,
+    )
+    .expect("Parody should start");
+
+    match tungstenite::connect(format!("ws://127.0.0.1:{}/chat?room=1", parody.port())) {
+        Err(tungstenite::Error::Http(response)) => {
+            let body = String::from_utf8(response.body().clone().unwrap_or_default()).unwrap();
+
+            assert_eq!(response.status(), 410);
+            assert!(
+                body.starts_with("No recording for: GET /chat?room=1\n"),
+                "Unexpected body: {}",
+                body
+            );
+        }
+        result => panic!("Handshake should be rejected: {:?}", result.map(|_| ())),
+    }
+    assert!(listener.accept().is_err(), "Upstream should not be reached");
+}
+
+#[test]
+fn transcript_should_find_client_messages_and_their_replies() {
+    let text = |from, text: &str| TranscriptMessage {
+        from,
+        delay_ms: 0,
+        payload: Payload::Text(text.to_owned()),
+    };
+    let transcript = Transcript {
+        messages: vec![
+            text(Peer::Server, "hello"),
+            text(Peer::Client, "ping"),
+            text(Peer::Server, "pong 1"),
+            text(Peer::Client, "ping"),
+            text(Peer::Server, "pong 2"),
+            text(Peer::Server, "bye"),
+        ],
+        ..Transcript::new(None)
+    };
+    let ping = Payload::Text("ping".to_owned());
+
+    assert_eq!(transcript.server_replies(0), &transcript.messages[..1]);
+    assert_eq!(transcript.find_client_message(1, &ping), Some(1));
+    assert_eq!(transcript.find_client_message(2, &ping), Some(3));
+    assert_eq!(transcript.find_client_message(4, &ping), None);
+    assert_eq!(transcript.server_replies(4), &transcript.messages[4..]);
+}
@@ -0,0 +1,211 @@
+//! Ordered messages of a recorded WebSocket session
+
+use crate::{error::Error, result::Result, storage::Redaction};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+/// Version of the transcript format written by this Parody
+pub const CURRENT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Peer {
+    Client,
+    Server,
+}
+
+/// Message content, binary data is base64-encoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Payload {
+    Text(String),
+    Binary(String),
+    Close(Close),
+}
+
+/// Close code and reason of a close message
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RecordedClose")]
+pub struct Close {
+    /// `None` if the close message had no frame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    /// Empty if there was none
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RecordedClose {
+    Frame {
+        #[serde(default)]
+        code: Option<u16>,
+        #[serde(default)]
+        reason: String,
+    },
+    /// Transcripts recorded before close codes were kept have the reason only
+    Reason(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TranscriptMessage {
+    pub from: Peer,
+    /// Time since the previous message or since the session started
+    #[serde(default)]
+    pub delay_ms: u64,
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transcript {
+    pub version: u32,
+    /// Subprotocol the upstream selected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default)]
+    pub messages: Vec<TranscriptMessage>,
+    /// RFC 3339 time of recording
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recorded_at: Option<String>,
+}
+
+impl Payload {
+    /// Recordable content of the message, `None` for control messages
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match message {
+            Message::Text(text) => Some(Payload::Text(text.as_str().to_owned())),
+            Message::Binary(data) => Some(Payload::Binary(STANDARD.encode(data))),
+            Message::Close(frame) => Some(Payload::Close(
+                frame
+                    .as_ref()
+                    .map(|frame| Close {
+                        code: Some(frame.code.into()),
+                        reason: frame.reason.as_str().to_owned(),
+                    })
+                    .unwrap_or_default(),
+            )),
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => None,
+        }
+    }
+
+    /// The payload with secrets replaced as the redaction rules of bodies say
+    pub fn redacted(&self, redaction: &Redaction) -> Self {
+        match self {
+            Payload::Text(text) => Payload::Text(
+                redaction
+                    .redact_body(text.as_bytes())
+                    .and_then(|redacted| String::from_utf8(redacted).ok())
+                    .unwrap_or_else(|| text.clone()),
+            ),
+            Payload::Binary(data) => {
+                match STANDARD
+                    .decode(data)
+                    .ok()
+                    .and_then(|data| redaction.redact_body(&data))
+                {
+                    Some(redacted) => Payload::Binary(STANDARD.encode(redacted)),
+                    None => self.clone(),
+                }
+            }
+            Payload::Close(close) => Payload::Close(Close {
+                code: close.code,
+                reason: redaction.redact_text(close.reason.clone()),
+            }),
+        }
+    }
+
+    /// Text the redaction rules of bodies check, decoded for binary payloads
+    pub fn content(&self) -> Option<Vec<u8>> {
+        match self {
+            Payload::Text(text) => Some(text.as_bytes().to_vec()),
+            Payload::Close(close) => Some(close.reason.as_bytes().to_vec()),
+            Payload::Binary(data) => STANDARD.decode(data).ok(),
+        }
+    }
+
+    pub fn to_message(&self) -> Result<Message> {
+        Ok(match self {
+            Payload::Text(text) => Message::text(text.as_str()),
+            Payload::Binary(data) => Message::binary(STANDARD.decode(data)?),
+            Payload::Close(Close { code: None, reason }) if reason.is_empty() => {
+                Message::Close(None)
+            }
+            // A reason needs a code, older transcripts recorded none
+            Payload::Close(close) => Message::Close(Some(CloseFrame {
+                code: close.code.map(CloseCode::from).unwrap_or(CloseCode::Normal),
+                reason: close.reason.as_str().into(),
+            })),
+        })
+    }
+}
+
+impl From<RecordedClose> for Close {
+    fn from(recorded: RecordedClose) -> Self {
+        match recorded {
+            RecordedClose::Frame { code, reason } => Close { code, reason },
+            RecordedClose::Reason(reason) => Close { code: None, reason },
+        }
+    }
+}
+
+impl Transcript {
+    pub fn new(protocol: Option<String>) -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            protocol,
+            messages: Vec::new(),
+            recorded_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let transcript: Self = serde_yaml::from_reader(File::open(path)?)?;
+
+        if transcript.version > CURRENT_VERSION {
+            return Err(Error::UnsupportedMetadataVersion(transcript.version));
+        }
+
+        Ok(transcript)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        crate::storage::save_yaml(path, self)
+    }
+
+    /// Time since recording, `None` if unknown
+    pub fn age(&self) -> Option<Duration> {
+        crate::storage::get_age(self.recorded_at.as_deref())
+    }
+
+    /// Index of the first client message with the payload at or after the position
+    pub fn find_client_message(&self, position: usize, payload: &Payload) -> Option<usize> {
+        self.messages
+            .iter()
+            .enumerate()
+            .skip(position)
+            .find(|(_, message)| message.from == Peer::Client && message.payload == *payload)
+            .map(|(index, _)| index)
+    }
+
+    /// Server messages from the position up to the next client message
+    pub fn server_replies(&self, position: usize) -> &[TranscriptMessage] {
+        let rest = self.messages.get(position..).unwrap_or_default();
+        let end = rest
+            .iter()
+            .position(|message| message.from == Peer::Client)
+            .unwrap_or(rest.len());
+
+        &rest[..end]
+    }
+}